@@ -0,0 +1,222 @@
+use lib_udp_server::{fragment, interpolation, WireFormat};
+
+/// Command line of the client. Every setting can also come from a `BELL_*` environment
+/// variable. Settings that worked are saved to the settings file, so they only have to be
+/// given once.
+#[derive(clap::Parser, Debug)]
+#[command(name = "game", about = "Client for the bell game")]
+pub struct Cli {
+    /// Settings file to read and update, defaults to bell/client.toml in the user config
+    /// directory
+    #[arg(short, long, env = "BELL_CLIENT_CONFIG")]
+    config: Option<std::path::PathBuf>,
+    /// Do not write the settings back to the settings file
+    #[arg(long)]
+    no_save: bool,
+
+    /// Host name or IP address of the server
+    #[arg(short = 'H', long, env = "BELL_SERVER_HOST")]
+    server_host: Option<String>,
+    #[arg(short = 'p', long, env = "BELL_SERVER_PORT")]
+    server_port: Option<u16>,
+    /// Local address to send from, e.g. 0.0.0.0:0 or [::]:0. Defaults to any address of the
+    /// server's family. Not `BELL_BIND`, which is the server's listen address.
+    #[arg(short, long, env = "BELL_CLIENT_BIND")]
+    bind: Option<std::net::SocketAddr>,
+    /// Name shown in the window title. Only used locally, the server does not know names
+    #[arg(short, long, env = "BELL_NAME")]
+    name: Option<String>,
+    /// input lets the server move our player, local moves it here and sends the positions
+    #[arg(long, env = "BELL_MOVEMENT")]
+    movement: Option<MovementMode>,
+    /// binary or json
+    #[arg(long, env = "BELL_WIRE_FORMAT")]
+    wire_format: Option<WireFormat>,
+    /// Largest datagram sent, bigger messages are fragmented
+    #[arg(
+        long,
+        env = "BELL_MTU",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new()
+            .range(fragment::MIN_MTU as u64..=fragment::MAX_DATAGRAM_LEN as u64)
+    )]
+    mtu: Option<usize>,
+    /// How many milliseconds behind the server the other players are shown, more smooths out
+    /// worse connections
+    #[arg(long, env = "BELL_INTERPOLATION_DELAY")]
+    interpolation_delay_ms: Option<u64>,
+    /// How many milliseconds the other players keep moving when no snapshot came in time
+    #[arg(long, env = "BELL_MAX_EXTRAPOLATION")]
+    max_extrapolation_ms: Option<u64>,
+
+    /// Room to join once registered, instead of the default one
+    #[arg(short, long, env = "BELL_ROOM")]
+    pub room: Option<String>,
+    /// Create the room given with --room instead of joining it
+    #[arg(long, requires = "room")]
+    pub create_room: bool,
+    /// Password of the room to join or create
+    #[arg(long, env = "BELL_ROOM_PASSWORD")]
+    pub room_password: Option<String>,
+}
+
+/// Who moves our own sprite. In `Local` mode the client moves it and reports absolute
+/// positions, in `Input` mode it only sends the held directions and the server moves it.
+#[derive(
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+    bevy::prelude::Resource,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Debug,
+    Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum MovementMode {
+    Local,
+    #[default]
+    Input,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ClientSettings {
+    pub server_host: String,
+    pub server_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bind: Option<std::net::SocketAddr>,
+    pub name: String,
+    pub interpolation_delay_ms: u64,
+    pub max_extrapolation_ms: u64,
+    pub movement: MovementMode,
+    pub wire_format: WireFormat,
+    pub mtu: usize,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        Self {
+            server_host: "127.0.0.1".to_string(),
+            server_port: 8080,
+            bind: None,
+            name: std::env::var("USER")
+                .or_else(|_| std::env::var("USERNAME"))
+                .unwrap_or_else(|_| "player".to_string()),
+            interpolation_delay_ms: interpolation::DEFAULT_DELAY.as_millis() as u64,
+            max_extrapolation_ms: interpolation::DEFAULT_MAX_EXTRAPOLATION.as_millis() as u64,
+            movement: MovementMode::default(),
+            wire_format: WireFormat::default(),
+            mtu: fragment::DEFAULT_MTU,
+        }
+    }
+}
+
+impl ClientSettings {
+    /// Reads the settings file and applies the environment and command line on top of it.
+    pub fn load(cli: &Cli) -> Result<Self, String> {
+        let mut settings = match cli.settings_path() {
+            Some(path) if path.exists() => {
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+                toml::from_str(&contents)
+                    .map_err(|e| format!("invalid settings file {}: {}", path.display(), e))?
+            }
+            _ => ClientSettings::default(),
+        };
+
+        if let Some(host) = &cli.server_host {
+            settings.server_host = host.clone();
+        }
+        if let Some(port) = cli.server_port {
+            settings.server_port = port;
+        }
+        if cli.bind.is_some() {
+            settings.bind = cli.bind;
+        }
+        if let Some(name) = &cli.name {
+            settings.name = name.clone();
+        }
+        if let Some(delay) = cli.interpolation_delay_ms {
+            settings.interpolation_delay_ms = delay;
+        }
+        if let Some(max_extrapolation) = cli.max_extrapolation_ms {
+            settings.max_extrapolation_ms = max_extrapolation;
+        }
+        if let Some(movement) = cli.movement {
+            settings.movement = movement;
+        }
+        if let Some(wire_format) = cli.wire_format {
+            settings.wire_format = wire_format;
+        }
+        if let Some(mtu) = cli.mtu {
+            settings.mtu = mtu;
+        }
+        if settings.mtu < fragment::MIN_MTU || settings.mtu > fragment::MAX_DATAGRAM_LEN {
+            return Err(format!(
+                "mtu must be between {} and {}",
+                fragment::MIN_MTU,
+                fragment::MAX_DATAGRAM_LEN
+            ));
+        }
+
+        Ok(settings)
+    }
+
+    /// Writes the settings to the settings file, unless `--no-save` was given.
+    pub fn save(&self, cli: &Cli) {
+        let Some(path) = cli.settings_path().filter(|_| !cli.no_save) else {
+            return;
+        };
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| toml::to_string_pretty(self).map_err(std::io::Error::other))
+            .and_then(|contents| std::fs::write(&path, contents));
+        if let Err(e) = written {
+            println!("Failed to save settings to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Looks up the server. Host names can resolve to several addresses, the first one of the
+    /// same family as the bind address is used. IPv6 literals may be written with or without
+    /// brackets.
+    pub fn resolve_server(&self) -> Result<std::net::SocketAddr, String> {
+        use std::net::ToSocketAddrs;
+
+        let host = self
+            .server_host
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let mut addrs = (host, self.server_port)
+            .to_socket_addrs()
+            .map_err(|e| format!("cannot resolve {}: {}", self.server_host, e))?;
+        let found = match self.bind {
+            Some(bind) => addrs.find(|addr| addr.is_ipv4() == bind.is_ipv4()),
+            None => addrs.next(),
+        };
+
+        found.ok_or_else(|| format!("{} has no usable address", self.server_host))
+    }
+
+    /// The configured bind address, or any local address of the server's family.
+    pub fn bind_addr(&self, server: std::net::SocketAddr) -> std::net::SocketAddr {
+        self.bind.unwrap_or(match server {
+            std::net::SocketAddr::V4(_) => {
+                std::net::SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, 0))
+            }
+            std::net::SocketAddr::V6(_) => {
+                std::net::SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0))
+            }
+        })
+    }
+}
+
+impl Cli {
+    fn settings_path(&self) -> Option<std::path::PathBuf> {
+        self.config
+            .clone()
+            .or_else(|| dirs::config_dir().map(|dir| dir.join("bell").join("client.toml")))
+    }
+}
@@ -1,16 +1,26 @@
+use bevy::app::AppExit;
 use bevy::prelude::*;
-use lib_udp_server::{BellMessage, Point};
-use std::collections::HashMap;
-use std::net::UdpSocket;
-use std::sync::mpsc::channel;
-use std::sync::Arc;
-use std::sync::Mutex;
-use std::sync::RwLock;
-use std::thread;
+use bevy::window::{PrimaryWindow, WindowResized};
+use clap::Parser;
+use config::MovementMode;
+use lib_udp_server::client::{ClientError, ClientOptions};
+use lib_udp_server::movement::{self, PLAYER_SPEED};
+use lib_udp_server::protocol::{capabilities, PROTOCOL_VERSION};
+use lib_udp_server::room::{self, RoomInfo};
+use network::{
+    BellConnection, BellNetworkPlugin, Connected, Disconnected, InterpolatedPositions,
+    PlayerJoined, PlayerLeft, PlayerMoved, PlayersCollided, RoomJoined, RoomRefused, RoomsListed,
+    Waitlisted,
+};
 
-use bevy::diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin};
+mod config;
+mod network;
 
-mod packet_sender;
+/// How often the held inputs are sent again when they have not changed, so that a lost
+/// packet does not leave the player stuck or running.
+const INPUT_RESEND_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+/// How long a sprite stays tinted after a collision.
+const COLLISION_FLASH_SECS: f32 = 0.3;
 
 #[derive(Component)]
 enum Direction {
@@ -24,418 +34,548 @@ enum Direction {
 #[derive(Component)]
 struct PlayerId(u32);
 
-#[derive(Resource, Clone, Default)]
-struct SpritePosition {
-    pub x: Arc<RwLock<f32>>,
-    pub y: Arc<RwLock<f32>>,
-    pub has_extern_changes: Arc<RwLock<bool>>,
-}
+/// Marks the sprite of our own player.
+#[derive(Component)]
+struct LocalPlayer;
 
-// TODO: Use this instead of a single SpritePosition
-#[derive(Resource, Clone, Default)]
-struct SpritePositions {
-    pub position_collections: Arc<RwLock<HashMap<u32, SpritePosition>>>,
-    pub injection_order: Arc<RwLock<Option<u32>>>,
-    pub self_id: Arc<RwLock<u32>>,
+/// Tints a sprite for a moment after it collided with another one.
+#[derive(Component)]
+struct CollisionFlash(Timer);
+
+/// Rooms from the last listing, so that the number keys can pick one of them.
+#[derive(Resource, Default)]
+struct RoomDirectory {
+    listed: Vec<RoomInfo>,
+    /// Password sent along when joining a room from the listing
+    password: Option<String>,
+    /// Room from the command line to join, or create, once we are connected
+    requested: Option<(String, bool)>,
 }
 
+/// The sprite of every remote player, so that each is spawned and despawned exactly once.
+#[derive(Resource, Default)]
+struct Players(std::collections::HashMap<u32, Entity>);
+
+/// Title of the window while nothing else has to be shown in it.
 #[derive(Resource)]
-struct MessageSender {
-    tx: Mutex<std::sync::mpsc::Sender<UpdateMessage>>,
-}
+struct Title(String);
 
-#[derive(Clone)]
-enum UpdateMessage {
-    PositionChange,
-    PositionChangeExtern(lib_udp_server::Point),
-    PlayerInsertion(lib_udp_server::Point),
-}
+/// The settings are only written once the server let us in with them.
+#[derive(Resource)]
+struct SettingsToSave(Option<(config::ClientSettings, config::Cli)>);
 
 fn main() {
-    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
-    let sprite_collections = {
-        let map = HashMap::new();
-        // TODO: replace this with a proper initialization routine
-
-        let position_collections = Arc::new(RwLock::new(map));
-        let injection_order = Arc::new(RwLock::new(None));
-        SpritePositions {
-            position_collections,
-            injection_order,
-            self_id: Arc::new(RwLock::new(0)),
+    let cli = config::Cli::parse();
+    let settings = match config::ClientSettings::load(&cli) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Invalid settings: {}", e);
+            return;
         }
     };
-
-    let (tx, rx) = channel::<UpdateMessage>();
-
-    let sprite_collections_clone = sprite_collections.clone();
-    let socket_clone = socket.try_clone().unwrap();
-    // Internal listening thread
-    thread::spawn(move || {
-        while let Ok(message) = rx.recv() {
-            let self_id = sprite_collections_clone.self_id.read().unwrap();
-            match message {
-                UpdateMessage::PositionChange => {
-                    let target_sprite = sprite_collections_clone
-                        .position_collections
-                        .read()
-                        .unwrap();
-                    let target_sprite = target_sprite.get(&self_id).unwrap();
-                    let SpritePosition { ref x, ref y, .. } = target_sprite;
-                    let (x, y) = {
-                        let x = x.read().unwrap();
-                        let y = y.read().unwrap();
-                        (*x, *y)
-                    };
-                    let message = BellMessage::PositionChangeMessage(Point { x, y, id: *self_id });
-                    let message = serde_json::to_vec(&message).unwrap();
-                    socket_clone.send_to(&message, "127.0.0.1:8080").unwrap();
-                }
-                UpdateMessage::PositionChangeExtern(point) => {
-                    let mut target_sprite_collections = sprite_collections_clone
-                        .position_collections
-                        .write()
-                        .unwrap();
-                    let target_sprite = target_sprite_collections.get(&(point.id as u32));
-                    match target_sprite {
-                        Some(target_sprite) => {
-                            let SpritePosition {
-                                ref x,
-                                ref y,
-                                ref has_extern_changes,
-                            } = target_sprite;
-                            {
-                                let mut x = x.write().unwrap();
-                                let mut y = y.write().unwrap();
-                                let mut has_extern_changes = has_extern_changes.write().unwrap();
-                                *x = point.x;
-                                *y = point.y;
-                                *has_extern_changes = true;
-                            }
-                        }
-                        None => {
-                            let sprite_position = SpritePosition {
-                                x: Arc::new(RwLock::new(point.x)),
-                                y: Arc::new(RwLock::new(point.y)),
-                                has_extern_changes: Arc::new(RwLock::new(true)),
-                            };
-                            target_sprite_collections.insert(point.id as u32, sprite_position);
-                        }
-                    }
-                }
-                UpdateMessage::PlayerInsertion(point) => {
-                    println!("Inserting player {}", point.id);
-                    let mut player_registry = sprite_collections_clone
-                        .position_collections
-                        .write()
-                        .unwrap();
-                    println!("Registry obtained");
-                    let sprite_position = SpritePosition {
-                        x: Arc::new(RwLock::new(point.x)),
-                        y: Arc::new(RwLock::new(point.y)),
-                        has_extern_changes: Arc::new(RwLock::new(false)),
-                    };
-
-                    player_registry.insert(point.id as u32, sprite_position);
-                    sprite_collections_clone
-                        .injection_order
-                        .write()
-                        .unwrap()
-                        .replace(point.id as u32);
-                    println!("Injection order updated");
-                }
-            }
+    let server = match settings.resolve_server() {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Failed to find the server: {}", e);
+            return;
         }
-    });
-
-    // fetch id as assigned by the server
-    let registration_message = BellMessage::PlayerRegistrationMessage(Point {
-        x: 0.,
-        y: 0.,
-        id: 0,
-    });
-    let registration_message = serde_json::to_vec(&registration_message).unwrap();
-    socket
-        .send_to(&registration_message, "127.0.0.1:8080")
-        .unwrap();
-
-    // this doesn't work because the second message is sent before the listener for it is setup
-    // In order to fix this would need to receive the second message along with the first
-    // This means coming up with a different enum for the message
-    let mut buf = vec![0; 1024];
-    if let Ok((size, _src)) = socket.recv_from(&mut buf) {
-        let data = &buf[..size];
-        let message = serde_json::from_slice::<BellMessage>(&data).unwrap();
-        if let BellMessage::RegistrationReplyMessage(id, points) = message {
-            println!("Received registration reply message");
-            println!("There are currently {} players", points.len());
-            *sprite_collections.self_id.write().unwrap() = id;
-            sprite_collections
-                .position_collections
-                .write()
-                .unwrap()
-                .insert(
-                    id,
-                    SpritePosition {
-                        x: Arc::new(RwLock::new(0.)),
-                        y: Arc::new(RwLock::new(0.)),
-                        has_extern_changes: Arc::new(RwLock::new(false)),
-                    },
-                );
-            for point in points {
-                let sprite_position = SpritePosition {
-                    x: Arc::new(RwLock::new(point.x)),
-                    y: Arc::new(RwLock::new(point.y)),
-                    has_extern_changes: Arc::new(RwLock::new(true)),
-                };
-                sprite_collections.position_collections.write().unwrap().insert(point.id, sprite_position);
-                *sprite_collections.injection_order.write().unwrap() = Some(point.id);
-            }
-        } else {
-            println!("Failed to receive proper registration reply message");
-        }
-    } else {
-        panic!("Received registration data but failed to parse it");
-    }
-
-
-    // External listening thread
-    let tx_clone = tx.clone();
-    let socket_clone = socket.try_clone().unwrap();
-    thread::spawn(move || {
-        let mut buf = vec![0; 1024];
-        while let Ok((size, _src)) = socket_clone.recv_from(&mut buf) {
-            let data = &buf[..size];
-            let message = serde_json::from_slice::<BellMessage>(&data);
-            if let Ok(message) = message {
-                match message {
-                    BellMessage::PositionChangeMessage(point) => {
-                        let send_result = tx_clone.send(UpdateMessage::PositionChangeExtern(point));
-                        if let Err(e) = send_result {
-                            println!("Error sending message: {:?}", e);
-                        }
-                    }
-                    BellMessage::PlayerInsertionMessage(point) => {
-                        println!("Player insertion message received");
-                        tx_clone
-                            .send(UpdateMessage::PlayerInsertion(point))
-                            .unwrap();
-                    }
-                    _ => {}
-                }
-            }
-        }
-    });
+    };
+    let movement_mode = settings.movement;
+    let options = ClientOptions {
+        bind: Some(settings.bind_addr(server)),
+        capabilities: match movement_mode {
+            MovementMode::Input => capabilities::ALL,
+            MovementMode::Local => capabilities::ALL & !capabilities::INPUT_MOVEMENT,
+        },
+        wire_format: settings.wire_format,
+        mtu: settings.mtu,
+        ..ClientOptions::default()
+    };
+    let network = BellNetworkPlugin::new(server, options).interpolation(
+        std::time::Duration::from_millis(settings.interpolation_delay_ms),
+        std::time::Duration::from_millis(settings.max_extrapolation_ms),
+    );
+    println!("Playing as {} on {}", settings.name, server);
 
-    let message_sender = {
-        let sender_lock = Mutex::new(tx);
-        MessageSender { tx: sender_lock }
+    let room_directory = RoomDirectory {
+        listed: Vec::new(),
+        password: cli.room_password.clone(),
+        requested: cli
+            .room
+            .clone()
+            .filter(|name| name != room::DEFAULT_ROOM)
+            .map(|name| (name, cli.create_room)),
     };
+    let title = format!("bell - {}", settings.name);
 
     App::new()
-        .add_plugins(DefaultPlugins)
-        // .add_plugin(LogDiagnosticsPlugin::default())
-        // .add_plugin(FrameTimeDiagnosticsPlugin::default())
-        .insert_resource(sprite_collections)
-        .insert_resource(message_sender)
-        .add_system(sprite_movement)
-        .add_system(maybe_insert_player)
+        .add_plugins(DefaultPlugins.set(WindowPlugin {
+            primary_window: Some(Window {
+                title: title.clone(),
+                ..default()
+            }),
+            ..default()
+        }))
+        // .add_plugin(bevy::diagnostic::LogDiagnosticsPlugin::default())
+        // .add_plugin(bevy::diagnostic::FrameTimeDiagnosticsPlugin::default())
+        .add_plugin(network)
+        .insert_resource(movement_mode)
+        .insert_resource(room_directory)
+        .insert_resource(Title(title))
+        .insert_resource(Players::default())
+        .insert_resource(SettingsToSave(Some((settings, cli))))
+        .add_system(on_connected)
+        .add_system(show_waitlist)
+        .add_system(on_disconnected)
+        .add_system(spawn_players)
+        .add_system(move_players)
+        .add_system(interpolate_players.after(move_players))
+        .add_system(despawn_players)
+        .add_system(change_room)
+        .add_system(sprite_movement.run_if(resource_equals(MovementMode::Local)))
+        .add_system(send_inputs.run_if(resource_equals(MovementMode::Input)))
+        .add_system(room_controls)
+        .add_system(show_rooms)
+        .add_system(send_view)
+        .add_system(start_collision_flash)
+        .add_system(fade_collision_flash)
         .add_startup_system(setup)
         .run();
 }
 
-fn setup(
+fn setup(mut commands: Commands) {
+    commands.spawn(Camera2dBundle::default());
+}
+
+fn player_sprite(asset_server: &AssetServer, x: f32, y: f32) -> SpriteBundle {
+    SpriteBundle {
+        texture: asset_server.load("icon.png"),
+        transform: Transform::from_xyz(x, y, 0.),
+        ..default()
+    }
+}
+
+/// Spawns our own sprite once the server let us in, and asks for the room from the command
+/// line.
+fn on_connected(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
-    sprite_collections: ResMut<SpritePositions>,
+    mut connected: EventReader<Connected>,
+    connection: Res<BellConnection>,
+    mut movement_mode: ResMut<MovementMode>,
+    mut to_save: ResMut<SettingsToSave>,
+    room_directory: Res<RoomDirectory>,
 ) {
-    let id = sprite_collections.self_id.read().unwrap();
-    commands.spawn(Camera2dBundle::default());
+    let Some(event) = connected.iter().last() else {
+        return;
+    };
+    let Some(client) = connection.client() else {
+        return;
+    };
+    if let Some((settings, cli)) = to_save.0.take() {
+        settings.save(&cli);
+    }
+    match client.local_addr() {
+        Ok(addr) => println!("Registered as player {} from {}", event.id, addr),
+        Err(e) => {
+            println!("Registered as player {}", event.id);
+            eprintln!("Failed to read our own address: {}", e);
+        }
+    }
+    println!(
+        "Server accepted features {:?}",
+        capabilities::names(event.capabilities)
+    );
+    if *movement_mode == MovementMode::Input
+        && event.capabilities & capabilities::INPUT_MOVEMENT == 0
+    {
+        println!("Server does not support input movement, moving locally instead");
+        *movement_mode = MovementMode::Local;
+    }
+
     commands.spawn((
-        SpriteBundle {
-            texture: asset_server.load("icon.png"),
-            transform: Transform::from_xyz(0., 0., 0.),
-            ..default()
-        },
+        player_sprite(&asset_server, 0., 0.),
         Direction::Still,
-        PlayerId(*id),
+        PlayerId(event.id),
+        LocalPlayer,
     ));
+
+    if let Some((name, create)) = &room_directory.requested {
+        let requested = if *create {
+            client.create_room(name, 0, room_directory.password.clone())
+        } else {
+            client.join_room(name, room_directory.password.clone())
+        };
+        if requested.is_err() {
+            println!("Failed to ask for room {}", name);
+        }
+    }
+}
+
+/// Puts our place in the waitlist into the window title until the server lets us in.
+fn show_waitlist(
+    mut waitlisted: EventReader<Waitlisted>,
+    mut connected: EventReader<Connected>,
+    title: Res<Title>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+) {
+    let Ok(mut window) = windows.get_single_mut() else {
+        return;
+    };
+    if connected.iter().last().is_some() {
+        window.title = title.0.clone();
+        return;
+    }
+    let Some(Waitlisted(position)) = waitlisted.iter().last() else {
+        return;
+    };
+    println!(
+        "The server is full, you are number {} in the waitlist",
+        position
+    );
+    window.title = format!("{} - server full, number {} in line", title.0, position);
+}
+
+fn on_disconnected(mut disconnected: EventReader<Disconnected>, mut exit: EventWriter<AppExit>) {
+    let Some(event) = disconnected.iter().next() else {
+        return;
+    };
+    match event {
+        Disconnected::Failed(ClientError::Rejected(min_version)) => eprintln!(
+            "The server rejected this client: it speaks protocol version {} but the server requires at least version {}. Please update the game.",
+            PROTOCOL_VERSION, min_version
+        ),
+        Disconnected::Failed(ClientError::MissingCapabilities(missing))
+            if missing & capabilities::INPUT_MOVEMENT != 0 =>
+        {
+            eprintln!("The server only lets players move by sending inputs, start the game with --movement input to play on it.")
+        }
+        Disconnected::Failed(e) => eprintln!("Failed to register with the server: {}", e),
+        Disconnected::Lost(reason) => {
+            eprintln!("Lost the connection to the server: {:?}", reason)
+        }
+    }
+    exit.send(AppExit);
 }
 
-fn maybe_insert_player(
+fn spawn_players(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
-    sprite_positions: ResMut<SpritePositions>,
+    mut joined: EventReader<PlayerJoined>,
+    mut players: ResMut<Players>,
 ) {
-    let mut insertion_order = sprite_positions.injection_order.write().unwrap();
-    if insertion_order.is_some() {
-        println!("Injection order found");
-        let insert_id = insertion_order.take();
-        if let Some(insert_id) = insert_id {
-            let target_position = sprite_positions.position_collections.read().unwrap();
-            println!("Read copy of target position map obtained");
-            let target_position = target_position.get(&insert_id);
-            if target_position.is_none() {
-                println!("Target position not found for injection order");
-                return;
-            }
-            let target_position = target_position.unwrap();
-            println!("Target position found");
-            commands.spawn((
-                SpriteBundle {
-                    texture: asset_server.load("icon.png"),
-                    transform: Transform::from_xyz(
-                        *target_position.x.read().unwrap(),
-                        *target_position.y.read().unwrap(),
-                        0.,
-                    ),
-                    ..default()
-                },
+    for PlayerJoined(point) in joined.iter() {
+        let std::collections::hash_map::Entry::Vacant(entry) = players.0.entry(point.id) else {
+            continue;
+        };
+        println!("Inserting player {}", point.id);
+        let entity = commands
+            .spawn((
+                player_sprite(&asset_server, point.x, point.y),
                 Direction::Still,
-                PlayerId(insert_id),
-            ));
+                PlayerId(point.id),
+            ))
+            .id();
+        entry.insert(entity);
+    }
+}
 
-            println!("Sprite bundle spawned");
-        }
+/// Puts every sprite where the server last saw it, the other players are then smoothed out
+/// by `interpolate_players`. Our own sprite only gets here when the server moves it by our
+/// inputs or corrects a move we made.
+fn move_players(
+    mut moved: EventReader<PlayerMoved>,
+    mut players: Query<(&PlayerId, &mut Transform, &mut Direction)>,
+) {
+    let latest = moved
+        .iter()
+        .map(|PlayerMoved(point)| (point.id, (point.x, point.y)))
+        .collect::<std::collections::HashMap<u32, (f32, f32)>>();
+    if latest.is_empty() {
+        return;
     }
-    // if let Some(target_id) = sprite_positions.1.read().unwrap() {
 
-    // }
+    for (player_id, mut transform, mut direction) in players.iter_mut() {
+        let Some((x, y)) = latest.get(&player_id.0) else {
+            continue;
+        };
+        transform.translation.x = *x;
+        transform.translation.y = *y;
+        // otherwise a locally moved sprite keeps going from where it was put
+        *direction = Direction::Still;
+    }
 }
 
-/// The sprite is animated by changing its translation depending on the time that has passed since
-/// the last frame.
-fn sprite_movement(
-    time: Res<Time>,
-    mut sprite_position: Query<(&mut Direction, &mut Transform, &PlayerId)>,
-    mut position_record: ResMut<SpritePositions>,
-    keyboard_input: Res<Input<KeyCode>>,
-    message_sender: Res<MessageSender>,
+/// The other players are shown a little behind the server, in between the snapshots of the
+/// jitter buffer, so that they move smoothly however unevenly the snapshots arrive.
+fn interpolate_players(
+    interpolated: Res<InterpolatedPositions>,
+    mut players: Query<(&PlayerId, &mut Transform), Without<LocalPlayer>>,
 ) {
-    let self_id = { *position_record.self_id.read().unwrap() };
-
-    for (mut logo, mut transform, player_id) in &mut sprite_position {
-        let has_extern_changes = {
-            let guard = position_record
-                .position_collections
-                .read()
-                .unwrap()
-                .get(&player_id.0)
-                .unwrap()
-                .has_extern_changes
-                .read()
-                .unwrap()
-                .clone();
-            guard
+    for (player_id, mut transform) in players.iter_mut() {
+        let Some(position) = interpolated.0.get(&player_id.0) else {
+            continue;
         };
+        transform.translation.x = position.x;
+        transform.translation.y = position.y;
+    }
+}
 
-        if has_extern_changes || self_id != player_id.0 {
-            let position_record = position_record.position_collections.read().unwrap();
-            let position_record = position_record.get(&player_id.0).unwrap();
-            transform.translation.x = position_record.x.read().unwrap().clone();
-            transform.translation.y = position_record.y.read().unwrap().clone();
-            let mut has_extern_changes = position_record.has_extern_changes.write().unwrap();
-            *has_extern_changes = false;
-            *logo = Direction::Still; // reset the direction here otherwise the sprite will
-                                      // keep moving
-            continue;
+fn despawn_players(
+    mut commands: Commands,
+    mut left: EventReader<PlayerLeft>,
+    mut players: ResMut<Players>,
+) {
+    for PlayerLeft(id) in left.iter() {
+        if let Some(entity) = players.0.remove(id) {
+            commands.entity(entity).despawn();
+            println!("Sprite for player {} despawned", id);
         }
+    }
+}
 
-        match *logo {
-            Direction::Up if !has_extern_changes => {
-                transform.translation.y += 150. * time.delta_seconds()
-            }
-            Direction::Down if !has_extern_changes => {
-                transform.translation.y -= 150. * time.delta_seconds()
-            }
-            Direction::Left if !has_extern_changes => {
-                transform.translation.x -= 150. * time.delta_seconds()
-            }
-            Direction::Right if !has_extern_changes => {
-                transform.translation.x += 150. * time.delta_seconds()
-            }
-            Direction::Still if !has_extern_changes => {}
-            _ => {
-                // we have external commands to carry out from the update_server
-                let position_record = position_record.position_collections.read().unwrap();
-                let position_record = position_record.get(&player_id.0).unwrap();
-                transform.translation.x = position_record.x.read().unwrap().clone();
-                transform.translation.y = position_record.y.read().unwrap().clone();
-                let mut has_extern_changes = position_record.has_extern_changes.write().unwrap();
-                *has_extern_changes = false;
-                *logo = Direction::Still; // reset the direction here otherwise the sprite will
-                                          // keep moving
-                continue;
-            }
-        }
+/// We start over at the origin. The players of the old room go as `PlayerLeft`, the ones of
+/// the new room come as `PlayerJoined`.
+fn change_room(
+    mut joined: EventReader<RoomJoined>,
+    mut own: Query<&mut Transform, With<LocalPlayer>>,
+) {
+    let Some(RoomJoined(name)) = joined.iter().last() else {
+        return;
+    };
+    println!("Joined room {}", name);
+    for mut transform in own.iter_mut() {
+        transform.translation.x = 0.;
+        transform.translation.y = 0.;
+    }
+}
 
-        if keyboard_input.pressed(KeyCode::Up) {
-            *logo = Direction::Up;
-            update_server(
-                transform.translation.x,
-                transform.translation.y,
-                &mut position_record,
-                &message_sender,
-                &player_id,
-            );
-        }
-        if keyboard_input.pressed(KeyCode::Down) {
-            *logo = Direction::Down;
-            update_server(
-                transform.translation.x,
-                transform.translation.y,
-                &mut position_record,
-                &message_sender,
-                &player_id,
-            );
-        }
-        if keyboard_input.pressed(KeyCode::Left) {
-            *logo = Direction::Left;
-            update_server(
-                transform.translation.x,
-                transform.translation.y,
-                &mut position_record,
-                &message_sender,
-                &player_id,
-            );
+fn show_rooms(
+    mut listed: EventReader<RoomsListed>,
+    mut refused: EventReader<RoomRefused>,
+    mut room_directory: ResMut<RoomDirectory>,
+) {
+    for RoomRefused(e) in refused.iter() {
+        println!("Could not change rooms: {}", e);
+    }
+    let Some(RoomsListed(rooms)) = listed.iter().last() else {
+        return;
+    };
+
+    println!("Rooms (press the number to join):");
+    for (index, room) in rooms.iter().enumerate() {
+        println!(
+            "  {}. {} {}/{}{}",
+            index + 1,
+            room.name,
+            room.players,
+            room.max_players,
+            if room.has_password { " (password)" } else { "" }
+        );
+    }
+    room_directory.listed = rooms.clone();
+}
+
+fn start_collision_flash(
+    mut commands: Commands,
+    mut collided: EventReader<PlayersCollided>,
+    mut players: Query<(Entity, &PlayerId, &mut Sprite)>,
+) {
+    let collisions = collided
+        .iter()
+        .map(|PlayersCollided(a, b)| {
+            println!("Players {} and {} collided", a, b);
+            (*a, *b)
+        })
+        .collect::<Vec<(u32, u32)>>();
+    if collisions.is_empty() {
+        return;
+    }
+
+    for (entity, player_id, mut sprite) in players.iter_mut() {
+        if collisions
+            .iter()
+            .any(|(a, b)| *a == player_id.0 || *b == player_id.0)
+        {
+            sprite.color = Color::RED;
+            commands
+                .entity(entity)
+                .insert(CollisionFlash(Timer::from_seconds(
+                    COLLISION_FLASH_SECS,
+                    TimerMode::Once,
+                )));
         }
-        if keyboard_input.pressed(KeyCode::Right) {
-            *logo = Direction::Right;
-            update_server(
-                transform.translation.x,
-                transform.translation.y,
-                &mut position_record,
-                &message_sender,
-                &player_id,
-            );
+    }
+}
+
+fn fade_collision_flash(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut flashing: Query<(Entity, &mut CollisionFlash, &mut Sprite)>,
+) {
+    for (entity, mut flash, mut sprite) in flashing.iter_mut() {
+        if flash.0.tick(time.delta()).finished() {
+            sprite.color = Color::WHITE;
+            commands.entity(entity).remove::<CollisionFlash>();
         }
-        if keyboard_input.just_released(KeyCode::Up)
-            || keyboard_input.just_released(KeyCode::Down)
-            || keyboard_input.just_released(KeyCode::Left)
-            || keyboard_input.just_released(KeyCode::Right)
+    }
+}
+
+/// `L` lists the rooms on the server, the number keys join one from the last listing.
+fn room_controls(
+    keyboard_input: Res<Input<KeyCode>>,
+    connection: Res<BellConnection>,
+    room_directory: Res<RoomDirectory>,
+) {
+    let Some(client) = connection.client() else {
+        return;
+    };
+    if keyboard_input.just_pressed(KeyCode::L) && client.list_rooms().is_err() {
+        println!("Failed to ask for the rooms");
+    }
+
+    let number_keys = [
+        KeyCode::Key1,
+        KeyCode::Key2,
+        KeyCode::Key3,
+        KeyCode::Key4,
+        KeyCode::Key5,
+        KeyCode::Key6,
+        KeyCode::Key7,
+        KeyCode::Key8,
+        KeyCode::Key9,
+    ];
+    let Some(index) = number_keys
+        .iter()
+        .position(|key| keyboard_input.just_pressed(*key))
+    else {
+        return;
+    };
+    if let Some(room) = room_directory.listed.get(index) {
+        if client
+            .join_room(&room.name, room_directory.password.clone())
+            .is_err()
         {
-            *logo = Direction::Still;
+            println!("Failed to ask to join the room");
+        }
+    }
+}
+
+/// Tells the server how much of the world we show, so that it only sends us the players we
+/// can see. Sent once we are connected and the window exists, and again whenever it is
+/// resized.
+fn send_view(
+    mut resized: EventReader<WindowResized>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    connection: Res<BellConnection>,
+    mut sent: Local<bool>,
+) {
+    let resized = resized.iter().last().is_some();
+    if *sent && !resized {
+        return;
+    }
+    let Some(client) = connection.client() else {
+        return;
+    };
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
+
+    // players whose sprite sticks into the window from outside are visible too
+    let margin = 2. * lib_udp_server::DEFAULT_HITBOX_SIZE;
+    if client
+        .set_view(window.width() + margin, window.height() + margin)
+        .is_err()
+    {
+        println!("Failed to tell the server our view");
+    }
+    *sent = true;
+}
+
+/// The sprite is animated by changing its translation depending on the time that has passed since
+/// the last frame. Every frame a direction is held our position is sent to the server.
+fn sprite_movement(
+    time: Res<Time>,
+    keyboard_input: Res<Input<KeyCode>>,
+    connection: Res<BellConnection>,
+    mut own: Query<(&mut Direction, &mut Transform), With<LocalPlayer>>,
+) {
+    let Ok((mut direction, mut transform)) = own.get_single_mut() else {
+        return;
+    };
+
+    match *direction {
+        Direction::Up => transform.translation.y += PLAYER_SPEED * time.delta_seconds(),
+        Direction::Down => transform.translation.y -= PLAYER_SPEED * time.delta_seconds(),
+        Direction::Left => transform.translation.x -= PLAYER_SPEED * time.delta_seconds(),
+        Direction::Right => transform.translation.x += PLAYER_SPEED * time.delta_seconds(),
+        Direction::Still => {}
+    }
+    // the server corrects positions outside the world it told us about, no need to walk there
+    let world = connection
+        .client()
+        .map_or_else(movement::WorldBounds::default, |client| client.world());
+    let (x, y) = world.clamp((transform.translation.x, transform.translation.y));
+    transform.translation.x = x;
+    transform.translation.y = y;
+
+    let held = [
+        (KeyCode::Up, Direction::Up),
+        (KeyCode::Down, Direction::Down),
+        (KeyCode::Left, Direction::Left),
+        (KeyCode::Right, Direction::Right),
+    ]
+    .into_iter()
+    .filter(|(key, _)| keyboard_input.pressed(*key))
+    .map(|(_, held)| held)
+    .next_back();
+    if let Some(held) = held {
+        *direction = held;
+        if let Some(client) = connection.client() {
+            if client.send_position(x, y).is_err() {
+                println!("Failed to send our position");
+            }
         }
     }
+    if keyboard_input.just_released(KeyCode::Up)
+        || keyboard_input.just_released(KeyCode::Down)
+        || keyboard_input.just_released(KeyCode::Left)
+        || keyboard_input.just_released(KeyCode::Right)
+    {
+        *direction = Direction::Still;
+    }
 }
 
-fn update_server(
-    x: f32,
-    y: f32,
-    position_record: &mut ResMut<SpritePositions>,
-    message_sender: &Res<MessageSender>,
-    player_id: &PlayerId,
+/// Tells the server which directions are held. Sent whenever they change and again every
+/// `INPUT_RESEND_INTERVAL` while they stay the same.
+fn send_inputs(
+    keyboard_input: Res<Input<KeyCode>>,
+    connection: Res<BellConnection>,
+    mut last_sent: Local<Option<(u8, std::time::Instant)>>,
 ) {
-    let position_record = position_record.position_collections.read().unwrap();
-    let position_record = position_record.get(&player_id.0).unwrap();
-    let mut x_ = position_record.x.write().unwrap();
-    let mut y_ = position_record.y.write().unwrap();
-    *x_ = x;
-    *y_ = y;
-
-    let _ = message_sender
-        .tx
-        .lock()
-        .unwrap()
-        .send(UpdateMessage::PositionChange);
+    let Some(client) = connection.client() else {
+        return;
+    };
+    let inputs = [
+        (KeyCode::Up, movement::input::UP),
+        (KeyCode::Down, movement::input::DOWN),
+        (KeyCode::Left, movement::input::LEFT),
+        (KeyCode::Right, movement::input::RIGHT),
+    ]
+    .into_iter()
+    .filter(|(key, _)| keyboard_input.pressed(*key))
+    .fold(0, |inputs, (_, bit)| inputs | bit);
+
+    let is_due = match *last_sent {
+        Some((last_inputs, sent_at)) => {
+            last_inputs != inputs || sent_at.elapsed() >= INPUT_RESEND_INTERVAL
+        }
+        None => true,
+    };
+    if is_due {
+        if client.send_input(inputs).is_err() {
+            println!("Failed to send inputs");
+        }
+        *last_sent = Some((inputs, std::time::Instant::now()));
+    }
 }
@@ -0,0 +1,386 @@
+use bevy::app::AppExit;
+use bevy::diagnostic::{Diagnostic, DiagnosticId, Diagnostics};
+use bevy::prelude::*;
+use lib_udp_server::client::{ClientError, ClientOptions, DisconnectReason};
+use lib_udp_server::interpolation::{self, JitterBuffer};
+use lib_udp_server::room::{RoomError, RoomInfo};
+use lib_udp_server::{BellClient, ClientEvent, Point};
+use std::sync::Arc;
+
+/// We are registered with the server as player `id`.
+pub struct Connected {
+    pub id: u32,
+    /// Capabilities the server accepted, see `protocol::capabilities`
+    pub capabilities: u32,
+}
+
+/// The server is full and put us in its waitlist, carries our 1-based place in it. Sent
+/// again whenever the place changes, until `Connected` or `Disconnected`.
+pub struct Waitlisted(pub u32);
+
+/// Nothing more will come from the server.
+pub enum Disconnected {
+    /// We never got in
+    Failed(ClientError),
+    /// The connection was lost after we got in
+    Lost(DisconnectReason),
+}
+
+/// A player came into view, or was already there when we connected or changed rooms. Sent
+/// once per player until it left, at the position it is at by the end of the frame.
+pub struct PlayerJoined(pub Point);
+
+/// A player is somewhere else now, our own one included when the server moves or corrects it.
+pub struct PlayerMoved(pub Point);
+
+/// A player left, timed out or went out of view. Only sent for players that joined before.
+pub struct PlayerLeft(pub u32);
+
+pub struct PlayersCollided(pub u32, pub u32);
+
+pub struct RoomsListed(pub Vec<RoomInfo>);
+
+/// We are in another room now. Everybody from the old one comes as `PlayerLeft`, the players
+/// of the new one as `PlayerJoined`.
+pub struct RoomJoined(pub String);
+
+pub struct RoomRefused(pub RoomError);
+
+/// The client once it is connected. Systems send through it, e.g.
+/// `connection.client().map(|client| client.send_input(inputs))`.
+#[derive(Resource)]
+pub struct BellConnection {
+    runtime: Arc<tokio::runtime::Runtime>,
+    client: Option<Arc<BellClient>>,
+    connecting:
+        Option<std::sync::Mutex<std::sync::mpsc::Receiver<Result<BellClient, ClientError>>>>,
+    waitlist: std::sync::Mutex<std::sync::mpsc::Receiver<u32>>,
+    roster: Roster,
+    jitter_buffer: JitterBuffer,
+}
+
+/// Where the remote players are shown this frame, played back from the jitter buffer.
+/// Players only appear here once a snapshot had them.
+#[derive(Resource, Default)]
+pub struct InterpolatedPositions(pub std::collections::HashMap<u32, Vec2>);
+
+impl BellConnection {
+    pub fn client(&self) -> Option<&BellClient> {
+        self.client.as_deref()
+    }
+}
+
+/// The remote players the game was told about. A frame's worth of joins and leaves is folded
+/// into at most one `PlayerJoined` or `PlayerLeft` per player, so that no burst can spawn a
+/// player twice or leave a sprite behind, whatever order the systems read the events in.
+#[derive(Default)]
+struct Roster {
+    known: std::collections::HashSet<u32>,
+}
+
+/// What the events of one frame add up to.
+#[derive(Default)]
+struct Changes {
+    left: Vec<u32>,
+    joined: Vec<Point>,
+    moved: Vec<Point>,
+}
+
+impl Changes {
+    fn moved(&mut self, point: Point) {
+        match self.joined.iter_mut().find(|joined| joined.id == point.id) {
+            // not spawned yet, it is spawned where it ends up
+            Some(joined) => *joined = point,
+            None => self.moved.push(point),
+        }
+    }
+}
+
+impl Roster {
+    fn join(&mut self, changes: &mut Changes, point: Point) {
+        if !self.known.insert(point.id) {
+            changes.moved(point);
+            return;
+        }
+        match changes.left.iter().position(|id| *id == point.id) {
+            // it left and came back within the frame, its sprite can stay
+            Some(index) => {
+                changes.left.swap_remove(index);
+                changes.moved.push(point);
+            }
+            None => changes.joined.push(point),
+        }
+    }
+
+    fn leave(&mut self, changes: &mut Changes, id: u32) {
+        if !self.known.remove(&id) {
+            return;
+        }
+        changes.moved.retain(|point| point.id != id);
+        match changes.joined.iter().position(|point| point.id == id) {
+            // it came and went within the frame, the game never sees it
+            Some(index) => {
+                changes.joined.remove(index);
+            }
+            None => changes.left.push(id),
+        }
+    }
+
+    fn clear(&mut self, changes: &mut Changes) {
+        let known = self.known.iter().copied().collect::<Vec<u32>>();
+        for id in known {
+            self.leave(changes, id);
+        }
+    }
+}
+
+/// Connects to a bell server in the background when the app starts and turns what the server
+/// sends into events. Runs in `PreUpdate`, so the events are there for every system of the
+/// frame. The server is told we left when the app exits.
+pub struct BellNetworkPlugin {
+    server: std::net::SocketAddr,
+    options: ClientOptions,
+    interpolation_delay: std::time::Duration,
+    max_extrapolation: std::time::Duration,
+}
+
+impl BellNetworkPlugin {
+    /// How many milliseconds the jitter buffer is ahead of the time shown.
+    pub const JITTER_BUFFER_DEPTH: DiagnosticId =
+        DiagnosticId::from_u128(157250480376528315929125380154062781183);
+    /// How often the jitter buffer ran dry so far.
+    pub const JITTER_BUFFER_UNDERRUNS: DiagnosticId =
+        DiagnosticId::from_u128(281093413845916201734385460839718322241);
+    /// How many snapshots arrived too late to be shown so far.
+    pub const LATE_SNAPSHOTS: DiagnosticId =
+        DiagnosticId::from_u128(94761870251318442680531951473092866013);
+
+    pub fn new(server: std::net::SocketAddr, options: ClientOptions) -> Self {
+        Self {
+            server,
+            options,
+            interpolation_delay: interpolation::DEFAULT_DELAY,
+            max_extrapolation: interpolation::DEFAULT_MAX_EXTRAPOLATION,
+        }
+    }
+
+    /// How far behind the server remote players are shown, and how far they keep moving when
+    /// no snapshot came in time, see `JitterBuffer`.
+    pub fn interpolation(
+        mut self,
+        delay: std::time::Duration,
+        max_extrapolation: std::time::Duration,
+    ) -> Self {
+        self.interpolation_delay = delay;
+        self.max_extrapolation = max_extrapolation;
+        self
+    }
+}
+
+impl Plugin for BellNetworkPlugin {
+    fn build(&self, app: &mut App) {
+        let runtime = match tokio::runtime::Runtime::new() {
+            Ok(runtime) => Arc::new(runtime),
+            Err(e) => panic!("Failed to start the network runtime: {}", e),
+        };
+        let (connected, connecting) = std::sync::mpsc::channel();
+        let (waitlisted, waitlist) = std::sync::mpsc::channel();
+        let waitlisted = std::sync::Mutex::new(waitlisted);
+        let server = self.server;
+        let mut options = self.options.clone();
+        // the callback the game passed in, if any, still gets called
+        let callback = options.waitlisted.take();
+        options.waitlisted = Some(Arc::new(move |position| {
+            if let Some(callback) = &callback {
+                callback(position);
+            }
+            _ = waitlisted.lock().unwrap().send(position);
+        }));
+        runtime.spawn(async move {
+            _ = connected.send(BellClient::connect_with(server, options).await);
+        });
+
+        app.add_event::<Connected>()
+            .add_event::<Waitlisted>()
+            .add_event::<Disconnected>()
+            .add_event::<PlayerJoined>()
+            .add_event::<PlayerMoved>()
+            .add_event::<PlayerLeft>()
+            .add_event::<PlayersCollided>()
+            .add_event::<RoomsListed>()
+            .add_event::<RoomJoined>()
+            .add_event::<RoomRefused>()
+            .insert_resource(BellConnection {
+                runtime,
+                client: None,
+                connecting: Some(std::sync::Mutex::new(connecting)),
+                waitlist: std::sync::Mutex::new(waitlist),
+                roster: Roster::default(),
+                jitter_buffer: JitterBuffer::new(self.interpolation_delay, self.max_extrapolation),
+            })
+            .init_resource::<InterpolatedPositions>()
+            .add_startup_system(setup_diagnostics)
+            .add_system(finish_connecting.in_base_set(CoreSet::PreUpdate))
+            .add_system(
+                receive_events
+                    .in_base_set(CoreSet::PreUpdate)
+                    .after(finish_connecting),
+            )
+            .add_system(
+                interpolate
+                    .in_base_set(CoreSet::PreUpdate)
+                    .after(receive_events),
+            )
+            .add_system(leave_on_exit.in_base_set(CoreSet::Last));
+    }
+}
+
+fn finish_connecting(
+    mut connection: ResMut<BellConnection>,
+    mut connected: EventWriter<Connected>,
+    mut waitlisted: EventWriter<Waitlisted>,
+    mut disconnected: EventWriter<Disconnected>,
+) {
+    let Some(connecting) = &connection.connecting else {
+        return;
+    };
+    waitlisted.send_batch(
+        connection
+            .waitlist
+            .lock()
+            .unwrap()
+            .try_iter()
+            .map(Waitlisted),
+    );
+    let result = match connecting.lock().unwrap().try_recv() {
+        Ok(result) => result,
+        Err(std::sync::mpsc::TryRecvError::Empty) => return,
+        Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+            Err(ClientError::Io(std::io::ErrorKind::Interrupted.into()))
+        }
+    };
+    connection.connecting = None;
+
+    match result {
+        Ok(client) => {
+            connected.send(Connected {
+                id: client.id(),
+                capabilities: client.capabilities(),
+            });
+            connection.client = Some(Arc::new(client));
+        }
+        Err(e) => disconnected.send(Disconnected::Failed(e)),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn receive_events(
+    mut connection: ResMut<BellConnection>,
+    mut joined: EventWriter<PlayerJoined>,
+    mut moved: EventWriter<PlayerMoved>,
+    mut left: EventWriter<PlayerLeft>,
+    mut collided: EventWriter<PlayersCollided>,
+    mut listed: EventWriter<RoomsListed>,
+    mut room_joined: EventWriter<RoomJoined>,
+    mut refused: EventWriter<RoomRefused>,
+    mut disconnected: EventWriter<Disconnected>,
+) {
+    let BellConnection {
+        client,
+        roster,
+        jitter_buffer,
+        ..
+    } = &mut *connection;
+    let Some(client) = client else {
+        return;
+    };
+    let mut changes = Changes::default();
+    while let Some(event) = client.try_next_event() {
+        match event {
+            ClientEvent::PlayerJoined(point) => roster.join(&mut changes, point),
+            ClientEvent::PlayerMoved(point) => changes.moved(point),
+            ClientEvent::PlayerLeft(id) => roster.leave(&mut changes, id),
+            ClientEvent::Snapshot(time, points) => {
+                jitter_buffer.push(time, points, std::time::Instant::now());
+            }
+            ClientEvent::Collision(a, b) => collided.send(PlayersCollided(a, b)),
+            ClientEvent::RoomList(rooms) => listed.send(RoomsListed(rooms)),
+            ClientEvent::RoomJoined(name) => {
+                roster.clear(&mut changes);
+                jitter_buffer.clear();
+                room_joined.send(RoomJoined(name));
+            }
+            ClientEvent::RoomError(e) => refused.send(RoomRefused(e)),
+            ClientEvent::Disconnected(reason) => disconnected.send(Disconnected::Lost(reason)),
+        }
+    }
+
+    left.send_batch(changes.left.into_iter().map(PlayerLeft));
+    joined.send_batch(changes.joined.into_iter().map(PlayerJoined));
+    moved.send_batch(changes.moved.into_iter().map(PlayerMoved));
+}
+
+/// Diagnostics are only kept when the app has the `DiagnosticsPlugin`.
+fn setup_diagnostics(diagnostics: Option<ResMut<Diagnostics>>) {
+    let Some(mut diagnostics) = diagnostics else {
+        return;
+    };
+    diagnostics.add(
+        Diagnostic::new(
+            BellNetworkPlugin::JITTER_BUFFER_DEPTH,
+            "jitter_buffer_depth",
+            20,
+        )
+        .with_suffix("ms"),
+    );
+    diagnostics.add(
+        Diagnostic::new(
+            BellNetworkPlugin::JITTER_BUFFER_UNDERRUNS,
+            "jitter_buffer_underruns",
+            1,
+        )
+        .with_smoothing_factor(0.0),
+    );
+    diagnostics.add(
+        Diagnostic::new(BellNetworkPlugin::LATE_SNAPSHOTS, "late_snapshots", 1)
+            .with_smoothing_factor(0.0),
+    );
+}
+
+fn interpolate(
+    mut connection: ResMut<BellConnection>,
+    mut interpolated: ResMut<InterpolatedPositions>,
+    diagnostics: Option<ResMut<Diagnostics>>,
+) {
+    interpolated.0 = connection
+        .jitter_buffer
+        .sample(std::time::Instant::now())
+        .into_iter()
+        .map(|point| (point.id, Vec2::new(point.x, point.y)))
+        .collect();
+
+    let Some(mut diagnostics) = diagnostics else {
+        return;
+    };
+    let stats = connection.jitter_buffer.stats();
+    diagnostics.add_measurement(BellNetworkPlugin::JITTER_BUFFER_DEPTH, || {
+        stats.depth.as_secs_f64() * 1000.
+    });
+    diagnostics.add_measurement(BellNetworkPlugin::JITTER_BUFFER_UNDERRUNS, || {
+        stats.underruns as f64
+    });
+    diagnostics.add_measurement(BellNetworkPlugin::LATE_SNAPSHOTS, || stats.late as f64);
+}
+
+fn leave_on_exit(mut exit_events: EventReader<AppExit>, connection: Res<BellConnection>) {
+    if exit_events.iter().next().is_none() {
+        return;
+    }
+    let Some(client) = &connection.client else {
+        return;
+    };
+
+    // waits a moment for the server to ack, if it does not the server falls back to the
+    // heartbeat timeout
+    connection.runtime.block_on(client.shutdown());
+}
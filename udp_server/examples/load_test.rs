@@ -0,0 +1,172 @@
+//! Simulates a crowd of players against a running server, to see how the tick and the
+//! outgoing traffic hold up. Every player is a `BellClient` on its own socket that walks
+//! around by sending inputs, like the game client does. The client keeps it alive and acks
+//! its snapshots.
+//!
+//! ```text
+//! cargo run --release -p udp_server -- --max-players 256 --world-width 4000 --world-height 4000
+//! cargo run --release -p udp_server --example load_test -- --players 250
+//! ```
+
+use clap::Parser;
+use lib_udp_server::client::{BellClient, ClientEvent, ClientOptions};
+use lib_udp_server::movement::input;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// How often a simulated player picks new directions.
+const INPUT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+#[derive(clap::Parser, Debug)]
+#[command(
+    name = "load_test",
+    about = "Simulates many players against a bell server"
+)]
+struct Cli {
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    server: std::net::SocketAddr,
+    #[arg(long, default_value_t = 200)]
+    players: usize,
+    /// How long to keep the players around once they are started
+    #[arg(long, default_value_t = 30)]
+    secs: u64,
+    /// Side of the square around the origin the players start in. Should fit in the world of
+    /// the server, everybody starting on top of each other mostly measures collisions
+    #[arg(long, default_value_t = 4000.)]
+    area: f32,
+}
+
+#[derive(Default)]
+struct Stats {
+    registered: AtomicU64,
+    waitlisted: AtomicU64,
+    registration_micros: AtomicU64,
+    datagrams: AtomicU64,
+    bytes: AtomicU64,
+    snapshots: AtomicU64,
+    players_seen: AtomicU64,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let stats = Arc::new(Stats::default());
+    println!(
+        "Starting {} players against {} for {} seconds",
+        cli.players, cli.server, cli.secs
+    );
+
+    for _ in 0..cli.players {
+        let start = (
+            (rand::random::<f32>() - 0.5) * cli.area,
+            (rand::random::<f32>() - 0.5) * cli.area,
+        );
+        tokio::spawn(play(cli.server, start, stats.clone()));
+    }
+
+    let started = std::time::Instant::now();
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    interval.tick().await;
+    let mut last = (0, 0, 0);
+    while started.elapsed() < std::time::Duration::from_secs(cli.secs) {
+        interval.tick().await;
+        let registered = stats.registered.load(Ordering::Relaxed);
+        let snapshots = stats.snapshots.load(Ordering::Relaxed);
+        let bytes = stats.bytes.load(Ordering::Relaxed);
+        let datagrams = stats.datagrams.load(Ordering::Relaxed);
+        println!(
+            "{:>3}s: {} registered, {} waitlisted, {} snapshots/s, {} datagrams/s, {:.1} KB/s per player",
+            started.elapsed().as_secs(),
+            registered,
+            stats.waitlisted.load(Ordering::Relaxed),
+            snapshots - last.0,
+            datagrams - last.1,
+            (bytes - last.2) as f64 / 1024. / registered.max(1) as f64
+        );
+        last = (snapshots, datagrams, bytes);
+    }
+
+    let registered = stats.registered.load(Ordering::Relaxed);
+    let snapshots = stats.snapshots.load(Ordering::Relaxed);
+    println!(
+        "{} of {} players registered, {:.1} ms on average",
+        registered,
+        cli.players,
+        stats.registration_micros.load(Ordering::Relaxed) as f64 / 1000. / registered.max(1) as f64
+    );
+    println!(
+        "{} snapshots with {:.1} players in view on average, {:.1} bytes per datagram",
+        snapshots,
+        stats.players_seen.load(Ordering::Relaxed) as f64 / snapshots.max(1) as f64,
+        stats.bytes.load(Ordering::Relaxed) as f64
+            / stats.datagrams.load(Ordering::Relaxed).max(1) as f64
+    );
+
+    Ok(())
+}
+
+/// One simulated player, runs until the process exits or the connection is lost. Players
+/// that end up in the waitlist wait until they are let in.
+async fn play(server: std::net::SocketAddr, start: (f32, f32), stats: Arc<Stats>) {
+    let waitlisted = Arc::new(AtomicBool::new(false));
+    let options = ClientOptions {
+        start,
+        waitlisted: Some(Arc::new({
+            let waitlisted = waitlisted.clone();
+            let stats = stats.clone();
+            move |_| {
+                if !waitlisted.swap(true, Ordering::Relaxed) {
+                    stats.waitlisted.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        })),
+        ..ClientOptions::default()
+    };
+    let started = std::time::Instant::now();
+    let mut client = match BellClient::connect_with(server, options).await {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to register: {}", e);
+            return;
+        }
+    };
+    if waitlisted.load(Ordering::Relaxed) {
+        stats.waitlisted.fetch_sub(1, Ordering::Relaxed);
+    }
+    stats.registered.fetch_add(1, Ordering::Relaxed);
+    stats
+        .registration_micros
+        .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+
+    let mut counted = (0, 0);
+    let mut inputs = tokio::time::interval(INPUT_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = inputs.tick() => {
+                let held = rand::random::<u8>()
+                    & (input::UP | input::DOWN | input::LEFT | input::RIGHT);
+                // a full socket buffer only delays the next direction change
+                _ = client.send_input(held);
+
+                let (datagrams, bytes) = client.received();
+                stats.datagrams.fetch_add(datagrams - counted.0, Ordering::Relaxed);
+                stats.bytes.fetch_add(bytes - counted.1, Ordering::Relaxed);
+                counted = (datagrams, bytes);
+            }
+            event = client.next_event() => match event {
+                Some(ClientEvent::Snapshot(_, positions)) => {
+                    stats.snapshots.fetch_add(1, Ordering::Relaxed);
+                    stats
+                        .players_seen
+                        .fetch_add(positions.len() as u64, Ordering::Relaxed);
+                }
+                Some(ClientEvent::Disconnected(reason)) => {
+                    eprintln!("Player {} lost the connection: {:?}", client.id(), reason);
+                    return;
+                }
+                None => return,
+                Some(_) => {}
+            },
+        }
+    }
+}
@@ -0,0 +1,584 @@
+use crate::movement::WorldBounds;
+use crate::protocol::{self, capabilities, PROTOCOL_VERSION};
+use crate::reliability::{Connection, Delivery};
+use crate::room::{RoomError, RoomInfo};
+use crate::snapshot::Baselines;
+use crate::{fragment, BellMessage, Point, Registration, WireFormat};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+
+/// How often unacked reliable messages are checked for a retransmission.
+const RETRANSMIT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(20);
+/// How long `shutdown` waits for the server to ack that we left.
+const LEAVE_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Something the server told us, in the order it arrived.
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// A player came into view, or was already in view when we registered or changed rooms
+    PlayerJoined(Point),
+    /// A player is somewhere else now. Our own player shows up here when the server moves it
+    /// or corrects it
+    PlayerMoved(Point),
+    /// A player left, timed out or went out of view
+    PlayerLeft(u32),
+    /// Where every player in view was at a server time, in milliseconds since the server
+    /// started. Follows the `PlayerMoved` of every snapshot, to be smoothed out with an
+    /// `interpolation::JitterBuffer`
+    Snapshot(u32, Vec<Point>),
+    Collision(u32, u32),
+    RoomList(Vec<RoomInfo>),
+    /// We are in another room now, everybody from the old one is gone and the players seen in
+    /// the new one come as `PlayerJoined` right after
+    RoomJoined(String),
+    RoomError(RoomError),
+    /// Nothing more will come, the client has to connect again
+    Disconnected(DisconnectReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Not even our heartbeats were acked for `ClientOptions::silence_timeout`
+    ServerSilent,
+    /// The socket failed
+    Io(std::io::ErrorKind),
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(std::io::Error),
+    /// The server did not answer for `ClientOptions::timeout`
+    Timeout,
+    /// The server needs at least this protocol version
+    Rejected(u8),
+    /// The server requires these capabilities, which were not asked for
+    MissingCapabilities(u32),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::Timeout => write!(f, "the server is not responding"),
+            ClientError::Rejected(min_version) => write!(
+                f,
+                "the server requires protocol version {} but this client speaks version {}",
+                min_version, PROTOCOL_VERSION
+            ),
+            ClientError::MissingCapabilities(missing) => write!(
+                f,
+                "the server requires {:?}, which this client did not ask for",
+                capabilities::names(*missing)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+/// How to connect. The defaults match what the game does.
+#[derive(Clone)]
+pub struct ClientOptions {
+    /// Local address to send from, any address of the server's family if None
+    pub bind: Option<std::net::SocketAddr>,
+    /// Where our player starts
+    pub start: (f32, f32),
+    /// Optional features we ask for, see `protocol::capabilities`
+    pub capabilities: u32,
+    pub wire_format: WireFormat,
+    pub mtu: usize,
+    /// How long registering waits for an answer before giving up. Challenges and waitlist
+    /// updates count as answers, so waiting in a full server never times out
+    pub timeout: std::time::Duration,
+    /// How long the server may stay silent once we are registered before we count as
+    /// disconnected
+    pub silence_timeout: std::time::Duration,
+    /// Called with our place in the waitlist while the server is full
+    pub waitlisted: Option<Arc<dyn Fn(u32) + Send + Sync>>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            bind: None,
+            start: (0., 0.),
+            capabilities: capabilities::ALL,
+            wire_format: WireFormat::default(),
+            mtu: fragment::DEFAULT_MTU,
+            timeout: protocol::HEARTBEAT_INTERVAL * 5,
+            silence_timeout: protocol::DEFAULT_PLAYER_TIMEOUT,
+            waitlisted: None,
+        }
+    }
+}
+
+/// The socket and the reliability state, shared by the client and its receive task. Sends go
+/// through a std handle of the same nonblocking socket, so that they work from any thread
+/// without waiting.
+struct Link {
+    socket: UdpSocket,
+    sender: std::net::UdpSocket,
+    connection: std::sync::Mutex<Connection>,
+    /// Datagrams and bytes received so far
+    received: (std::sync::atomic::AtomicU64, std::sync::atomic::AtomicU64),
+}
+
+impl Link {
+    fn new(
+        local: std::net::SocketAddr,
+        server: std::net::SocketAddr,
+        wire_format: WireFormat,
+        mtu: usize,
+    ) -> std::io::Result<Self> {
+        let socket = std::net::UdpSocket::bind(local)?;
+        socket.connect(server)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            sender: socket.try_clone()?,
+            socket: UdpSocket::from_std(socket)?,
+            connection: std::sync::Mutex::new(Connection::new(wire_format, mtu)),
+            received: Default::default(),
+        })
+    }
+
+    fn send(&self, message: &BellMessage) -> std::io::Result<()> {
+        self.send_with(message, message.delivery())
+    }
+
+    /// Sends without waiting, a full socket buffer is reported as `WouldBlock`. Reliable
+    /// messages are retransmitted anyway. A message that cannot be encoded is reported as
+    /// `InvalidInput`.
+    fn send_with(&self, message: &BellMessage, delivery: Delivery) -> std::io::Result<()> {
+        let datagrams = self
+            .connection
+            .lock()
+            .unwrap()
+            .send_with(message, delivery, std::time::Instant::now())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        for data in datagrams {
+            self.sender.send(&data)?;
+        }
+
+        Ok(())
+    }
+
+    fn retransmit(&self) -> std::io::Result<()> {
+        let due = self
+            .connection
+            .lock()
+            .unwrap()
+            .poll(std::time::Instant::now());
+        for data in due {
+            self.sender.send(&data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads one datagram, acks it if needed and returns the messages it made available.
+    /// Nothing is lost when the future is dropped, the acks are sent without waiting.
+    async fn recv(&self, buf: &mut [u8]) -> std::io::Result<Vec<BellMessage>> {
+        let size = self.socket.recv(buf).await?;
+        self.received
+            .0
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.received
+            .1
+            .fetch_add(size as u64, std::sync::atomic::Ordering::Relaxed);
+        let received = self
+            .connection
+            .lock()
+            .unwrap()
+            .receive(&buf[..size], std::time::Instant::now());
+        let received = match received {
+            Ok(received) => received,
+            Err(e) => {
+                log::warn!("Failed to parse message from the server: {}", e);
+                return Ok(vec![]);
+            }
+        };
+        for reply in received.replies {
+            // a lost ack only costs a retransmission
+            _ = self.sender.send(&reply);
+        }
+
+        Ok(received.messages)
+    }
+}
+
+/// A connection to a bell server, registered as one player. A background task keeps it
+/// alive, retransmits and acks, and turns what the server sends into `ClientEvent`s.
+///
+/// Needs a tokio runtime to connect and shut down, the other methods can be called from any
+/// thread.
+pub struct BellClient {
+    link: Arc<Link>,
+    id: u32,
+    capabilities: u32,
+    world: WorldBounds,
+    server: std::net::SocketAddr,
+    input_sequence: std::sync::atomic::AtomicU32,
+    events: std::sync::Mutex<mpsc::UnboundedReceiver<ClientEvent>>,
+    stop: std::sync::Mutex<Option<oneshot::Sender<()>>>,
+    receiving: std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+impl BellClient {
+    pub async fn connect(server: std::net::SocketAddr) -> Result<Self, ClientError> {
+        Self::connect_with(server, ClientOptions::default()).await
+    }
+
+    /// Registers with the server, answering its challenge and waiting in its waitlist if it
+    /// is full, and starts the receive task.
+    pub async fn connect_with(
+        server: std::net::SocketAddr,
+        options: ClientOptions,
+    ) -> Result<Self, ClientError> {
+        let bind = options.bind.unwrap_or(match server {
+            std::net::SocketAddr::V4(_) => {
+                std::net::SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, 0))
+            }
+            std::net::SocketAddr::V6(_) => {
+                std::net::SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0))
+            }
+        });
+        let link = Arc::new(Link::new(bind, server, options.wire_format, options.mtu)?);
+
+        let mut registration = Registration {
+            point: Point {
+                x: options.start.0,
+                y: options.start.1,
+                id: 0,
+            },
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: options.capabilities,
+            cookie: 0,
+        };
+        let mut buf = vec![0; fragment::RECV_BUFFER_LEN];
+        // only one registration is in flight at a time, the reliability layer resends it if it
+        // got lost
+        link.send(&BellMessage::PlayerRegistrationMessage(
+            registration.clone(),
+        ))?;
+        // when we last told the server we are still waiting, None until it waitlisted us
+        let mut refreshed: Option<std::time::Instant> = None;
+        let mut last_answer = std::time::Instant::now();
+        let (id, points, accepted, world, pending) = 'registration: loop {
+            // keeps our place in the waitlist, unreliable since the next one replaces it
+            if refreshed.is_some_and(|sent| sent.elapsed() >= protocol::HEARTBEAT_INTERVAL) {
+                link.send_with(
+                    &BellMessage::PlayerRegistrationMessage(registration.clone()),
+                    Delivery::Unreliable,
+                )?;
+                refreshed = Some(std::time::Instant::now());
+            }
+            if last_answer.elapsed() >= options.timeout {
+                return Err(ClientError::Timeout);
+            }
+
+            // a lost registration would hold up the ordered channel, so keep retransmitting
+            let received =
+                tokio::time::timeout(RETRANSMIT_POLL_INTERVAL, link.recv(&mut buf)).await;
+            link.retransmit()?;
+            let Ok(messages) = received else {
+                continue;
+            };
+            let mut messages = messages?.into_iter();
+            while let Some(message) = messages.next() {
+                match message {
+                    // the server wants proof that we can receive at our address, register
+                    // again right away with the cookie. The same cookie again only answers a
+                    // registration that was already on its way
+                    BellMessage::ChallengeMessage(cookie) => {
+                        last_answer = std::time::Instant::now();
+                        if cookie != registration.cookie {
+                            registration.cookie = cookie;
+                            link.send(&BellMessage::PlayerRegistrationMessage(
+                                registration.clone(),
+                            ))?;
+                        }
+                    }
+                    BellMessage::ServerFullMessage(position) => {
+                        last_answer = std::time::Instant::now();
+                        refreshed.get_or_insert(last_answer);
+                        if let Some(waitlisted) = &options.waitlisted {
+                            waitlisted(position);
+                        }
+                    }
+                    BellMessage::RegistrationReplyMessage(id, points, accepted, token, world) => {
+                        link.connection.lock().unwrap().set_session(token);
+                        // released by the ordered channel together with the reply
+                        let pending = messages.collect::<Vec<_>>();
+                        break 'registration (id, points, accepted, world, pending);
+                    }
+                    BellMessage::RegistrationRejectedMessage(min_version) => {
+                        return Err(ClientError::Rejected(min_version));
+                    }
+                    BellMessage::MissingCapabilitiesMessage(missing) => {
+                        return Err(ClientError::MissingCapabilities(missing));
+                    }
+                    _ => {}
+                }
+            }
+        };
+
+        let (events, receiver) = mpsc::unbounded_channel();
+        let mut task = ReceiveTask {
+            link: link.clone(),
+            id,
+            baselines: Baselines::default(),
+            events,
+            silence_timeout: options.silence_timeout,
+        };
+        for point in points {
+            task.emit(ClientEvent::PlayerJoined(point));
+        }
+        for message in pending {
+            task.handle(message);
+        }
+        let (stop, stopped) = oneshot::channel();
+        let receiving = tokio::spawn(task.run(stopped));
+
+        Ok(Self {
+            link,
+            id,
+            capabilities: accepted,
+            world,
+            server,
+            input_sequence: std::sync::atomic::AtomicU32::new(0),
+            events: std::sync::Mutex::new(receiver),
+            stop: std::sync::Mutex::new(Some(stop)),
+            receiving: std::sync::Mutex::new(Some(receiving)),
+        })
+    }
+
+    /// The id the server gave our player.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The capabilities the server accepted, a subset of the ones asked for.
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    /// The area the server keeps players in.
+    pub fn world(&self) -> WorldBounds {
+        self.world
+    }
+
+    pub fn server_addr(&self) -> std::net::SocketAddr {
+        self.server
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.link.socket.local_addr()
+    }
+
+    /// How many datagrams and bytes came from the server so far, the registration included.
+    pub fn received(&self) -> (u64, u64) {
+        let (datagrams, bytes) = &self.link.received;
+        (
+            datagrams.load(std::sync::atomic::Ordering::Relaxed),
+            bytes.load(std::sync::atomic::Ordering::Relaxed),
+        )
+    }
+
+    /// Sends any message as it is. The others are shortcuts for the usual ones.
+    pub fn send(&self, message: &BellMessage) -> std::io::Result<()> {
+        self.link.send(message)
+    }
+
+    /// Reports where our player is, for clients that move it themselves.
+    pub fn send_position(&self, x: f32, y: f32) -> std::io::Result<()> {
+        self.send(&BellMessage::PositionChangeMessage(Point {
+            x,
+            y,
+            id: self.id,
+        }))
+    }
+
+    /// Tells the server which directions are held (see `movement::input`), for clients that
+    /// let the server move their player.
+    pub fn send_input(&self, inputs: u8) -> std::io::Result<()> {
+        let sequence = self
+            .input_sequence
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            .wrapping_add(1);
+        self.send(&BellMessage::InputMessage(self.id, sequence, inputs))
+    }
+
+    /// Asks to only hear about players in this area around ours.
+    pub fn set_view(&self, width: f32, height: f32) -> std::io::Result<()> {
+        self.send(&BellMessage::SetViewMessage(self.id, width, height))
+    }
+
+    pub fn list_rooms(&self) -> std::io::Result<()> {
+        self.send(&BellMessage::ListRoomsMessage(self.id))
+    }
+
+    pub fn create_room(
+        &self,
+        name: &str,
+        max_players: u16,
+        password: Option<String>,
+    ) -> std::io::Result<()> {
+        self.send(&BellMessage::CreateRoomMessage(
+            self.id,
+            name.to_string(),
+            max_players,
+            password,
+        ))
+    }
+
+    pub fn join_room(&self, name: &str, password: Option<String>) -> std::io::Result<()> {
+        self.send(&BellMessage::JoinRoomMessage(
+            self.id,
+            name.to_string(),
+            password,
+        ))
+    }
+
+    /// The next event if there is one, for callers that poll, e.g. once a frame.
+    pub fn try_next_event(&self) -> Option<ClientEvent> {
+        self.events.lock().unwrap().try_recv().ok()
+    }
+
+    /// Waits for the next event. None once the client was shut down or disconnected and
+    /// every event was taken.
+    pub async fn next_event(&mut self) -> Option<ClientEvent> {
+        self.events.get_mut().unwrap().recv().await
+    }
+
+    /// Tells the server we are leaving, waits a moment for it to ack that and stops the
+    /// receive task. Nothing should be sent afterwards.
+    pub async fn shutdown(&self) {
+        let Some(stop) = self.stop.lock().unwrap().take() else {
+            return;
+        };
+        if self
+            .link
+            .send(&BellMessage::PlayerLeaveMessage(self.id))
+            .is_ok()
+        {
+            let deadline = tokio::time::Instant::now() + LEAVE_TIMEOUT;
+            while self.link.connection.lock().unwrap().has_pending()
+                && tokio::time::Instant::now() < deadline
+            {
+                tokio::time::sleep(RETRANSMIT_POLL_INTERVAL).await;
+            }
+        }
+
+        _ = stop.send(());
+        let receiving = self.receiving.lock().unwrap().take();
+        if let Some(receiving) = receiving {
+            _ = receiving.await;
+        }
+    }
+}
+
+/// Reads from the server until the client stops it or the connection is lost. Dropping the
+/// client stops it too.
+struct ReceiveTask {
+    link: Arc<Link>,
+    id: u32,
+    // snapshots are deltas against earlier ones, so they are applied in arrival order
+    baselines: Baselines,
+    events: mpsc::UnboundedSender<ClientEvent>,
+    silence_timeout: std::time::Duration,
+}
+
+impl ReceiveTask {
+    async fn run(mut self, mut stopped: oneshot::Receiver<()>) {
+        let mut buf = vec![0; fragment::RECV_BUFFER_LEN];
+        let mut retransmit = tokio::time::interval(RETRANSMIT_POLL_INTERVAL);
+        let mut heartbeat = tokio::time::interval(protocol::HEARTBEAT_INTERVAL);
+        let reason = loop {
+            tokio::select! {
+                _ = &mut stopped => return,
+                _ = retransmit.tick() => {
+                    if let Err(e) = self.link.retransmit() {
+                        break DisconnectReason::Io(e.kind());
+                    }
+                    let last_received = self.link.connection.lock().unwrap().last_received();
+                    if last_received.elapsed() >= self.silence_timeout {
+                        break DisconnectReason::ServerSilent;
+                    }
+                }
+                // sent reliably so that the ack tells us the server is still there while it
+                // has nothing else to send
+                _ = heartbeat.tick() => {
+                    let heartbeat = BellMessage::HeartbeatMessage(self.id);
+                    if let Err(e) = self.link.send_with(&heartbeat, Delivery::ReliableUnordered) {
+                        log::debug!("Failed to send heartbeat: {}", e);
+                    }
+                }
+                received = self.link.recv(&mut buf) => match received {
+                    Ok(messages) => {
+                        for message in messages {
+                            self.handle(message);
+                        }
+                    }
+                    Err(e) => break DisconnectReason::Io(e.kind()),
+                },
+            }
+        };
+
+        log::info!(
+            "Disconnected from {:?}: {:?}",
+            self.link.socket.peer_addr(),
+            reason
+        );
+        self.emit(ClientEvent::Disconnected(reason));
+    }
+
+    fn handle(&mut self, message: BellMessage) {
+        match message {
+            BellMessage::SnapshotMessage(snapshot) => {
+                let Some(moved) = self.baselines.apply(&snapshot) else {
+                    return;
+                };
+                let ack = BellMessage::SnapshotAckMessage(self.id, snapshot.tick);
+                if let Err(e) = self.link.send(&ack) {
+                    log::debug!("Failed to ack snapshot {}: {}", snapshot.tick, e);
+                }
+                for point in moved {
+                    self.emit(ClientEvent::PlayerMoved(point));
+                }
+                self.emit(ClientEvent::Snapshot(
+                    snapshot.time,
+                    self.baselines.newest(),
+                ));
+            }
+            BellMessage::PositionChangeMessage(point) => self.emit(ClientEvent::PlayerMoved(point)),
+            BellMessage::PlayerInsertionMessage(point) | BellMessage::EnterViewMessage(point) => {
+                self.emit(ClientEvent::PlayerJoined(point));
+            }
+            BellMessage::PlayerRemovalMessage(id) | BellMessage::LeaveViewMessage(id) => {
+                self.emit(ClientEvent::PlayerLeft(id));
+            }
+            BellMessage::CollisionMessage(a, b) => self.emit(ClientEvent::Collision(a, b)),
+            BellMessage::RoomListMessage(rooms) => self.emit(ClientEvent::RoomList(rooms)),
+            BellMessage::RoomJoinedMessage(name, points) => {
+                self.emit(ClientEvent::RoomJoined(name));
+                for point in points {
+                    self.emit(ClientEvent::PlayerJoined(point));
+                }
+            }
+            BellMessage::RoomErrorMessage(e) => self.emit(ClientEvent::RoomError(e)),
+            _ => {}
+        }
+    }
+
+    fn emit(&self, event: ClientEvent) {
+        // a client that is not interested in the events anymore is fine
+        _ = self.events.send(event);
+    }
+}
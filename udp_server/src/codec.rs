@@ -0,0 +1,912 @@
+use crate::movement::WorldBounds;
+use crate::protocol::{self, PROTOCOL_VERSION};
+use crate::room::{RoomError, RoomInfo};
+use crate::snapshot::{Snapshot, SnapshotEntry};
+use crate::{BellMessage, Point, Registration};
+
+/// First two bytes of every binary datagram.
+pub const MAGIC: [u8; 2] = *b"BL";
+/// magic (2) + protocol version (1) + message kind (1)
+pub const HEADER_LEN: usize = 4;
+
+const POINT_LEN: usize = 12;
+
+/// Message kinds as they appear in the header.
+pub mod kind {
+    pub const POSITION_CHANGE: u8 = 0;
+    pub const DEFER: u8 = 1;
+    pub const PLAYER_INSERTION: u8 = 2;
+    pub const PLAYER_REGISTRATION: u8 = 3;
+    pub const REGISTRATION_REPLY: u8 = 4;
+    /// The layout of this kind is frozen (a single version byte) so that clients of any
+    /// version can read why they were turned away.
+    pub const REGISTRATION_REJECTED: u8 = 5;
+    pub const PLAYER_LEAVE: u8 = 6;
+    pub const HEARTBEAT: u8 = 7;
+    pub const PLAYER_REMOVAL: u8 = 8;
+    pub const SERVER_FULL: u8 = 9;
+    pub const INPUT: u8 = 10;
+    pub const COLLISION: u8 = 11;
+    /// Transport framing used by `reliability`, these wrap or acknowledge other datagrams and
+    /// are not messages of their own. Their layout is frozen like the rejection's.
+    pub const RELIABLE: u8 = 12;
+    pub const ACK: u8 = 13;
+    /// One piece of a datagram that was larger than the MTU, see `fragment`.
+    pub const FRAGMENT: u8 = 14;
+    pub const LIST_ROOMS: u8 = 15;
+    pub const ROOM_LIST: u8 = 16;
+    pub const CREATE_ROOM: u8 = 17;
+    pub const JOIN_ROOM: u8 = 18;
+    pub const ROOM_JOINED: u8 = 19;
+    pub const ROOM_ERROR: u8 = 20;
+    /// Transport framing that prefixes a client datagram with its session token.
+    pub const SESSION: u8 = 21;
+    pub const CHALLENGE: u8 = 22;
+    pub const ENTER_VIEW: u8 = 23;
+    pub const LEAVE_VIEW: u8 = 24;
+    pub const SET_VIEW: u8 = 25;
+    pub const SNAPSHOT: u8 = 26;
+    pub const SNAPSHOT_ACK: u8 = 27;
+    pub const MISSING_CAPABILITIES: u8 = 28;
+}
+
+/// How messages are put on the wire.
+/// Binary is the default, JSON is kept around so packets can be read by eye while debugging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    #[default]
+    Binary,
+    Json,
+}
+
+impl std::str::FromStr for WireFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "binary" => Ok(WireFormat::Binary),
+            "json" => Ok(WireFormat::Json),
+            _ => Err(format!(
+                "unknown wire format {:?}, expected binary or json",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    /// The datagram ended before the message did.
+    Truncated,
+    BadMagic,
+    /// The header carries a protocol version this build does not speak. The kind is kept so
+    /// that the server can still answer registrations with a rejection.
+    UnsupportedVersion {
+        version: u8,
+        kind: u8,
+    },
+    UnknownKind(u8),
+    /// The message was decoded but there were bytes left over.
+    TrailingBytes(usize),
+    /// The datagram, or the message being reassembled from fragments, is larger than we are
+    /// willing to receive. Datagrams cut off by the receive buffer end up here too.
+    Oversized(usize),
+    /// A list has more entries than its count on the wire can hold.
+    TooMany(usize),
+    /// A string is longer than its length on the wire can hold, in bytes.
+    StringTooLong(usize),
+    BadFragment {
+        index: u16,
+        count: u16,
+    },
+    /// A string field is not valid UTF-8.
+    InvalidString,
+    UnknownRoomError(u8),
+    /// A varint ran longer than five bytes.
+    BadVarint,
+    Json(serde_json::Error),
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Truncated => write!(f, "datagram is truncated"),
+            CodecError::BadMagic => write!(f, "datagram does not start with the bell magic"),
+            CodecError::UnsupportedVersion { version, kind } => {
+                write!(
+                    f,
+                    "unsupported protocol version {} (kind {})",
+                    version, kind
+                )
+            }
+            CodecError::UnknownKind(kind) => write!(f, "unknown message kind {}", kind),
+            CodecError::TrailingBytes(count) => {
+                write!(f, "{} trailing bytes after message", count)
+            }
+            CodecError::Oversized(len) => {
+                write!(f, "message of at least {} bytes is too large", len)
+            }
+            CodecError::TooMany(count) => {
+                write!(f, "list of {} entries is too long to encode", count)
+            }
+            CodecError::StringTooLong(len) => {
+                write!(f, "string of {} bytes is too long to encode", len)
+            }
+            CodecError::BadFragment { index, count } => {
+                write!(f, "invalid fragment {} of {}", index, count)
+            }
+            CodecError::InvalidString => write!(f, "string is not valid utf-8"),
+            CodecError::UnknownRoomError(code) => write!(f, "unknown room error {}", code),
+            CodecError::BadVarint => write!(f, "varint is too long"),
+            CodecError::Json(e) => write!(f, "invalid json message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Encodes a message with the binary format. Fails for lists and strings longer than their
+/// count or length field can hold.
+pub fn encode(message: &BellMessage) -> Result<Vec<u8>, CodecError> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + POINT_LEN);
+    write_header(&mut buf, message_kind(message));
+    match message {
+        BellMessage::PositionChangeMessage(point)
+        | BellMessage::PlayerInsertionMessage(point)
+        | BellMessage::EnterViewMessage(point) => write_point(&mut buf, point),
+        BellMessage::DeferMessage => {}
+        BellMessage::PlayerRegistrationMessage(registration) => {
+            write_point(&mut buf, &registration.point);
+            buf.push(registration.protocol_version);
+            buf.extend_from_slice(&registration.capabilities.to_le_bytes());
+            buf.extend_from_slice(&registration.cookie.to_le_bytes());
+        }
+        BellMessage::RegistrationReplyMessage(id, points, capabilities, token, world) => {
+            buf.extend_from_slice(&id.to_le_bytes());
+            write_points(&mut buf, points)?;
+            buf.extend_from_slice(&capabilities.to_le_bytes());
+            buf.extend_from_slice(&token.to_le_bytes());
+            buf.extend_from_slice(&world.half_width.to_le_bytes());
+            buf.extend_from_slice(&world.half_height.to_le_bytes());
+        }
+        BellMessage::RegistrationRejectedMessage(min_version) => buf.push(*min_version),
+        BellMessage::PlayerLeaveMessage(id)
+        | BellMessage::HeartbeatMessage(id)
+        | BellMessage::PlayerRemovalMessage(id)
+        | BellMessage::LeaveViewMessage(id) => buf.extend_from_slice(&id.to_le_bytes()),
+        BellMessage::ServerFullMessage(position) => buf.extend_from_slice(&position.to_le_bytes()),
+        BellMessage::MissingCapabilitiesMessage(missing) => {
+            buf.extend_from_slice(&missing.to_le_bytes())
+        }
+        BellMessage::InputMessage(id, sequence, inputs) => {
+            buf.extend_from_slice(&id.to_le_bytes());
+            buf.extend_from_slice(&sequence.to_le_bytes());
+            buf.push(*inputs);
+        }
+        BellMessage::CollisionMessage(a, b) => {
+            buf.extend_from_slice(&a.to_le_bytes());
+            buf.extend_from_slice(&b.to_le_bytes());
+        }
+        BellMessage::ListRoomsMessage(id) => buf.extend_from_slice(&id.to_le_bytes()),
+        BellMessage::RoomListMessage(rooms) => {
+            write_count(&mut buf, rooms.len())?;
+            for room in rooms {
+                write_str(&mut buf, &room.name)?;
+                buf.extend_from_slice(&room.players.to_le_bytes());
+                buf.extend_from_slice(&room.max_players.to_le_bytes());
+                buf.push(room.has_password as u8);
+            }
+        }
+        BellMessage::CreateRoomMessage(id, name, max_players, password) => {
+            buf.extend_from_slice(&id.to_le_bytes());
+            write_str(&mut buf, name)?;
+            buf.extend_from_slice(&max_players.to_le_bytes());
+            write_str(&mut buf, password.as_deref().unwrap_or_default())?;
+        }
+        BellMessage::JoinRoomMessage(id, name, password) => {
+            buf.extend_from_slice(&id.to_le_bytes());
+            write_str(&mut buf, name)?;
+            write_str(&mut buf, password.as_deref().unwrap_or_default())?;
+        }
+        BellMessage::RoomJoinedMessage(name, points) => {
+            write_str(&mut buf, name)?;
+            write_points(&mut buf, points)?;
+        }
+        BellMessage::RoomErrorMessage(error) => buf.push(error.code()),
+        BellMessage::ChallengeMessage(cookie) => buf.extend_from_slice(&cookie.to_le_bytes()),
+        BellMessage::SetViewMessage(id, width, height) => {
+            buf.extend_from_slice(&id.to_le_bytes());
+            buf.extend_from_slice(&width.to_le_bytes());
+            buf.extend_from_slice(&height.to_le_bytes());
+        }
+        BellMessage::SnapshotMessage(snapshot) => write_snapshot(&mut buf, snapshot),
+        BellMessage::SnapshotAckMessage(id, tick) => {
+            buf.extend_from_slice(&id.to_le_bytes());
+            buf.extend_from_slice(&tick.to_le_bytes());
+        }
+    }
+
+    Ok(buf)
+}
+
+/// Encodes a message with the given format.
+pub fn encode_with(message: &BellMessage, format: WireFormat) -> Result<Vec<u8>, CodecError> {
+    match format {
+        WireFormat::Binary => encode(message),
+        WireFormat::Json => serde_json::to_vec(message).map_err(CodecError::Json),
+    }
+}
+
+/// Decodes a datagram. Binary datagrams are recognised by their magic, anything else is
+/// treated as JSON so that debug clients can talk to a binary server and vice versa.
+pub fn decode(data: &[u8]) -> Result<BellMessage, CodecError> {
+    if !data.starts_with(&MAGIC) {
+        return serde_json::from_slice::<BellMessage>(data).map_err(CodecError::Json);
+    }
+
+    let kind = decode_header(data)?;
+    let mut reader = Reader::new(&data[HEADER_LEN..]);
+    let message = match kind {
+        kind::POSITION_CHANGE => BellMessage::PositionChangeMessage(reader.point()?),
+        kind::DEFER => BellMessage::DeferMessage,
+        kind::PLAYER_INSERTION => BellMessage::PlayerInsertionMessage(reader.point()?),
+        kind::PLAYER_REGISTRATION => BellMessage::PlayerRegistrationMessage(Registration {
+            point: reader.point()?,
+            protocol_version: reader.u8()?,
+            capabilities: reader.u32()?,
+            cookie: reader.u64()?,
+        }),
+        kind::REGISTRATION_REPLY => {
+            let id = reader.u32()?;
+            let points = reader.points()?;
+            let (capabilities, token) = (reader.u32()?, reader.u64()?);
+            let world = WorldBounds {
+                half_width: reader.f32()?,
+                half_height: reader.f32()?,
+            };
+            BellMessage::RegistrationReplyMessage(id, points, capabilities, token, world)
+        }
+        kind::REGISTRATION_REJECTED => BellMessage::RegistrationRejectedMessage(reader.u8()?),
+        kind::PLAYER_LEAVE => BellMessage::PlayerLeaveMessage(reader.u32()?),
+        kind::HEARTBEAT => BellMessage::HeartbeatMessage(reader.u32()?),
+        kind::PLAYER_REMOVAL => BellMessage::PlayerRemovalMessage(reader.u32()?),
+        kind::SERVER_FULL => BellMessage::ServerFullMessage(reader.u32()?),
+        kind::MISSING_CAPABILITIES => BellMessage::MissingCapabilitiesMessage(reader.u32()?),
+        kind::INPUT => BellMessage::InputMessage(reader.u32()?, reader.u32()?, reader.u8()?),
+        kind::COLLISION => BellMessage::CollisionMessage(reader.u32()?, reader.u32()?),
+        kind::LIST_ROOMS => BellMessage::ListRoomsMessage(reader.u32()?),
+        kind::ROOM_LIST => BellMessage::RoomListMessage(reader.rooms()?),
+        kind::CREATE_ROOM => BellMessage::CreateRoomMessage(
+            reader.u32()?,
+            reader.string()?,
+            reader.u16()?,
+            reader.password()?,
+        ),
+        kind::JOIN_ROOM => {
+            BellMessage::JoinRoomMessage(reader.u32()?, reader.string()?, reader.password()?)
+        }
+        kind::ROOM_JOINED => BellMessage::RoomJoinedMessage(reader.string()?, reader.points()?),
+        kind::ROOM_ERROR => {
+            let code = reader.u8()?;
+            BellMessage::RoomErrorMessage(
+                RoomError::from_code(code).ok_or(CodecError::UnknownRoomError(code))?,
+            )
+        }
+        kind::CHALLENGE => BellMessage::ChallengeMessage(reader.u64()?),
+        kind::ENTER_VIEW => BellMessage::EnterViewMessage(reader.point()?),
+        kind::LEAVE_VIEW => BellMessage::LeaveViewMessage(reader.u32()?),
+        kind::SET_VIEW => BellMessage::SetViewMessage(reader.u32()?, reader.f32()?, reader.f32()?),
+        kind::SNAPSHOT => BellMessage::SnapshotMessage(reader.snapshot()?),
+        kind::SNAPSHOT_ACK => BellMessage::SnapshotAckMessage(reader.u32()?, reader.u32()?),
+        other => return Err(CodecError::UnknownKind(other)),
+    };
+    reader.finish()?;
+
+    Ok(message)
+}
+
+pub fn write_header(buf: &mut Vec<u8>, kind: u8) {
+    buf.extend_from_slice(&MAGIC);
+    buf.push(PROTOCOL_VERSION);
+    buf.push(kind);
+}
+
+/// Kind of a binary datagram without checking its version, None for JSON or garbage.
+pub fn peek_kind(data: &[u8]) -> Option<u8> {
+    if data.len() < HEADER_LEN || !data.starts_with(&MAGIC) {
+        return None;
+    }
+
+    Some(data[3])
+}
+
+/// Validates the header of a binary datagram and returns its message kind.
+pub fn decode_header(data: &[u8]) -> Result<u8, CodecError> {
+    if data.len() < HEADER_LEN {
+        return Err(CodecError::Truncated);
+    }
+    if data[..2] != MAGIC {
+        return Err(CodecError::BadMagic);
+    }
+    let (version, kind) = (data[2], data[3]);
+    if !protocol::is_supported_version(version) && kind != kind::REGISTRATION_REJECTED {
+        return Err(CodecError::UnsupportedVersion { version, kind });
+    }
+
+    Ok(kind)
+}
+
+pub fn message_kind(message: &BellMessage) -> u8 {
+    match message {
+        BellMessage::PositionChangeMessage(_) => kind::POSITION_CHANGE,
+        BellMessage::DeferMessage => kind::DEFER,
+        BellMessage::PlayerInsertionMessage(_) => kind::PLAYER_INSERTION,
+        BellMessage::PlayerRegistrationMessage(_) => kind::PLAYER_REGISTRATION,
+        BellMessage::RegistrationReplyMessage(_, _, _, _, _) => kind::REGISTRATION_REPLY,
+        BellMessage::RegistrationRejectedMessage(_) => kind::REGISTRATION_REJECTED,
+        BellMessage::PlayerLeaveMessage(_) => kind::PLAYER_LEAVE,
+        BellMessage::HeartbeatMessage(_) => kind::HEARTBEAT,
+        BellMessage::PlayerRemovalMessage(_) => kind::PLAYER_REMOVAL,
+        BellMessage::ServerFullMessage(_) => kind::SERVER_FULL,
+        BellMessage::MissingCapabilitiesMessage(_) => kind::MISSING_CAPABILITIES,
+        BellMessage::InputMessage(_, _, _) => kind::INPUT,
+        BellMessage::CollisionMessage(_, _) => kind::COLLISION,
+        BellMessage::ListRoomsMessage(_) => kind::LIST_ROOMS,
+        BellMessage::RoomListMessage(_) => kind::ROOM_LIST,
+        BellMessage::CreateRoomMessage(_, _, _, _) => kind::CREATE_ROOM,
+        BellMessage::JoinRoomMessage(_, _, _) => kind::JOIN_ROOM,
+        BellMessage::RoomJoinedMessage(_, _) => kind::ROOM_JOINED,
+        BellMessage::RoomErrorMessage(_) => kind::ROOM_ERROR,
+        BellMessage::ChallengeMessage(_) => kind::CHALLENGE,
+        BellMessage::EnterViewMessage(_) => kind::ENTER_VIEW,
+        BellMessage::LeaveViewMessage(_) => kind::LEAVE_VIEW,
+        BellMessage::SetViewMessage(_, _, _) => kind::SET_VIEW,
+        BellMessage::SnapshotMessage(_) => kind::SNAPSHOT,
+        BellMessage::SnapshotAckMessage(_, _) => kind::SNAPSHOT_ACK,
+    }
+}
+
+fn write_point(buf: &mut Vec<u8>, point: &Point) {
+    buf.extend_from_slice(&point.x.to_le_bytes());
+    buf.extend_from_slice(&point.y.to_le_bytes());
+    buf.extend_from_slice(&point.id.to_le_bytes());
+}
+
+fn write_points(buf: &mut Vec<u8>, points: &[Point]) -> Result<(), CodecError> {
+    write_count(buf, points.len())?;
+    for point in points {
+        write_point(buf, point);
+    }
+
+    Ok(())
+}
+
+/// Lists are prefixed with their length as a u16.
+fn write_count(buf: &mut Vec<u8>, count: usize) -> Result<(), CodecError> {
+    let count = u16::try_from(count).map_err(|_| CodecError::TooMany(count))?;
+    buf.extend_from_slice(&count.to_le_bytes());
+
+    Ok(())
+}
+
+/// Snapshots are mostly small numbers, so counts, ids and coordinates are varints. The
+/// coordinates are zigzag encoded since deltas are just as often negative.
+fn write_snapshot(buf: &mut Vec<u8>, snapshot: &Snapshot) {
+    buf.extend_from_slice(&snapshot.tick.to_le_bytes());
+    buf.extend_from_slice(&snapshot.time.to_le_bytes());
+    buf.extend_from_slice(&snapshot.baseline.to_le_bytes());
+    write_varint(buf, snapshot.changed.len() as u32);
+    for entry in snapshot.changed.iter() {
+        write_varint(buf, entry.id);
+        write_varint(buf, zigzag(entry.x));
+        write_varint(buf, zigzag(entry.y));
+    }
+    write_varint(buf, snapshot.removed.len() as u32);
+    for id in snapshot.removed.iter() {
+        write_varint(buf, *id);
+    }
+}
+
+/// Size of a `SnapshotMessage` in the binary format, without encoding it.
+pub fn snapshot_len(snapshot: &Snapshot) -> usize {
+    let changed = snapshot
+        .changed
+        .iter()
+        .map(|entry| {
+            varint_len(entry.id) + varint_len(zigzag(entry.x)) + varint_len(zigzag(entry.y))
+        })
+        .sum::<usize>();
+    let removed = snapshot
+        .removed
+        .iter()
+        .map(|id| varint_len(*id))
+        .sum::<usize>();
+
+    HEADER_LEN
+        + 12
+        + varint_len(snapshot.changed.len() as u32)
+        + changed
+        + varint_len(snapshot.removed.len() as u32)
+        + removed
+}
+
+/// Seven bits per byte, lowest first, the high bit says whether another byte follows.
+fn write_varint(buf: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn varint_len(value: u32) -> usize {
+    (32 - value.leading_zeros() as usize).max(1).div_ceil(7)
+}
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+fn unzigzag(value: u32) -> i32 {
+    (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+/// Strings are prefixed with their length in a single byte.
+fn write_str(buf: &mut Vec<u8>, value: &str) -> Result<(), CodecError> {
+    let len = u8::try_from(value.len()).map_err(|_| CodecError::StringTooLong(value.len()))?;
+    buf.push(len);
+    buf.extend_from_slice(value.as_bytes());
+
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], CodecError> {
+        let end = self.pos + N;
+        if end > self.data.len() {
+            return Err(CodecError::Truncated);
+        }
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(&self.data[self.pos..end]);
+        self.pos = end;
+
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, CodecError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, CodecError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, CodecError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, CodecError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, CodecError> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    fn point(&mut self) -> Result<Point, CodecError> {
+        Ok(Point {
+            x: self.f32()?,
+            y: self.f32()?,
+            id: self.u32()?,
+        })
+    }
+
+    fn points(&mut self) -> Result<Vec<Point>, CodecError> {
+        let count = self.u16()? as usize;
+        // don't trust the count for the allocation, a bogus header could ask for a lot
+        let mut points = Vec::with_capacity(count.min(self.remaining() / POINT_LEN));
+        for _ in 0..count {
+            points.push(self.point()?);
+        }
+
+        Ok(points)
+    }
+
+    fn varint(&mut self) -> Result<u32, CodecError> {
+        let mut value = 0_u32;
+        for shift in (0..32).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(CodecError::BadVarint)
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot, CodecError> {
+        let tick = self.u32()?;
+        let time = self.u32()?;
+        let baseline = self.u32()?;
+        let count = self.varint()? as usize;
+        // every entry takes at least three bytes
+        let mut changed = Vec::with_capacity(count.min(self.remaining() / 3));
+        for _ in 0..count {
+            changed.push(SnapshotEntry {
+                id: self.varint()?,
+                x: unzigzag(self.varint()?),
+                y: unzigzag(self.varint()?),
+            });
+        }
+        let count = self.varint()? as usize;
+        let mut removed = Vec::with_capacity(count.min(self.remaining()));
+        for _ in 0..count {
+            removed.push(self.varint()?);
+        }
+
+        Ok(Snapshot {
+            tick,
+            time,
+            baseline,
+            changed,
+            removed,
+        })
+    }
+
+    fn string(&mut self) -> Result<String, CodecError> {
+        let len = self.u8()? as usize;
+        let end = self.pos + len;
+        if end > self.data.len() {
+            return Err(CodecError::Truncated);
+        }
+        let value = std::str::from_utf8(&self.data[self.pos..end])
+            .map_err(|_| CodecError::InvalidString)?;
+        self.pos = end;
+
+        Ok(value.to_string())
+    }
+
+    /// An empty password means the room has none.
+    fn password(&mut self) -> Result<Option<String>, CodecError> {
+        Ok(Some(self.string()?).filter(|password| !password.is_empty()))
+    }
+
+    fn rooms(&mut self) -> Result<Vec<RoomInfo>, CodecError> {
+        let count = self.u16()? as usize;
+        let mut rooms = Vec::with_capacity(count.min(self.remaining()));
+        for _ in 0..count {
+            rooms.push(RoomInfo {
+                name: self.string()?,
+                players: self.u16()?,
+                max_players: self.u16()?,
+                has_password: self.u8()? != 0,
+            });
+        }
+
+        Ok(rooms)
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn finish(&self) -> Result<(), CodecError> {
+        match self.remaining() {
+            0 => Ok(()),
+            count => Err(CodecError::TrailingBytes(count)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(id: u32) -> Point {
+        Point {
+            x: id as f32 * 1.5,
+            y: -(id as f32) * 0.25,
+            id,
+        }
+    }
+
+    /// One of every message, with values that survive the trip through f32 and JSON.
+    fn messages() -> Vec<BellMessage> {
+        vec![
+            BellMessage::PositionChangeMessage(point(1)),
+            BellMessage::DeferMessage,
+            BellMessage::PlayerInsertionMessage(point(2)),
+            BellMessage::PlayerRegistrationMessage(Registration {
+                point: point(0),
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: 3,
+                cookie: u64::MAX - 1,
+            }),
+            BellMessage::RegistrationReplyMessage(
+                4,
+                vec![point(1), point(2), point(3)],
+                1,
+                0x0123_4567_89ab_cdef,
+                WorldBounds {
+                    half_width: 640.,
+                    half_height: 360.,
+                },
+            ),
+            BellMessage::RegistrationRejectedMessage(2),
+            BellMessage::MissingCapabilitiesMessage(2),
+            BellMessage::PlayerLeaveMessage(5),
+            BellMessage::HeartbeatMessage(6),
+            BellMessage::PlayerRemovalMessage(7),
+            BellMessage::ServerFullMessage(8),
+            BellMessage::InputMessage(9, u32::MAX, 0b1010),
+            BellMessage::CollisionMessage(10, 11),
+            BellMessage::ListRoomsMessage(12),
+            BellMessage::RoomListMessage(vec![
+                RoomInfo {
+                    name: "main".to_string(),
+                    players: 3,
+                    max_players: 16,
+                    has_password: false,
+                },
+                RoomInfo {
+                    name: "größer".to_string(),
+                    players: 0,
+                    max_players: 2,
+                    has_password: true,
+                },
+            ]),
+            BellMessage::CreateRoomMessage(13, "lobby".to_string(), 4, Some("hunter2".to_string())),
+            BellMessage::CreateRoomMessage(13, "open".to_string(), 0, None),
+            BellMessage::JoinRoomMessage(14, "lobby".to_string(), None),
+            BellMessage::RoomJoinedMessage("lobby".to_string(), vec![point(15)]),
+            BellMessage::RoomJoinedMessage(String::new(), vec![]),
+            BellMessage::RoomErrorMessage(RoomError::WrongPassword),
+            BellMessage::ChallengeMessage(42),
+            BellMessage::EnterViewMessage(point(16)),
+            BellMessage::LeaveViewMessage(17),
+            BellMessage::SetViewMessage(18, 1280., 720.5),
+            BellMessage::SnapshotMessage(Snapshot {
+                tick: 100,
+                time: 123_456,
+                baseline: 98,
+                changed: vec![
+                    SnapshotEntry { id: 1, x: 0, y: -1 },
+                    SnapshotEntry {
+                        id: u32::MAX,
+                        x: i32::MIN,
+                        y: i32::MAX,
+                    },
+                ],
+                removed: vec![2, 300, u32::MAX],
+            }),
+            BellMessage::SnapshotAckMessage(19, 100),
+        ]
+    }
+
+    fn assert_round_trip(message: &BellMessage, format: WireFormat) {
+        let data = encode_with(message, format).unwrap();
+        let decoded = decode(&data).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+    }
+
+    #[test]
+    fn every_message_kind_is_covered() {
+        let covered: std::collections::BTreeSet<u8> = messages().iter().map(message_kind).collect();
+        let framing = [kind::RELIABLE, kind::ACK, kind::FRAGMENT, kind::SESSION];
+        for kind in 0..=kind::MISSING_CAPABILITIES {
+            assert!(
+                covered.contains(&kind) || framing.contains(&kind),
+                "no message of kind {}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        for message in messages() {
+            let data = encode(&message).unwrap();
+            assert_eq!(peek_kind(&data), Some(message_kind(&message)));
+            assert_round_trip(&message, WireFormat::Binary);
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        for message in messages() {
+            assert_round_trip(&message, WireFormat::Json);
+        }
+    }
+
+    #[test]
+    fn snapshot_len_matches_encoding() {
+        for message in messages() {
+            if let BellMessage::SnapshotMessage(snapshot) = &message {
+                assert_eq!(snapshot_len(snapshot), encode(&message).unwrap().len());
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_datagrams_are_rejected() {
+        for message in messages() {
+            let data = encode(&message).unwrap();
+            for len in MAGIC.len()..data.len() {
+                assert!(
+                    matches!(decode(&data[..len]), Err(CodecError::Truncated)),
+                    "{:?} cut to {} bytes",
+                    message,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        for message in messages() {
+            let mut data = encode(&message).unwrap();
+            data.push(0);
+            assert!(matches!(decode(&data), Err(CodecError::TrailingBytes(1))));
+        }
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let mut data = encode(&BellMessage::HeartbeatMessage(1)).unwrap();
+        data[0] = b'X';
+        assert!(matches!(decode_header(&data), Err(CodecError::BadMagic)));
+        // without the magic the datagram is taken for JSON
+        assert!(matches!(decode(&data), Err(CodecError::Json(_))));
+        assert_eq!(peek_kind(&data), None);
+    }
+
+    #[test]
+    fn unsupported_version_is_rejected() {
+        let mut data = encode(&BellMessage::HeartbeatMessage(1)).unwrap();
+        data[2] = PROTOCOL_VERSION + 1;
+        assert!(matches!(
+            decode(&data),
+            Err(CodecError::UnsupportedVersion {
+                kind: kind::HEARTBEAT,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejection_is_readable_by_any_version() {
+        let mut data = encode(&BellMessage::RegistrationRejectedMessage(3)).unwrap();
+        data[2] = u8::MAX;
+        assert!(matches!(
+            decode(&data),
+            Ok(BellMessage::RegistrationRejectedMessage(3))
+        ));
+    }
+
+    #[test]
+    fn unknown_kind_is_rejected() {
+        let mut data = vec![];
+        write_header(&mut data, 200);
+        assert!(matches!(decode(&data), Err(CodecError::UnknownKind(200))));
+
+        let mut data = vec![];
+        write_header(&mut data, kind::ROOM_ERROR);
+        data.push(200);
+        assert!(matches!(
+            decode(&data),
+            Err(CodecError::UnknownRoomError(200))
+        ));
+    }
+
+    #[test]
+    fn oversized_counts_are_rejected() {
+        let mut data = vec![];
+        write_header(&mut data, kind::ROOM_JOINED);
+        write_str(&mut data, "lobby").unwrap();
+        data.extend_from_slice(&u16::MAX.to_le_bytes());
+        data.extend_from_slice(&[0; POINT_LEN]);
+        assert!(matches!(decode(&data), Err(CodecError::Truncated)));
+
+        let mut data = vec![];
+        write_header(&mut data, kind::ROOM_LIST);
+        data.extend_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(decode(&data), Err(CodecError::Truncated)));
+
+        let mut data = vec![];
+        write_header(&mut data, kind::SNAPSHOT);
+        data.extend_from_slice(&[0; 12]);
+        write_varint(&mut data, u32::MAX);
+        assert!(matches!(decode(&data), Err(CodecError::Truncated)));
+    }
+
+    #[test]
+    fn overlong_varint_is_rejected() {
+        let mut data = vec![];
+        write_header(&mut data, kind::SNAPSHOT);
+        data.extend_from_slice(&[0; 12]);
+        data.extend_from_slice(&[0xff; 5]);
+        assert!(matches!(decode(&data), Err(CodecError::BadVarint)));
+    }
+
+    #[test]
+    fn invalid_string_is_rejected() {
+        let mut data = vec![];
+        write_header(&mut data, kind::ROOM_JOINED);
+        data.extend_from_slice(&[2, 0xc3, 0x28, 0, 0]);
+        assert!(matches!(decode(&data), Err(CodecError::InvalidString)));
+    }
+
+    #[test]
+    fn strings_longer_than_their_length_fail_to_encode() {
+        let name = "é".repeat(128);
+        assert!(matches!(
+            encode(&BellMessage::JoinRoomMessage(1, name, None)),
+            Err(CodecError::StringTooLong(256))
+        ));
+        assert!(matches!(
+            encode(&BellMessage::CreateRoomMessage(
+                1,
+                "lobby".to_string(),
+                0,
+                Some("x".repeat(300))
+            )),
+            Err(CodecError::StringTooLong(300))
+        ));
+
+        let name = "x".repeat(u8::MAX as usize);
+        let data = encode(&BellMessage::JoinRoomMessage(1, name.clone(), None)).unwrap();
+        match decode(&data).unwrap() {
+            BellMessage::JoinRoomMessage(_, decoded, _) => assert_eq!(decoded, name),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lists_longer_than_their_count_fail_to_encode() {
+        let count = u16::MAX as usize + 1;
+        let points = vec![Point::default(); count];
+        assert!(matches!(
+            encode(&BellMessage::RoomJoinedMessage("lobby".to_string(), points.clone())),
+            Err(CodecError::TooMany(len)) if len == count
+        ));
+        assert!(matches!(
+            encode(&BellMessage::RegistrationReplyMessage(
+                1,
+                points,
+                0,
+                0,
+                WorldBounds::default()
+            )),
+            Err(CodecError::TooMany(_))
+        ));
+
+        let rooms = vec![
+            RoomInfo {
+                name: String::new(),
+                players: 0,
+                max_players: 0,
+                has_password: false,
+            };
+            count
+        ];
+        assert!(matches!(
+            encode(&BellMessage::RoomListMessage(rooms)),
+            Err(CodecError::TooMany(_))
+        ));
+
+        let points = vec![Point::default(); u16::MAX as usize];
+        assert!(encode(&BellMessage::RoomJoinedMessage(String::new(), points)).is_ok());
+    }
+}
@@ -0,0 +1,272 @@
+use lib_udp_server::interest::{self, View};
+use lib_udp_server::movement::{self, WorldBounds};
+use lib_udp_server::ratelimit::RateLimits;
+use lib_udp_server::{fragment, protocol, room, tick, Server, ServerBuilder, WireFormat};
+
+/// Command line of the server. Every setting can also come from a `BELL_*` environment
+/// variable or the config file, the command line wins over the environment, which wins over
+/// the file.
+#[derive(clap::Parser, Debug)]
+#[command(name = "udp_server", about = "Server for the bell game")]
+pub struct Cli {
+    /// TOML file to read settings from
+    #[arg(short, long, env = "BELL_CONFIG")]
+    pub config: Option<std::path::PathBuf>,
+    /// Print the effective settings as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Address to listen on, e.g. 127.0.0.1, 0.0.0.0 or ::
+    #[arg(short, long, env = "BELL_BIND")]
+    bind: Option<std::net::IpAddr>,
+    #[arg(short, long, env = "BELL_PORT")]
+    port: Option<u16>,
+    /// Server ticks per second
+    #[arg(long, env = "BELL_TICK_RATE")]
+    tick_rate: Option<u32>,
+    #[arg(long, env = "BELL_MAX_PLAYERS")]
+    max_players: Option<usize>,
+    /// Most rooms that can exist at once, including the default one
+    #[arg(long, env = "BELL_MAX_ROOMS")]
+    max_rooms: Option<usize>,
+    /// Most messages waiting to be handled by the state task
+    #[arg(long, env = "BELL_QUEUE_CAPACITY")]
+    queue_capacity: Option<usize>,
+    /// Seconds without hearing from a player before it is evicted
+    #[arg(long, env = "BELL_PLAYER_TIMEOUT_SECS")]
+    player_timeout_secs: Option<u64>,
+    /// Only let players move by sending inputs. The variable takes true/false, 1/0, yes/no or
+    /// on/off
+    #[arg(
+        long,
+        env = "BELL_INPUT_ONLY",
+        num_args = 0..=1,
+        default_missing_value = "true",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    input_only: Option<bool>,
+    #[arg(long, env = "BELL_HITBOX_SIZE")]
+    hitbox_size: Option<f32>,
+    /// Fastest a player may move, in units per second
+    #[arg(long, env = "BELL_MAX_SPEED")]
+    max_speed: Option<f32>,
+    /// Size of the world, centered on the origin
+    #[arg(long, env = "BELL_WORLD_WIDTH")]
+    world_width: Option<f32>,
+    #[arg(long, env = "BELL_WORLD_HEIGHT")]
+    world_height: Option<f32>,
+    /// Size of the area around a player it is told about, clients may ask for less
+    #[arg(long, env = "BELL_VIEW_WIDTH")]
+    view_width: Option<f32>,
+    #[arg(long, env = "BELL_VIEW_HEIGHT")]
+    view_height: Option<f32>,
+    /// binary or json
+    #[arg(long, env = "BELL_WIRE_FORMAT")]
+    wire_format: Option<WireFormat>,
+    /// Largest datagram sent, bigger messages are fragmented
+    #[arg(long, env = "BELL_MTU")]
+    mtu: Option<usize>,
+    /// off, error, warn, info, debug or trace
+    #[arg(long, env = "BELL_LOG_LEVEL")]
+    log_level: Option<log::LevelFilter>,
+
+    /// Datagrams per second accepted from one address
+    #[arg(long, env = "BELL_PACKET_RATE")]
+    packet_rate: Option<f32>,
+    #[arg(long, env = "BELL_PACKET_BURST")]
+    packet_burst: Option<f32>,
+    /// Messages per second accepted for one player
+    #[arg(long, env = "BELL_PLAYER_MESSAGE_RATE")]
+    player_message_rate: Option<f32>,
+    #[arg(long, env = "BELL_PLAYER_MESSAGE_BURST")]
+    player_message_burst: Option<f32>,
+    /// Registrations per second accepted from one address
+    #[arg(long, env = "BELL_REGISTRATION_RATE")]
+    registration_rate: Option<f32>,
+    #[arg(long, env = "BELL_REGISTRATION_BURST")]
+    registration_burst: Option<f32>,
+    /// Dropped packets within 10 seconds that get an address banned, 0 never bans
+    #[arg(long, env = "BELL_BAN_STRIKES")]
+    ban_strikes: Option<u32>,
+    /// How long a ban lasts
+    #[arg(long, env = "BELL_BAN_SECS")]
+    ban_secs: Option<u64>,
+}
+
+/// Effective server settings after merging the defaults, the config file, the environment
+/// and the command line.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub bind: std::net::IpAddr,
+    pub port: u16,
+    pub tick_rate: u32,
+    pub max_players: usize,
+    pub max_rooms: usize,
+    pub queue_capacity: usize,
+    pub player_timeout_secs: u64,
+    /// When set, absolute positions from clients are ignored and players can only move by
+    /// sending inputs
+    pub input_only: bool,
+    pub hitbox_size: f32,
+    /// Fastest a player may move, positions from clients that move faster are corrected
+    pub max_speed: f32,
+    pub world_width: f32,
+    pub world_height: f32,
+    /// Size of the area around a player it is told about, clients may ask for less
+    pub view_width: f32,
+    pub view_height: f32,
+    pub wire_format: WireFormat,
+    /// Largest datagram sent, bigger messages are fragmented
+    pub mtu: usize,
+    pub log_level: log::LevelFilter,
+    pub rate_limits: RateLimits,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        let world = WorldBounds::default();
+        Self {
+            bind: std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+            port: 8080,
+            tick_rate: tick::DEFAULT_TICK_RATE,
+            max_players: lib_udp_server::DEFAULT_MAX_PLAYERS,
+            max_rooms: room::DEFAULT_MAX_ROOMS,
+            queue_capacity: lib_udp_server::DEFAULT_QUEUE_CAPACITY,
+            player_timeout_secs: protocol::DEFAULT_PLAYER_TIMEOUT.as_secs(),
+            input_only: false,
+            hitbox_size: lib_udp_server::DEFAULT_HITBOX_SIZE,
+            max_speed: movement::PLAYER_SPEED,
+            world_width: world.half_width * 2.,
+            world_height: world.half_height * 2.,
+            view_width: interest::DEFAULT_VIEW_WIDTH,
+            view_height: interest::DEFAULT_VIEW_HEIGHT,
+            wire_format: WireFormat::default(),
+            mtu: fragment::DEFAULT_MTU,
+            log_level: log::LevelFilter::Info,
+            rate_limits: RateLimits::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the config file named on the command line, if any, and applies the environment
+    /// and command line on top of it.
+    pub fn load(cli: &Cli) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut settings = match &cli.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+                toml::from_str(&contents)
+                    .map_err(|e| format!("invalid config file {}: {}", path.display(), e))?
+            }
+            None => Settings::default(),
+        };
+
+        overlay(&mut settings.bind, cli.bind);
+        overlay(&mut settings.port, cli.port);
+        overlay(&mut settings.tick_rate, cli.tick_rate);
+        overlay(&mut settings.max_players, cli.max_players);
+        overlay(&mut settings.max_rooms, cli.max_rooms);
+        overlay(&mut settings.queue_capacity, cli.queue_capacity);
+        overlay(&mut settings.player_timeout_secs, cli.player_timeout_secs);
+        overlay(&mut settings.input_only, cli.input_only);
+        overlay(&mut settings.hitbox_size, cli.hitbox_size);
+        overlay(&mut settings.max_speed, cli.max_speed);
+        overlay(&mut settings.world_width, cli.world_width);
+        overlay(&mut settings.world_height, cli.world_height);
+        overlay(&mut settings.view_width, cli.view_width);
+        overlay(&mut settings.view_height, cli.view_height);
+        overlay(&mut settings.wire_format, cli.wire_format);
+        overlay(&mut settings.mtu, cli.mtu);
+        overlay(&mut settings.log_level, cli.log_level);
+        let limits = &mut settings.rate_limits;
+        overlay(&mut limits.packet_rate, cli.packet_rate);
+        overlay(&mut limits.packet_burst, cli.packet_burst);
+        overlay(&mut limits.player_message_rate, cli.player_message_rate);
+        overlay(&mut limits.player_message_burst, cli.player_message_burst);
+        overlay(&mut limits.registration_rate, cli.registration_rate);
+        overlay(&mut limits.registration_burst, cli.registration_burst);
+        overlay(&mut limits.ban_strikes, cli.ban_strikes);
+        overlay(&mut limits.ban_secs, cli.ban_secs);
+
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.tick_rate == 0 {
+            return Err("tick_rate must be at least 1".to_string());
+        }
+        if self.player_timeout_secs == 0 {
+            return Err("player_timeout_secs must be at least 1".to_string());
+        }
+        if self.mtu < fragment::MIN_MTU || self.mtu > fragment::MAX_DATAGRAM_LEN {
+            return Err(format!(
+                "mtu must be between {} and {}",
+                fragment::MIN_MTU,
+                fragment::MAX_DATAGRAM_LEN
+            ));
+        }
+        if !is_positive(self.max_speed) {
+            return Err("max_speed must be above 0".to_string());
+        }
+        if !is_positive(self.hitbox_size) {
+            return Err("hitbox_size must be above 0".to_string());
+        }
+        if !is_positive(self.world_width) || !is_positive(self.world_height) {
+            return Err("world_width and world_height must be above 0".to_string());
+        }
+        if !is_positive(self.view_width) || !is_positive(self.view_height) {
+            return Err("view_width and view_height must be above 0".to_string());
+        }
+
+        let limits = &self.rate_limits;
+        let buckets = [
+            (limits.packet_rate, limits.packet_burst),
+            (limits.player_message_rate, limits.player_message_burst),
+            (limits.registration_rate, limits.registration_burst),
+        ];
+        // a burst below 1 would never let anything through
+        if buckets
+            .iter()
+            .any(|(rate, burst)| !(is_positive(*rate) && burst.is_finite() && *burst >= 1.))
+        {
+            return Err("rates must be above 0 and bursts at least 1".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// A server set up with these settings.
+    pub fn server_builder(&self) -> ServerBuilder {
+        Server::builder()
+            .bind(std::net::SocketAddr::new(self.bind, self.port))
+            .tick_rate(self.tick_rate)
+            .max_players(self.max_players)
+            .max_rooms(self.max_rooms)
+            .queue_capacity(self.queue_capacity)
+            .player_timeout(std::time::Duration::from_secs(self.player_timeout_secs))
+            .input_only(self.input_only)
+            .hitbox_size(self.hitbox_size)
+            .movement_limits(
+                self.max_speed,
+                WorldBounds::new(self.world_width, self.world_height),
+            )
+            .view(View::new(self.view_width, self.view_height))
+            .wire_format(self.wire_format)
+            .mtu(self.mtu)
+            .rate_limits(self.rate_limits)
+    }
+}
+
+fn is_positive(value: f32) -> bool {
+    value.is_finite() && value > 0.
+}
+
+fn overlay<T>(setting: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *setting = value;
+    }
+}
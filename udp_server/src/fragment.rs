@@ -0,0 +1,354 @@
+use crate::codec::{self, kind, CodecError, HEADER_LEN};
+
+/// Largest datagram we put on the wire unless configured otherwise. Stays below the usual
+/// 1500 byte ethernet MTU with room for IP/UDP headers and tunnels.
+pub const DEFAULT_MTU: usize = 1200;
+/// Smallest MTU that still leaves room for a useful fragment payload.
+pub const MIN_MTU: usize = 64;
+/// Largest payload a UDP datagram can carry over IPv4.
+pub const MAX_DATAGRAM_LEN: usize = 65507;
+/// Size of the receive buffers. One byte larger than any valid datagram, so a read that fills
+/// it completely means the datagram was cut off.
+pub const RECV_BUFFER_LEN: usize = MAX_DATAGRAM_LEN + 1;
+
+/// How long the fragments of an incomplete message are kept around.
+pub const REASSEMBLY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/// Most fragments a single message may be split into.
+pub const MAX_FRAGMENTS: u16 = 128;
+/// Most bytes of incomplete messages buffered for a single peer, the slots for fragments
+/// that did not arrive yet included.
+pub const MAX_REASSEMBLY_BYTES: usize = 256 * 1024;
+/// Most incomplete messages buffered for a single peer.
+pub const MAX_PARTIALS: usize = 32;
+
+/// header + message id (4) + fragment index (2) + fragment count (2)
+const FRAGMENT_HEADER_LEN: usize = HEADER_LEN + 8;
+/// What a message costs for every fragment it announces, before any of them arrived.
+const SLOT_LEN: usize = std::mem::size_of::<Option<Vec<u8>>>();
+
+/// Splits a datagram into fragments of at most `mtu` bytes. Datagrams that already fit are
+/// returned as they are.
+pub fn split(datagram: Vec<u8>, message_id: u32, mtu: usize) -> Vec<Vec<u8>> {
+    if datagram.len() <= mtu {
+        return vec![datagram];
+    }
+
+    let chunk_len = mtu.max(MIN_MTU) - FRAGMENT_HEADER_LEN;
+    let count = datagram.len().div_ceil(chunk_len) as u16;
+    datagram
+        .chunks(chunk_len)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut fragment = Vec::with_capacity(FRAGMENT_HEADER_LEN + chunk.len());
+            codec::write_header(&mut fragment, kind::FRAGMENT);
+            fragment.extend_from_slice(&message_id.to_le_bytes());
+            fragment.extend_from_slice(&(index as u16).to_le_bytes());
+            fragment.extend_from_slice(&count.to_le_bytes());
+            fragment.extend_from_slice(chunk);
+            fragment
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReassemblyStats {
+    pub reassembled: u64,
+    /// Incomplete messages thrown away because they timed out or did not fit the limits.
+    pub dropped: u64,
+}
+
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    bytes: usize,
+    started: std::time::Instant,
+}
+
+/// Collects fragments from one peer until their message is complete.
+#[derive(Default)]
+pub struct Reassembler {
+    partials: std::collections::HashMap<u32, Partial>,
+    bytes: usize,
+    stats: ReassemblyStats,
+}
+
+impl Reassembler {
+    /// Adds a fragment datagram. Returns the original datagram once every fragment of it
+    /// arrived. Duplicate fragments are ignored.
+    pub fn insert(
+        &mut self,
+        data: &[u8],
+        now: std::time::Instant,
+    ) -> Result<Option<Vec<u8>>, CodecError> {
+        if data.len() < FRAGMENT_HEADER_LEN {
+            return Err(CodecError::Truncated);
+        }
+        let message_id = u32::from_le_bytes([
+            data[HEADER_LEN],
+            data[HEADER_LEN + 1],
+            data[HEADER_LEN + 2],
+            data[HEADER_LEN + 3],
+        ]);
+        let index = u16::from_le_bytes([data[HEADER_LEN + 4], data[HEADER_LEN + 5]]);
+        let count = u16::from_le_bytes([data[HEADER_LEN + 6], data[HEADER_LEN + 7]]);
+        if count == 0 || index >= count {
+            return Err(CodecError::BadFragment { index, count });
+        }
+        if count > MAX_FRAGMENTS {
+            self.stats.dropped += 1;
+            return Err(CodecError::Oversized(count as usize * data.len()));
+        }
+
+        let chunk = &data[FRAGMENT_HEADER_LEN..];
+        let is_new = !self.partials.contains_key(&message_id);
+        if is_new && self.partials.len() >= MAX_PARTIALS {
+            self.stats.dropped += 1;
+            return Err(CodecError::TooMany(self.partials.len() + 1));
+        }
+        let slots = if is_new { count as usize * SLOT_LEN } else { 0 };
+        if self.bytes + slots + chunk.len() > MAX_REASSEMBLY_BYTES {
+            self.discard(message_id);
+            self.stats.dropped += 1;
+            return Err(CodecError::Oversized(self.bytes + slots + chunk.len()));
+        }
+
+        let partial = self.partials.entry(message_id).or_insert_with(|| Partial {
+            fragments: vec![None; count as usize],
+            missing: count as usize,
+            bytes: slots,
+            started: now,
+        });
+        self.bytes += slots;
+        if partial.fragments.len() != count as usize {
+            return Err(CodecError::BadFragment { index, count });
+        }
+        let slot = &mut partial.fragments[index as usize];
+        if slot.is_some() {
+            return Ok(None);
+        }
+        *slot = Some(chunk.to_vec());
+        partial.missing -= 1;
+        partial.bytes += chunk.len();
+        self.bytes += chunk.len();
+        if partial.missing > 0 {
+            return Ok(None);
+        }
+
+        let partial = self.partials.remove(&message_id).unwrap();
+        self.bytes -= partial.bytes;
+        self.stats.reassembled += 1;
+
+        Ok(Some(
+            partial.fragments.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    /// Drops incomplete messages older than `REASSEMBLY_TIMEOUT`.
+    pub fn expire(&mut self, now: std::time::Instant) {
+        let expired: Vec<u32> = self
+            .partials
+            .iter()
+            .filter(|(_, partial)| now.duration_since(partial.started) > REASSEMBLY_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.discard(id);
+            self.stats.dropped += 1;
+        }
+    }
+
+    fn discard(&mut self, message_id: u32) {
+        if let Some(partial) = self.partials.remove(&message_id) {
+            self.bytes -= partial.bytes;
+        }
+    }
+
+    pub fn stats(&self) -> ReassemblyStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn fragment(message_id: u32, index: u16, count: u16, chunk: &[u8]) -> Vec<u8> {
+        let mut fragment = vec![];
+        codec::write_header(&mut fragment, kind::FRAGMENT);
+        fragment.extend_from_slice(&message_id.to_le_bytes());
+        fragment.extend_from_slice(&index.to_le_bytes());
+        fragment.extend_from_slice(&count.to_le_bytes());
+        fragment.extend_from_slice(chunk);
+        fragment
+    }
+
+    #[test]
+    fn small_datagram_is_not_split() {
+        let data = datagram(DEFAULT_MTU);
+        assert_eq!(split(data.clone(), 0, DEFAULT_MTU), vec![data]);
+    }
+
+    #[test]
+    fn fragments_reassemble_in_any_order() {
+        let data = datagram(3000);
+        let fragments = split(data.clone(), 9, DEFAULT_MTU);
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments
+            .iter()
+            .all(|fragment| fragment.len() <= DEFAULT_MTU));
+
+        let now = std::time::Instant::now();
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.insert(&fragments[2], now).unwrap(), None);
+        assert_eq!(reassembler.insert(&fragments[0], now).unwrap(), None);
+        // a duplicate does not count towards completing the message
+        assert_eq!(reassembler.insert(&fragments[0], now).unwrap(), None);
+        assert_eq!(reassembler.insert(&fragments[1], now).unwrap(), Some(data));
+        assert_eq!(reassembler.stats().reassembled, 1);
+        assert_eq!(reassembler.bytes, 0);
+    }
+
+    #[test]
+    fn interleaved_messages_are_kept_apart() {
+        let first = datagram(2000);
+        let second: Vec<u8> = datagram(2000).into_iter().rev().collect();
+        let first_fragments = split(first.clone(), 1, DEFAULT_MTU);
+        let second_fragments = split(second.clone(), 2, DEFAULT_MTU);
+
+        let now = std::time::Instant::now();
+        let mut reassembler = Reassembler::default();
+        reassembler.insert(&first_fragments[0], now).unwrap();
+        reassembler.insert(&second_fragments[0], now).unwrap();
+        assert_eq!(
+            reassembler.insert(&second_fragments[1], now).unwrap(),
+            Some(second)
+        );
+        assert_eq!(
+            reassembler.insert(&first_fragments[1], now).unwrap(),
+            Some(first)
+        );
+    }
+
+    #[test]
+    fn incomplete_message_times_out() {
+        let fragments = split(datagram(2000), 3, DEFAULT_MTU);
+        let now = std::time::Instant::now();
+        let mut reassembler = Reassembler::default();
+        reassembler.insert(&fragments[0], now).unwrap();
+
+        reassembler.expire(now + REASSEMBLY_TIMEOUT);
+        assert_eq!(reassembler.stats().dropped, 0);
+        let later = now + REASSEMBLY_TIMEOUT + std::time::Duration::from_millis(1);
+        reassembler.expire(later);
+        assert_eq!(reassembler.stats().dropped, 1);
+        assert_eq!(reassembler.bytes, 0);
+
+        // what arrives afterwards starts over instead of completing the expired message
+        assert_eq!(reassembler.insert(&fragments[1], later).unwrap(), None);
+    }
+
+    #[test]
+    fn too_many_fragments_are_refused() {
+        let now = std::time::Instant::now();
+        let mut reassembler = Reassembler::default();
+        let result = reassembler.insert(&fragment(0, 0, MAX_FRAGMENTS + 1, &[0]), now);
+        assert!(matches!(result, Err(CodecError::Oversized(_))));
+        assert_eq!(reassembler.stats().dropped, 1);
+
+        assert!(reassembler
+            .insert(&fragment(0, 0, MAX_FRAGMENTS, &[0]), now)
+            .is_ok());
+    }
+
+    #[test]
+    fn buffered_bytes_are_limited() {
+        let now = std::time::Instant::now();
+        let mut reassembler = Reassembler::default();
+        let chunk = vec![0; MAX_REASSEMBLY_BYTES / 10];
+        let mut message_id = 0;
+        while reassembler.bytes + 2 * SLOT_LEN + chunk.len() <= MAX_REASSEMBLY_BYTES {
+            reassembler
+                .insert(&fragment(message_id, 0, 2, &chunk), now)
+                .unwrap();
+            message_id += 1;
+        }
+
+        let result = reassembler.insert(&fragment(message_id, 0, 2, &chunk), now);
+        assert!(matches!(result, Err(CodecError::Oversized(_))));
+        assert_eq!(reassembler.stats().dropped, 1);
+        assert!(reassembler.bytes <= MAX_REASSEMBLY_BYTES);
+    }
+
+    #[test]
+    fn incomplete_messages_per_peer_are_limited() {
+        let now = std::time::Instant::now();
+        let mut reassembler = Reassembler::default();
+        for message_id in 0..MAX_PARTIALS as u32 {
+            reassembler
+                .insert(&fragment(message_id, 0, 2, &[0]), now)
+                .unwrap();
+        }
+
+        let result = reassembler.insert(&fragment(MAX_PARTIALS as u32, 0, 2, &[0]), now);
+        assert!(matches!(result, Err(CodecError::TooMany(_))));
+        assert_eq!(reassembler.stats().dropped, 1);
+        // the messages already started can still complete
+        assert_eq!(
+            reassembler.insert(&fragment(0, 1, 2, &[1]), now).unwrap(),
+            Some(vec![0, 1])
+        );
+        assert!(reassembler
+            .insert(&fragment(MAX_PARTIALS as u32, 0, 2, &[0]), now)
+            .is_ok());
+    }
+
+    #[test]
+    fn empty_slots_count_towards_the_buffered_bytes() {
+        let now = std::time::Instant::now();
+        let mut reassembler = Reassembler::default();
+        reassembler
+            .insert(&fragment(0, 0, MAX_FRAGMENTS, &[]), now)
+            .unwrap();
+        assert_eq!(reassembler.bytes, MAX_FRAGMENTS as usize * SLOT_LEN);
+
+        let chunk = vec![0; MAX_REASSEMBLY_BYTES - reassembler.bytes - 2 * SLOT_LEN];
+        reassembler.insert(&fragment(1, 0, 2, &chunk), now).unwrap();
+        assert_eq!(reassembler.bytes, MAX_REASSEMBLY_BYTES);
+
+        // a fragment without any payload still has to fit the slots of its message
+        let result = reassembler.insert(&fragment(2, 0, MAX_FRAGMENTS, &[]), now);
+        assert!(matches!(result, Err(CodecError::Oversized(_))));
+
+        reassembler.expire(now + REASSEMBLY_TIMEOUT * 2);
+        assert_eq!(reassembler.bytes, 0);
+    }
+
+    #[test]
+    fn malformed_fragments_are_rejected() {
+        let now = std::time::Instant::now();
+        let mut reassembler = Reassembler::default();
+        assert!(matches!(
+            reassembler.insert(&fragment(0, 0, 2, &[])[..HEADER_LEN + 4], now),
+            Err(CodecError::Truncated)
+        ));
+        assert!(matches!(
+            reassembler.insert(&fragment(0, 2, 2, &[0]), now),
+            Err(CodecError::BadFragment { index: 2, count: 2 })
+        ));
+        assert!(matches!(
+            reassembler.insert(&fragment(0, 0, 0, &[0]), now),
+            Err(CodecError::BadFragment { .. })
+        ));
+
+        // the count has to stay the same for every fragment of a message
+        reassembler.insert(&fragment(1, 0, 2, &[0]), now).unwrap();
+        assert!(matches!(
+            reassembler.insert(&fragment(1, 1, 3, &[0]), now),
+            Err(CodecError::BadFragment { index: 1, count: 3 })
+        ));
+    }
+}
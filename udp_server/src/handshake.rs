@@ -0,0 +1,113 @@
+use siphasher::sip::SipHasher24;
+use std::hash::Hasher;
+
+/// How long a cookie stays valid. Cookies from the previous period are still accepted, so a
+/// cookie lives between one and two periods.
+pub const COOKIE_PERIOD: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Issues and checks registration cookies. A cookie is a MAC (SipHash-2-4 under a random
+/// 128 bit key) of the client address and the current period, so the server does not keep
+/// anything per client: only someone who can receive at an address can learn the cookie for
+/// it. Works like the HelloVerifyRequest of DTLS.
+pub struct Cookies {
+    key: [u64; 2],
+    started: std::time::Instant,
+}
+
+impl Default for Cookies {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cookies {
+    /// Starts with a random key, cookies do not survive a restart.
+    pub fn new() -> Self {
+        Self {
+            key: [rand::random(), rand::random()],
+            started: std::time::Instant::now(),
+        }
+    }
+
+    fn period(&self, now: std::time::Instant) -> u64 {
+        (now.duration_since(self.started).as_secs() / COOKIE_PERIOD.as_secs()) + 1
+    }
+
+    fn cookie(&self, addr: std::net::SocketAddr, period: u64) -> u64 {
+        let mut mac = SipHasher24::new_with_keys(self.key[0], self.key[1]);
+        match addr.ip() {
+            std::net::IpAddr::V4(ip) => mac.write(&ip.octets()),
+            std::net::IpAddr::V6(ip) => mac.write(&ip.octets()),
+        }
+        mac.write(&addr.port().to_le_bytes());
+        mac.write(&period.to_le_bytes());
+
+        // 0 is what clients send before they were challenged
+        mac.finish().max(1)
+    }
+
+    /// The cookie a client at `addr` has to echo back.
+    pub fn issue(&self, addr: std::net::SocketAddr, now: std::time::Instant) -> u64 {
+        self.cookie(addr, self.period(now))
+    }
+
+    /// Whether `cookie` was issued to `addr` in this period or the one before.
+    pub fn verify(&self, addr: std::net::SocketAddr, cookie: u64, now: std::time::Instant) -> bool {
+        let period = self.period(now);
+        cookie != 0
+            && (cookie == self.cookie(addr, period) || cookie == self.cookie(addr, period - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> std::net::SocketAddr {
+        std::net::SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn issued_cookie_verifies() {
+        let cookies = Cookies::new();
+        let now = std::time::Instant::now();
+        let cookie = cookies.issue(addr(1), now);
+        assert!(cookies.verify(addr(1), cookie, now));
+        assert!(!cookies.verify(addr(1), 0, now));
+        // a server with another key does not take it
+        assert!(!Cookies::new().verify(addr(1), cookie, now));
+    }
+
+    #[test]
+    fn cookie_is_bound_to_its_address() {
+        let cookies = Cookies::new();
+        let now = std::time::Instant::now();
+        let cookie = cookies.issue(addr(1), now);
+        assert!(!cookies.verify(addr(2), cookie, now));
+        let other_ip = std::net::SocketAddr::from(([127, 0, 0, 2], 1));
+        assert!(!cookies.verify(other_ip, cookie, now));
+    }
+
+    #[test]
+    fn cookie_survives_one_period_rollover() {
+        let cookies = Cookies::new();
+        let now = cookies.started;
+        let cookie = cookies.issue(addr(1), now);
+        let next_period = now + COOKIE_PERIOD;
+        assert_ne!(cookies.issue(addr(1), next_period), cookie);
+        assert!(cookies.verify(addr(1), cookie, next_period));
+    }
+
+    #[test]
+    fn cookie_expires_after_two_periods() {
+        let cookies = Cookies::new();
+        let now = cookies.started;
+        let cookie = cookies.issue(addr(1), now);
+        assert!(cookies.verify(
+            addr(1),
+            cookie,
+            now + COOKIE_PERIOD * 2 - std::time::Duration::from_secs(1)
+        ));
+        assert!(!cookies.verify(addr(1), cookie, now + COOKIE_PERIOD * 2));
+    }
+}
@@ -0,0 +1,296 @@
+use crate::spatial::SpatialHashGrid;
+use crate::Point;
+
+/// View every player gets unless it asks for another one, and the largest it may ask for.
+/// Covers the default 1280x720 window of the client with a sprite's worth of margin around it.
+pub const DEFAULT_VIEW_WIDTH: f32 = 1280. + 2. * crate::DEFAULT_HITBOX_SIZE;
+pub const DEFAULT_VIEW_HEIGHT: f32 = 720. + 2. * crate::DEFAULT_HITBOX_SIZE;
+/// A player that is in view only leaves it once it is this much further out than the view,
+/// so that somebody walking along the edge does not keep entering and leaving.
+pub const LEAVE_MARGIN: f32 = 1.1;
+/// Cell size of the grid used to find who is in view.
+const VIEW_CELL_SIZE: f32 = 256.;
+
+/// The rectangle around a player in which it is told about others.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct View {
+    pub half_width: f32,
+    pub half_height: f32,
+}
+
+impl Default for View {
+    fn default() -> Self {
+        Self::new(DEFAULT_VIEW_WIDTH, DEFAULT_VIEW_HEIGHT)
+    }
+}
+
+impl View {
+    pub fn new(width: f32, height: f32) -> Self {
+        Self {
+            half_width: width / 2.,
+            half_height: height / 2.,
+        }
+    }
+
+    fn contains(&self, center: (f32, f32), position: (f32, f32), scale: f32) -> bool {
+        (position.0 - center.0).abs() <= self.half_width * scale
+            && (position.1 - center.1).abs() <= self.half_height * scale
+    }
+
+    /// Clamps a requested view to this one.
+    pub fn limit(&self, requested: View) -> View {
+        View {
+            half_width: requested.half_width.clamp(0., self.half_width),
+            half_height: requested.half_height.clamp(0., self.half_height),
+        }
+    }
+}
+
+/// Something an observer has to be told about.
+#[derive(Debug, Clone)]
+pub enum InterestChange {
+    Enter(Point),
+    Leave(u32),
+}
+
+/// Who sees whom. Each player sees the others inside its view, positions of a player only go
+/// to the players that see it.
+#[derive(Default)]
+pub struct Interest {
+    default_view: View,
+    views: std::collections::HashMap<u32, View>,
+    /// observer -> players it sees
+    visible: std::collections::HashMap<u32, std::collections::HashSet<u32>>,
+    /// player -> observers that see it
+    observers: std::collections::HashMap<u32, std::collections::HashSet<u32>>,
+}
+
+impl Interest {
+    pub fn new(default_view: View) -> Self {
+        Self {
+            default_view,
+            ..Self::default()
+        }
+    }
+
+    /// The view players get by default, requests for larger ones are clamped to it.
+    pub fn set_default_view(&mut self, view: View) {
+        self.default_view = view;
+    }
+
+    pub fn set_view(&mut self, id: u32, view: View) {
+        self.views.insert(id, self.default_view.limit(view));
+    }
+
+    pub fn view(&self, id: u32) -> View {
+        self.views.get(&id).copied().unwrap_or(self.default_view)
+    }
+
+    /// Players that see `id`.
+    pub fn observers(&self, id: u32) -> impl Iterator<Item = u32> + '_ {
+        self.observers.get(&id).into_iter().flatten().copied()
+    }
+
+    /// Players `id` sees.
+    pub fn visible(&self, id: u32) -> impl Iterator<Item = u32> + '_ {
+        self.visible.get(&id).into_iter().flatten().copied()
+    }
+
+    /// Forgets a player, both as observer and as something observed.
+    pub fn remove(&mut self, id: u32) {
+        self.views.remove(&id);
+        for seen in self.visible.remove(&id).unwrap_or_default() {
+            if let Some(observers) = self.observers.get_mut(&seen) {
+                observers.remove(&id);
+            }
+        }
+        for observer in self.observers.remove(&id).unwrap_or_default() {
+            if let Some(visible) = self.visible.get_mut(&observer) {
+                visible.remove(&id);
+            }
+        }
+    }
+
+    /// Starts a player over with exactly the players that are in its view right now and
+    /// returns them, for messages that hand a client the full list of players it sees.
+    pub fn reset(
+        &mut self,
+        id: u32,
+        positions: &std::collections::HashMap<u32, (f32, f32)>,
+    ) -> Vec<Point> {
+        let Some(center) = positions.get(&id).copied() else {
+            return vec![];
+        };
+        let view = self.view(id);
+        let seen = positions
+            .iter()
+            .filter(|(other, position)| **other != id && view.contains(center, **position, 1.))
+            .map(|(other, _)| *other)
+            .collect::<std::collections::HashSet<u32>>();
+        self.set_visible(id, seen.clone());
+
+        seen.into_iter()
+            .map(|other| {
+                let (x, y) = positions[&other];
+                Point { x, y, id: other }
+            })
+            .collect()
+    }
+
+    /// Works out who sees whom after the players moved. Returns what every observer has to
+    /// be told, in no particular order.
+    pub fn update(
+        &mut self,
+        positions: &std::collections::HashMap<u32, (f32, f32)>,
+    ) -> Vec<(u32, InterestChange)> {
+        let mut grid = SpatialHashGrid::new(VIEW_CELL_SIZE);
+        for (id, (x, y)) in positions.iter() {
+            grid.insert(*id, *x, *y);
+        }
+
+        let mut changes = vec![];
+        for (id, center) in positions.iter() {
+            let view = self.view(*id);
+            let reach = view.half_width.max(view.half_height) * LEAVE_MARGIN;
+            let radius = (reach / VIEW_CELL_SIZE).ceil() as i32;
+            let previously = self.visible.get(id).cloned().unwrap_or_default();
+            let seen = grid
+                .within(center.0, center.1, radius)
+                .filter(|other| {
+                    let scale = match previously.contains(other) {
+                        true => LEAVE_MARGIN,
+                        false => 1.,
+                    };
+                    other != id && view.contains(*center, positions[other], scale)
+                })
+                .collect::<std::collections::HashSet<u32>>();
+
+            for entered in seen.difference(&previously) {
+                let (x, y) = positions[entered];
+                changes.push((*id, InterestChange::Enter(Point { x, y, id: *entered })));
+            }
+            for left in previously.difference(&seen) {
+                changes.push((*id, InterestChange::Leave(*left)));
+            }
+            if seen != previously {
+                self.set_visible(*id, seen);
+            }
+        }
+
+        changes
+    }
+
+    fn set_visible(&mut self, id: u32, seen: std::collections::HashSet<u32>) {
+        for previous in self.visible.remove(&id).unwrap_or_default() {
+            if let Some(observers) = self.observers.get_mut(&previous) {
+                observers.remove(&id);
+            }
+        }
+        for other in seen.iter() {
+            self.observers.entry(*other).or_default().insert(id);
+        }
+        self.visible.insert(id, seen);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positions(players: &[(u32, f32, f32)]) -> std::collections::HashMap<u32, (f32, f32)> {
+        players.iter().map(|(id, x, y)| (*id, (*x, *y))).collect()
+    }
+
+    /// Who `observer` saw enter and leave, sorted.
+    fn seen_by(changes: &[(u32, InterestChange)], observer: u32) -> (Vec<u32>, Vec<u32>) {
+        let (mut entered, mut left) = (vec![], vec![]);
+        for (_, change) in changes.iter().filter(|(id, _)| *id == observer) {
+            match change {
+                InterestChange::Enter(point) => entered.push(point.id),
+                InterestChange::Leave(other) => left.push(*other),
+            }
+        }
+        entered.sort_unstable();
+        left.sort_unstable();
+
+        (entered, left)
+    }
+
+    #[test]
+    fn players_enter_and_leave_the_view() {
+        let mut interest = Interest::new(View::new(200., 200.));
+        let changes = interest.update(&positions(&[(0, 0., 0.), (1, 50., -50.), (2, 300., 0.)]));
+        assert_eq!(seen_by(&changes, 0), (vec![1], vec![]));
+        assert_eq!(seen_by(&changes, 1), (vec![0], vec![]));
+        assert_eq!(seen_by(&changes, 2), (vec![], vec![]));
+        assert_eq!(interest.observers(1).collect::<Vec<_>>(), vec![0]);
+
+        // nothing changed, nothing to tell
+        assert!(interest
+            .update(&positions(&[(0, 0., 0.), (1, 50., -50.), (2, 300., 0.)]))
+            .is_empty());
+
+        let changes = interest.update(&positions(&[(0, 0., 0.), (1, 500., 0.), (2, 90., 0.)]));
+        assert_eq!(seen_by(&changes, 0), (vec![2], vec![1]));
+        assert_eq!(interest.visible(0).collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn leaving_takes_an_extra_margin() {
+        let mut interest = Interest::new(View::new(200., 200.));
+        interest.update(&positions(&[(0, 0., 0.), (1, 100., 0.)]));
+        assert_eq!(interest.visible(0).collect::<Vec<_>>(), vec![1]);
+
+        // past the view but within the margin, still seen
+        let changes = interest.update(&positions(&[(0, 0., 0.), (1, 105., 0.)]));
+        assert!(changes.is_empty());
+        let changes = interest.update(&positions(&[
+            (0, 0., 0.),
+            (1, 100. * LEAVE_MARGIN + 1., 0.),
+        ]));
+        assert_eq!(seen_by(&changes, 0), (vec![], vec![1]));
+
+        // coming back only counts once it is inside the view itself
+        let changes = interest.update(&positions(&[(0, 0., 0.), (1, 105., 0.)]));
+        assert_eq!(seen_by(&changes, 0), (vec![], vec![]));
+        let changes = interest.update(&positions(&[(0, 0., 0.), (1, 99., 0.)]));
+        assert_eq!(seen_by(&changes, 0), (vec![1], vec![]));
+    }
+
+    #[test]
+    fn requested_views_are_limited_to_the_default() {
+        let mut interest = Interest::new(View::new(200., 200.));
+        interest.set_view(0, View::new(1000., 50.));
+        assert_eq!(interest.view(0), View::new(200., 50.));
+        assert_eq!(interest.view(1), View::new(200., 200.));
+
+        let changes = interest.update(&positions(&[(0, 0., 0.), (1, 0., 50.)]));
+        assert_eq!(seen_by(&changes, 0), (vec![], vec![]));
+        assert_eq!(seen_by(&changes, 1), (vec![0], vec![]));
+    }
+
+    #[test]
+    fn removed_player_is_forgotten_on_both_sides() {
+        let mut interest = Interest::new(View::new(200., 200.));
+        interest.update(&positions(&[(0, 0., 0.), (1, 10., 0.), (2, 20., 0.)]));
+        interest.remove(1);
+        assert_eq!(interest.visible(0).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(interest.observers(0).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(interest.observers(1).count(), 0);
+        assert_eq!(interest.visible(1).count(), 0);
+    }
+
+    #[test]
+    fn reset_starts_over_with_who_is_in_view() {
+        let mut interest = Interest::new(View::new(200., 200.));
+        interest.update(&positions(&[(0, 0., 0.), (1, 100., 0.)]));
+        let now = positions(&[(0, 0., 0.), (1, 105., 0.), (2, -20., 20.)]);
+        let points = interest.reset(0, &now);
+        assert_eq!(
+            points.iter().map(|point| point.id).collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert_eq!(interest.visible(0).collect::<Vec<_>>(), vec![2]);
+        assert!(interest.reset(7, &now).is_empty());
+    }
+}
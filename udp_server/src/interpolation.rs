@@ -0,0 +1,304 @@
+use crate::Point;
+
+/// Default for how far in the past remote players are shown, room for snapshots that arrive
+/// late or unevenly.
+pub const DEFAULT_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+/// Default for how far past the newest snapshot players keep moving before they stop.
+pub const DEFAULT_MAX_EXTRAPOLATION: std::time::Duration = std::time::Duration::from_millis(50);
+/// How quickly the estimated clock offset follows snapshots that arrive later than the fastest
+/// one, so that it catches up with clocks drifting apart but not with single late packets.
+const OFFSET_DRIFT: f64 = 1. / 128.;
+
+type Positions = std::collections::HashMap<u32, (f32, f32)>;
+
+/// How the jitter buffer is doing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JitterStats {
+    /// How far the newest snapshot is ahead of the time shown, zero while the buffer is dry
+    pub depth: std::time::Duration,
+    pub buffered: usize,
+    /// How often the buffer ran dry and players had to be extrapolated. The server sends
+    /// nothing while nothing moves, so this includes everybody standing still
+    pub underruns: u64,
+    /// Snapshots that arrived after the time they were for was already shown
+    pub late: u64,
+}
+
+/// Client side: the snapshots received, played back `delay` behind the server. Positions in
+/// between are interpolated, so players move smoothly however unevenly the snapshots arrive.
+/// When the buffer runs dry players keep their last velocity for up to `max_extrapolation`.
+pub struct JitterBuffer {
+    delay: f64,
+    max_extrapolation: f64,
+    epoch: std::time::Instant,
+    /// Local time minus server time in milliseconds, as seen by the fastest snapshots
+    offset: Option<f64>,
+    /// Server time of the newest snapshot as sent, to unwrap the next one
+    newest: Option<(u32, f64)>,
+    /// The last snapshot that was played back completely, for the velocity when extrapolating
+    previous: Option<(f64, Positions)>,
+    snapshots: std::collections::VecDeque<(f64, Positions)>,
+    dry: bool,
+    stats: JitterStats,
+}
+
+impl Default for JitterBuffer {
+    fn default() -> Self {
+        Self::new(DEFAULT_DELAY, DEFAULT_MAX_EXTRAPOLATION)
+    }
+}
+
+impl JitterBuffer {
+    pub fn new(delay: std::time::Duration, max_extrapolation: std::time::Duration) -> Self {
+        Self {
+            delay: delay.as_secs_f64() * 1000.,
+            max_extrapolation: max_extrapolation.as_secs_f64() * 1000.,
+            epoch: std::time::Instant::now(),
+            offset: None,
+            newest: None,
+            previous: None,
+            snapshots: std::collections::VecDeque::new(),
+            dry: false,
+            stats: JitterStats::default(),
+        }
+    }
+
+    /// Adds the positions of a snapshot taken at server time `sent` that arrived at `now`.
+    /// Snapshots that are not newer than the newest one are ignored.
+    pub fn push(&mut self, sent: u32, positions: Vec<Point>, now: std::time::Instant) {
+        let time = match self.newest {
+            Some((raw, unwrapped)) => unwrapped + sent.wrapping_sub(raw) as i32 as f64,
+            None => sent as f64,
+        };
+        if self
+            .snapshots
+            .back()
+            .is_some_and(|(newest, _)| time <= *newest)
+        {
+            return;
+        }
+
+        let arrived = self.local_time(now) - time;
+        let offset = match self.offset {
+            Some(offset) if arrived > offset => offset + (arrived - offset) * OFFSET_DRIFT,
+            _ => arrived,
+        };
+        self.offset = Some(offset);
+        let render = self.render_time(now);
+        if time < render {
+            self.stats.late += 1;
+        } else if self
+            .snapshots
+            .back()
+            .is_some_and(|(newest, _)| *newest < render)
+        {
+            // the server sent nothing for a while since nothing moved, so play back from what
+            // is shown now instead of from the snapshot before the gap
+            let shown = self.sample(now);
+            self.clear();
+            self.snapshots.push_back((render, to_positions(shown)));
+        }
+
+        self.newest = Some((sent, time));
+        self.snapshots.push_back((time, to_positions(positions)));
+    }
+
+    /// Where every player is at `now`, that is `delay` behind the server.
+    pub fn sample(&mut self, now: std::time::Instant) -> Vec<Point> {
+        let render = self.render_time(now);
+        while self.snapshots.len() >= 2 && self.snapshots[1].0 <= render {
+            self.previous = self.snapshots.pop_front();
+        }
+        self.stats.buffered = self.snapshots.len();
+        let Some((first, positions)) = self.snapshots.front() else {
+            return vec![];
+        };
+
+        let newest = self.snapshots.back().map_or(*first, |(time, _)| *time);
+        self.stats.depth = std::time::Duration::from_secs_f64((newest - render).max(0.) / 1000.);
+        let dry = self.snapshots.len() == 1 && render > *first;
+        if dry && !self.dry {
+            self.stats.underruns += 1;
+        }
+        self.dry = dry;
+
+        if render <= *first {
+            // still filling up
+            return to_points(positions.iter().map(|(id, position)| (*id, *position)));
+        }
+        match self.snapshots.get(1) {
+            Some((next_time, next)) => {
+                let t = ((render - first) / (next_time - first)) as f32;
+                to_points(next.iter().map(|(id, to)| {
+                    let from = positions.get(id).unwrap_or(to);
+                    (*id, lerp(*from, *to, t))
+                }))
+            }
+            None => {
+                let ahead = (render - first).min(self.max_extrapolation);
+                let Some((previous_time, previous)) = &self.previous else {
+                    return to_points(positions.iter().map(|(id, position)| (*id, *position)));
+                };
+                let t = (1. + ahead / (first - previous_time)) as f32;
+                to_points(positions.iter().map(|(id, to)| {
+                    let from = previous.get(id).unwrap_or(to);
+                    (*id, lerp(*from, *to, t))
+                }))
+            }
+        }
+    }
+
+    /// Forgets every snapshot, e.g. when the players seen are about to be different ones.
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.previous = None;
+        self.dry = false;
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        self.stats
+    }
+
+    fn local_time(&self, now: std::time::Instant) -> f64 {
+        now.saturating_duration_since(self.epoch).as_secs_f64() * 1000.
+    }
+
+    /// The server time shown at `now`.
+    fn render_time(&self, now: std::time::Instant) -> f64 {
+        self.local_time(now) - self.offset.unwrap_or(0.) - self.delay
+    }
+}
+
+fn lerp(from: (f32, f32), to: (f32, f32), t: f32) -> (f32, f32) {
+    (from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t)
+}
+
+fn to_points(positions: impl Iterator<Item = (u32, (f32, f32))>) -> Vec<Point> {
+    positions.map(|(id, (x, y))| Point { x, y, id }).collect()
+}
+
+fn to_positions(points: Vec<Point>) -> Positions {
+    points
+        .into_iter()
+        .map(|point| (point.id, (point.x, point.y)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+    const MAX_EXTRAPOLATION: std::time::Duration = std::time::Duration::from_millis(50);
+
+    fn at(buffer: &JitterBuffer, millis: u64) -> std::time::Instant {
+        buffer.epoch + std::time::Duration::from_millis(millis)
+    }
+
+    fn player(x: f32) -> Vec<Point> {
+        vec![Point { x, y: -x, id: 1 }]
+    }
+
+    fn x(points: &[Point]) -> f32 {
+        match points {
+            [point] => point.x,
+            other => panic!("expected one player, got {:?}", other),
+        }
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn positions_are_interpolated_delay_behind() {
+        let mut buffer = JitterBuffer::new(DELAY, MAX_EXTRAPOLATION);
+        for (sent, position) in [(0, 0.), (50, 10.), (100, 20.)] {
+            buffer.push(sent, player(position), at(&buffer, sent as u64));
+        }
+
+        // still filling up, the oldest snapshot is shown
+        assert_near(x(&buffer.sample(at(&buffer, 80))), 0.);
+        assert_near(x(&buffer.sample(at(&buffer, 125))), 5.);
+        assert_near(x(&buffer.sample(at(&buffer, 150))), 10.);
+        let points = buffer.sample(at(&buffer, 175));
+        assert_near(x(&points), 15.);
+        assert_near(points[0].y, -15.);
+        assert_eq!(buffer.stats().underruns, 0);
+    }
+
+    #[test]
+    fn uneven_arrival_does_not_change_playback() {
+        let mut buffer = JitterBuffer::new(DELAY, MAX_EXTRAPOLATION);
+        // the second snapshot is 40ms late, the third arrives right after it
+        for (sent, arrived, position) in [(0, 0, 0.), (50, 90, 10.), (100, 100, 20.)] {
+            buffer.push(sent, player(position), at(&buffer, arrived));
+        }
+
+        assert_near(x(&buffer.sample(at(&buffer, 125))), 5.);
+        assert_near(x(&buffer.sample(at(&buffer, 175))), 15.);
+        assert_eq!(buffer.stats().late, 0);
+    }
+
+    #[test]
+    fn players_are_extrapolated_for_a_while_when_dry() {
+        let mut buffer = JitterBuffer::new(DELAY, MAX_EXTRAPOLATION);
+        buffer.push(0, player(0.), at(&buffer, 0));
+        buffer.push(50, player(10.), at(&buffer, 50));
+
+        assert_near(x(&buffer.sample(at(&buffer, 170))), 14.);
+        // extrapolation stops after MAX_EXTRAPOLATION
+        assert_near(x(&buffer.sample(at(&buffer, 400))), 20.);
+        assert_eq!(buffer.stats().underruns, 1);
+        assert_eq!(buffer.stats().depth, std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn playback_resumes_from_what_is_shown_after_a_gap() {
+        let mut buffer = JitterBuffer::new(DELAY, MAX_EXTRAPOLATION);
+        buffer.push(0, player(0.), at(&buffer, 0));
+        buffer.push(50, player(10.), at(&buffer, 50));
+        // nothing moved for a while, then the player shows up somewhere else
+        buffer.push(1000, player(100.), at(&buffer, 1000));
+
+        assert_near(x(&buffer.sample(at(&buffer, 1000))), 20.);
+        assert_near(x(&buffer.sample(at(&buffer, 1050))), 60.);
+        assert_near(x(&buffer.sample(at(&buffer, 1100))), 100.);
+    }
+
+    #[test]
+    fn old_and_late_snapshots() {
+        let mut buffer = JitterBuffer::new(DELAY, MAX_EXTRAPOLATION);
+        buffer.push(0, player(0.), at(&buffer, 0));
+        buffer.push(50, player(10.), at(&buffer, 50));
+        // reordered, older than the newest one
+        buffer.push(40, player(50.), at(&buffer, 60));
+        assert_near(x(&buffer.sample(at(&buffer, 125))), 5.);
+
+        // arrives after the time it was for was shown
+        buffer.push(60, player(12.), at(&buffer, 400));
+        assert_eq!(buffer.stats().late, 1);
+    }
+
+    #[test]
+    fn server_time_wraps() {
+        let mut buffer = JitterBuffer::new(DELAY, MAX_EXTRAPOLATION);
+        buffer.push(u32::MAX - 24, player(0.), at(&buffer, 0));
+        buffer.push(25, player(10.), at(&buffer, 50));
+
+        assert_near(x(&buffer.sample(at(&buffer, 125))), 5.);
+    }
+
+    #[test]
+    fn clear_forgets_everything() {
+        let mut buffer = JitterBuffer::default();
+        buffer.push(0, player(0.), at(&buffer, 0));
+        buffer.clear();
+        assert!(buffer.sample(at(&buffer, 200)).is_empty());
+    }
+}
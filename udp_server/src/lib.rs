@@ -1,5 +1,28 @@
 use serde::{Deserialize, Serialize};
 
+pub mod client;
+pub mod codec;
+pub mod fragment;
+pub mod handshake;
+pub mod interest;
+pub mod interpolation;
+pub mod movement;
+pub mod outbox;
+pub mod protocol;
+pub mod ratelimit;
+pub mod reliability;
+pub mod room;
+pub mod server;
+pub mod session;
+pub mod snapshot;
+pub mod spatial;
+mod state;
+pub mod tick;
+
+pub use client::{BellClient, ClientEvent};
+pub use codec::{decode, encode, encode_with, WireFormat};
+pub use server::{Server, ServerBuilder, ServerEvent, ServerStats};
+
 #[derive(Deserialize, Serialize, Default, Debug, Clone)]
 pub struct Point {
     pub x: f32,
@@ -7,58 +30,412 @@ pub struct Point {
     pub id: u32,
 }
 
+/// What a client sends to join: where it starts, which protocol version it speaks and which
+/// optional features it supports (see `protocol::capabilities`).
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Registration {
+    pub point: Point,
+    pub protocol_version: u8,
+    pub capabilities: u32,
+    /// Cookie from the last `ChallengeMessage`, 0 before the server sent one
+    pub cookie: u64,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum BellMessage {
     PositionChangeMessage(Point),
     DeferMessage,
     PlayerInsertionMessage(Point),
-    PlayerRegistrationMessage(Point),
-    RegistrationReplyMessage(u32, Vec<Point>)
+    PlayerRegistrationMessage(Registration),
+    /// Assigned id, positions of the other players, the accepted capabilities, the session
+    /// token every later message has to be sent with and the area players have to stay in
+    RegistrationReplyMessage(u32, Vec<Point>, u32, u64, movement::WorldBounds),
+    /// Registration refused, carries the minimum protocol version the server accepts
+    RegistrationRejectedMessage(u8),
+    /// Registration refused because the client did not ask for capabilities the server
+    /// requires, carries the missing ones
+    MissingCapabilitiesMessage(u32),
+    /// Sent by a client that is shutting down cleanly
+    PlayerLeaveMessage(u32),
+    /// Sent periodically by a client so the server knows it is still there
+    HeartbeatMessage(u32),
+    /// Tells clients to stop rendering a player that left or timed out
+    PlayerRemovalMessage(u32),
+    /// Registration parked because the game is full, carries the 1-based waitlist position
+    ServerFullMessage(u32),
+    /// Player id, input sequence number and the held directions (see `movement::input`)
+    InputMessage(u32, u32, u8),
+    /// Two players started touching
+    CollisionMessage(u32, u32),
+    /// Asks for the rooms on the server, carries the player id
+    ListRoomsMessage(u32),
+    RoomListMessage(Vec<room::RoomInfo>),
+    /// Player id, room name, player limit (0 for the server's) and optional password. The
+    /// player moves into the room once it is created
+    CreateRoomMessage(u32, String, u16, Option<String>),
+    /// Player id, room name and the password if the room has one
+    JoinRoomMessage(u32, String, Option<String>),
+    /// The player is now in the named room, carries the positions of the others in it
+    RoomJoinedMessage(String, Vec<Point>),
+    RoomErrorMessage(room::RoomError),
+    /// Answer to a registration without a valid cookie, the client has to register again with
+    /// this cookie (see `handshake`)
+    ChallengeMessage(u64),
+    /// Another player came into view, the client should show it
+    EnterViewMessage(Point),
+    /// A player went out of view, the client should stop showing it
+    LeaveViewMessage(u32),
+    /// Player id and the width and height of the area around it the client wants to hear
+    /// about. The server may make it smaller
+    SetViewMessage(u32, f32, f32),
+    /// Positions of the players a client sees, relative to a snapshot it acked before
+    SnapshotMessage(snapshot::Snapshot),
+    /// Player id and the tick of a snapshot the client received
+    SnapshotAckMessage(u32, u32),
+}
+
+impl BellMessage {
+    /// The player a client message claims to come from, None for messages that do not name
+    /// one.
+    pub fn player_id(&self) -> Option<u32> {
+        match self {
+            BellMessage::PositionChangeMessage(point) => Some(point.id),
+            BellMessage::PlayerLeaveMessage(id)
+            | BellMessage::HeartbeatMessage(id)
+            | BellMessage::SetViewMessage(id, _, _)
+            | BellMessage::SnapshotAckMessage(id, _)
+            | BellMessage::InputMessage(id, _, _)
+            | BellMessage::ListRoomsMessage(id)
+            | BellMessage::CreateRoomMessage(id, _, _, _)
+            | BellMessage::JoinRoomMessage(id, _, _) => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+/// Player limit used when the server is not told otherwise.
+pub const DEFAULT_MAX_PLAYERS: usize = 16;
+/// How many commands can wait for the state task before new ones are dropped.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1000;
+/// Side of the square hitbox around each player, matches the 225x225 sprite of the client.
+pub const DEFAULT_HITBOX_SIZE: f32 = 225.;
+
+struct WaitlistEntry {
+    addr: std::net::SocketAddr,
+    registration: Registration,
+    last_seen: std::time::Instant,
 }
 
 pub struct GameState {
-    capacity: usize,
-    process_queue: Vec<Option<BellMessage>>,
+    max_players: usize,
     positions: std::collections::HashMap<u32, (f32, f32)>,
     addrs: std::collections::HashMap<u32, std::net::SocketAddr>,
+    last_seen: std::collections::HashMap<u32, std::time::Instant>,
+    capabilities: std::collections::HashMap<u32, u32>,
+    inputs: std::collections::HashMap<u32, (u32, u8)>,
+    move_budgets: std::collections::HashMap<u32, movement::MoveBudget>,
+    suspicious_moves: std::collections::HashMap<u32, u32>,
+    max_speed: f32,
+    bounds: movement::WorldBounds,
+    interest: interest::Interest,
+    hitbox_size: f32,
+    active_collisions: std::collections::HashSet<(u32, u32)>,
+    waitlist: std::collections::VecDeque<WaitlistEntry>,
 }
 impl GameState {
-    pub fn new_with_capacity(capacity: usize) -> Self {
+    /// `max_players` bounds how many players can be in the game at once. Registrations over
+    /// the limit go to the waitlist.
+    pub fn new_with_limits(max_players: usize) -> Self {
         Self {
-            capacity,
-            process_queue: Vec::<Option<BellMessage>>::with_capacity(capacity),
+            max_players,
             positions: std::collections::HashMap::<u32, (f32, f32)>::with_capacity(2),
             addrs: std::collections::HashMap::<u32, std::net::SocketAddr>::with_capacity(2),
+            last_seen: std::collections::HashMap::<u32, std::time::Instant>::with_capacity(2),
+            capabilities: std::collections::HashMap::<u32, u32>::with_capacity(2),
+            inputs: std::collections::HashMap::<u32, (u32, u8)>::with_capacity(2),
+            move_budgets: std::collections::HashMap::new(),
+            suspicious_moves: std::collections::HashMap::new(),
+            max_speed: movement::PLAYER_SPEED,
+            bounds: movement::WorldBounds::default(),
+            interest: interest::Interest::default(),
+            hitbox_size: DEFAULT_HITBOX_SIZE,
+            active_collisions: std::collections::HashSet::new(),
+            waitlist: std::collections::VecDeque::new(),
         }
     }
 
+    pub fn set_hitbox_size(&mut self, hitbox_size: f32) {
+        self.hitbox_size = hitbox_size;
+    }
+
+    /// Speed limit for positions sent by clients and the area players have to stay in.
+    pub fn set_movement_limits(&mut self, max_speed: f32, bounds: movement::WorldBounds) {
+        self.max_speed = max_speed;
+        self.bounds = bounds;
+    }
+
+    pub fn bounds(&self) -> movement::WorldBounds {
+        self.bounds
+    }
+
+    /// Adds a player, starting positions outside the world are moved inside it.
     pub fn insert_player(&mut self, id: u32, x: f32, y: f32, addr: std::net::SocketAddr) {
+        let position = match x.is_finite() && y.is_finite() {
+            true => self.bounds.clamp((x, y)),
+            false => (0., 0.),
+        };
         self.addrs.insert(id, addr);
-        self.positions.insert(id, (x, y));
+        self.positions.insert(id, position);
+        self.last_seen.insert(id, std::time::Instant::now());
+    }
+
+    /// Forgets everything about a player. Returns false if the player was not known.
+    pub fn remove_player(&mut self, id: u32) -> bool {
+        self.positions.remove(&id);
+        self.last_seen.remove(&id);
+        self.capabilities.remove(&id);
+        self.inputs.remove(&id);
+        self.move_budgets.remove(&id);
+        self.suspicious_moves.remove(&id);
+        self.interest.remove(id);
+        self.addrs.remove(&id).is_some()
+    }
+
+    /// The view players get unless they ask for a smaller one.
+    pub fn set_default_view(&mut self, view: interest::View) {
+        self.interest.set_default_view(view);
+    }
+
+    /// Sets the view a client asked for, clamped to the default one.
+    pub fn set_view(&mut self, id: u32, width: f32, height: f32) {
+        if self.positions.contains_key(&id) && width.is_finite() && height.is_finite() {
+            self.interest
+                .set_view(id, interest::View::new(width, height));
+        }
+    }
+
+    /// The players a player sees right now. Whoever gets this list is considered to know
+    /// about exactly these players from then on.
+    pub fn reset_interest(&mut self, id: u32) -> Vec<Point> {
+        self.interest.reset(id, &self.positions)
+    }
+
+    /// Works out who came into or went out of view of whom since the last update.
+    pub fn update_interest(&mut self) -> Vec<(u32, interest::InterestChange)> {
+        self.interest.update(&self.positions)
+    }
+
+    /// Moves a player to a position it sent, if it could have gotten there since its last
+    /// accepted move without going over the speed limit or leaving the world. Otherwise the
+    /// player is put where it could have gotten and the correction is returned so the client
+    /// can be told.
+    pub fn validate_move(
+        &mut self,
+        id: u32,
+        x: f32,
+        y: f32,
+    ) -> Result<(), movement::MoveCorrection> {
+        let now = std::time::Instant::now();
+        let Some(position) = self.positions.get_mut(&id) else {
+            return Ok(());
+        };
+        let max_speed = self.max_speed;
+        let checked = self
+            .move_budgets
+            .entry(id)
+            .or_insert_with(|| movement::MoveBudget::new(max_speed, now))
+            .check(*position, (x, y), max_speed, &self.bounds, now);
+        match checked {
+            Ok(accepted) => {
+                *position = accepted;
+                Ok(())
+            }
+            Err((corrected, violation)) => {
+                *position = corrected;
+                let suspicious = self.suspicious_moves.entry(id).or_default();
+                if violation.is_suspicious() {
+                    *suspicious += 1;
+                }
+                Err(movement::MoveCorrection {
+                    point: Point {
+                        x: corrected.0,
+                        y: corrected.1,
+                        id,
+                    },
+                    violation,
+                    suspicious: *suspicious,
+                })
+            }
+        }
+    }
+
+    /// Stores the directions a player is holding. Inputs that arrive out of order (an older
+    /// sequence number than the last one applied) are ignored and false is returned.
+    pub fn apply_input(&mut self, id: u32, sequence: u32, inputs: u8) -> bool {
+        if !self.positions.contains_key(&id) {
+            return false;
+        }
+        match self.inputs.get(&id) {
+            Some((last_sequence, _)) if !movement::is_newer(sequence, *last_sequence) => false,
+            _ => {
+                self.inputs.insert(id, (sequence, inputs));
+                true
+            }
+        }
+    }
+
+    /// Advances every player that is holding a direction by `dt` seconds at the speed limit
+    /// and returns the new positions of the ones that moved.
+    pub fn simulate(&mut self, dt: f32) -> Vec<Point> {
+        let mut moved = vec![];
+        for (id, (_, inputs)) in self.inputs.iter() {
+            if *inputs == 0 {
+                continue;
+            }
+            if let Some(position) = self.positions.get_mut(id) {
+                *position =
+                    self.bounds
+                        .clamp(movement::step(*position, *inputs, self.max_speed, dt));
+                moved.push(Point {
+                    x: position.0,
+                    y: position.1,
+                    id: *id,
+                });
+            }
+        }
+
+        moved
+    }
+
+    pub fn set_capabilities(&mut self, id: u32, capabilities: u32) {
+        self.capabilities.insert(id, capabilities);
     }
+
+    /// Capabilities accepted for a player during registration.
+    pub fn get_capabilities(&self, id: u32) -> u32 {
+        self.capabilities.get(&id).copied().unwrap_or(0)
+    }
+
+    pub fn get_id_for_addr(&self, addr: &std::net::SocketAddr) -> Option<u32> {
+        self.addrs
+            .iter()
+            .find(|(_, player_addr)| *player_addr == addr)
+            .map(|(id, _)| *id)
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.addrs.len()
+    }
+
+    pub fn max_players(&self) -> usize {
+        self.max_players
+    }
+
+    /// True when no more players can join.
     pub fn is_full(&self) -> bool {
-        self.process_queue.len() >= self.capacity
+        self.player_count() >= self.max_players
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.process_queue.is_empty()
+    /// Parks a registration until a slot frees up and returns its 1-based position.
+    /// An address that is already waiting keeps its place.
+    pub fn join_waitlist(&mut self, addr: std::net::SocketAddr, registration: Registration) -> u32 {
+        let now = std::time::Instant::now();
+        if let Some(position) = self.waitlist.iter().position(|entry| entry.addr == addr) {
+            let entry = &mut self.waitlist[position];
+            entry.registration = registration;
+            entry.last_seen = now;
+            return position as u32 + 1;
+        }
+
+        self.waitlist.push_back(WaitlistEntry {
+            addr,
+            registration,
+            last_seen: now,
+        });
+
+        self.waitlist.len() as u32
+    }
+
+    /// Takes the next registration off the waitlist if there is room for it.
+    pub fn pop_waitlist(&mut self) -> Option<(std::net::SocketAddr, Registration)> {
+        if self.is_full() {
+            return None;
+        }
+
+        self.waitlist
+            .pop_front()
+            .map(|entry| (entry.addr, entry.registration))
     }
 
-    pub fn queue_message(&mut self, message: BellMessage) {
-        self.process_queue.push(Some(message));
+    /// Addresses still waiting, in order.
+    pub fn get_waitlist_addrs(&self) -> Vec<std::net::SocketAddr> {
+        self.waitlist.iter().map(|entry| entry.addr).collect()
     }
 
-    pub fn retrieve_messages(&mut self) -> Vec<BellMessage> {
-        let mut messages = Vec::<BellMessage>::with_capacity(self.process_queue.len());
-        while !self.process_queue.is_empty() {
-            messages.push(self.process_queue.pop().unwrap().unwrap());
+    /// Records that we just heard from a player.
+    pub fn touch(&mut self, id: u32) {
+        if let Some(last_seen) = self.last_seen.get_mut(&id) {
+            *last_seen = std::time::Instant::now();
         }
+    }
 
-        messages
+    /// Removes every player that has not been heard from within `timeout` and returns their ids.
+    /// Waitlisted registrations that stopped being refreshed are dropped as well.
+    pub fn evict_stale(&mut self, timeout: std::time::Duration) -> Vec<u32> {
+        let now = std::time::Instant::now();
+        let stale = self
+            .last_seen
+            .iter()
+            .filter(|(_, last_seen)| now.duration_since(**last_seen) > timeout)
+            .map(|(id, _)| *id)
+            .collect::<Vec<u32>>();
+        for id in stale.iter() {
+            self.remove_player(*id);
+        }
+        self.waitlist
+            .retain(|entry| now.duration_since(entry.last_seen) <= timeout);
+
+        stale
     }
 
+    /// Every pair of players whose hitboxes overlap, smaller id first, sorted.
     pub fn get_collided_pairs(&self) -> Vec<(u32, u32)> {
-        vec![]
+        // with cells as large as a hitbox, overlapping players are at most one cell apart
+        let mut grid = spatial::SpatialHashGrid::new(self.hitbox_size);
+        for (id, (x, y)) in self.positions.iter() {
+            grid.insert(*id, *x, *y);
+        }
+
+        let mut pairs = vec![];
+        for (id, (x, y)) in self.positions.iter() {
+            for other in grid.neighbours(*x, *y) {
+                if other <= *id {
+                    continue;
+                }
+                let (other_x, other_y) = self.positions[&other];
+                if (x - other_x).abs() < self.hitbox_size && (y - other_y).abs() < self.hitbox_size
+                {
+                    pairs.push((*id, other));
+                }
+            }
+        }
+        pairs.sort_unstable();
+
+        pairs
+    }
+
+    /// Recomputes the collisions and returns only the pairs that were not touching the last
+    /// time this was called.
+    pub fn update_collisions(&mut self) -> Vec<(u32, u32)> {
+        let pairs = self.get_collided_pairs();
+        let started = pairs
+            .iter()
+            .filter(|pair| !self.active_collisions.contains(pair))
+            .copied()
+            .collect::<Vec<(u32, u32)>>();
+        self.active_collisions = pairs.into_iter().collect();
+
+        started
     }
 
     pub fn get_addr_from_id(&self, id: u32) -> Option<&std::net::SocketAddr> {
@@ -67,7 +444,7 @@ impl GameState {
 
     pub fn get_addrs_for_id(&self, id: u32) -> Vec<&std::net::SocketAddr> {
         let mut res_addrs = Vec::with_capacity(3);
-        println!("addrs: {:?}", self.addrs);
+        log::trace!("addrs: {:?}", self.addrs);
         for (k, v) in self.addrs.iter() {
             if *k != id {
                 res_addrs.push(v);
@@ -77,15 +454,78 @@ impl GameState {
         res_addrs
     }
 
-    pub fn get_points_for_id(&self, id: u32) -> Vec<Point> {
-        self.positions
-            .iter()
-            .filter(|(k, _)| **k != id)
-            .map(|(k, (x, y))| Point {
-                x: *x,
-                y: *y,
-                id: *k,
+    /// Addresses of the players that see `id`.
+    pub fn get_observer_addrs(&self, id: u32) -> Vec<&std::net::SocketAddr> {
+        self.interest
+            .observers(id)
+            .filter_map(|observer| self.addrs.get(&observer))
+            .collect()
+    }
+
+    /// What goes into the snapshot of a player: the players it sees, and itself if the server
+    /// moves it.
+    pub fn snapshot_positions(
+        &self,
+        id: u32,
+    ) -> std::collections::HashMap<u32, snapshot::Quantized> {
+        let moves_by_input =
+            self.get_capabilities(id) & protocol::capabilities::INPUT_MOVEMENT != 0;
+        self.interest
+            .visible(id)
+            .chain(moves_by_input.then_some(id))
+            .filter_map(|other| {
+                let position = self.positions.get(&other)?;
+                Some((other, snapshot::quantize(*position)))
             })
-            .collect::<Vec<Point>>()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> std::net::SocketAddr {
+        std::net::SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn inputs_move_players_at_the_configured_speed() {
+        let mut game_state = GameState::new_with_limits(2);
+        game_state.set_movement_limits(400., movement::WorldBounds::new(2000., 2000.));
+        game_state.insert_player(1, 0., 0., addr(1));
+        assert!(game_state.apply_input(1, 1, movement::input::RIGHT));
+
+        let moved = game_state.simulate(0.5);
+        assert_eq!(moved.len(), 1);
+        assert_eq!((moved[0].x, moved[0].y), (200., 0.));
+    }
+
+    #[test]
+    fn overlapping_hitboxes_collide() {
+        let mut game_state = GameState::new_with_limits(4);
+        game_state.set_hitbox_size(10.);
+        game_state.insert_player(3, 0., 0., addr(3));
+        game_state.insert_player(1, 9.9, -9.9, addr(1));
+        game_state.insert_player(2, -10., 0., addr(2));
+        game_state.insert_player(4, 100., 100., addr(4));
+
+        // exactly a hitbox apart is touching, not overlapping
+        assert_eq!(game_state.get_collided_pairs(), vec![(1, 3)]);
+    }
+
+    #[test]
+    fn collisions_are_reported_once_until_they_end() {
+        let mut game_state = GameState::new_with_limits(2);
+        game_state.set_hitbox_size(10.);
+        game_state.insert_player(1, 0., 0., addr(1));
+        game_state.insert_player(2, 5., 0., addr(2));
+
+        assert_eq!(game_state.update_collisions(), vec![(1, 2)]);
+        assert!(game_state.update_collisions().is_empty());
+        game_state.remove_player(2);
+        assert!(game_state.update_collisions().is_empty());
+        game_state.insert_player(2, 5., 5., addr(2));
+        assert_eq!(game_state.update_collisions(), vec![(1, 2)]);
     }
 }
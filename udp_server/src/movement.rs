@@ -0,0 +1,296 @@
+use serde::{Deserialize, Serialize};
+
+/// Units per second a player moves while a direction is held. The client uses the same value
+/// when it moves its own sprite.
+pub const PLAYER_SPEED: f32 = 150.;
+
+/// Bits of the direction set carried by an `InputMessage`.
+pub mod input {
+    pub const UP: u8 = 1 << 0;
+    pub const DOWN: u8 = 1 << 1;
+    pub const LEFT: u8 = 1 << 2;
+    pub const RIGHT: u8 = 1 << 3;
+}
+
+/// Unit direction for a set of held inputs. Opposite directions cancel out and diagonals are
+/// normalised so they are not faster than straight moves.
+pub fn direction(inputs: u8) -> (f32, f32) {
+    let axis = |positive: u8, negative: u8| {
+        (inputs & positive != 0) as i8 as f32 - (inputs & negative != 0) as i8 as f32
+    };
+    let (x, y) = (
+        axis(input::RIGHT, input::LEFT),
+        axis(input::UP, input::DOWN),
+    );
+    let length = (x * x + y * y).sqrt();
+    if length == 0. {
+        return (0., 0.);
+    }
+
+    (x / length, y / length)
+}
+
+/// Moves a position along the held inputs at `speed` units per second for `dt` seconds.
+pub fn step(position: (f32, f32), inputs: u8, speed: f32, dt: f32) -> (f32, f32) {
+    let (dx, dy) = direction(inputs);
+    (position.0 + dx * speed * dt, position.1 + dy * speed * dt)
+}
+
+/// Whether sequence number `a` is newer than `b`, allowing the counter to wrap.
+pub fn is_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < u32::MAX / 2
+}
+
+/// How far ahead of the speed limit a player may get, in seconds of movement. Position
+/// updates that were sent a frame apart can arrive in the same tick, this keeps them from
+/// looking like teleports.
+pub const MOVE_SLACK: f32 = 0.5;
+/// Headroom on top of the speed limit for clocks and frame times that do not quite agree.
+pub const SPEED_TOLERANCE: f32 = 1.1;
+
+/// The area players can be in, centered on the origin. The default matches the default
+/// 1280x720 window of the client, the server tells clients the one it uses when they register.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct WorldBounds {
+    pub half_width: f32,
+    pub half_height: f32,
+}
+
+impl Default for WorldBounds {
+    fn default() -> Self {
+        Self {
+            half_width: 640.,
+            half_height: 360.,
+        }
+    }
+}
+
+impl WorldBounds {
+    pub fn new(width: f32, height: f32) -> Self {
+        Self {
+            half_width: width / 2.,
+            half_height: height / 2.,
+        }
+    }
+
+    pub fn contains(&self, position: (f32, f32)) -> bool {
+        position.0.abs() <= self.half_width && position.1.abs() <= self.half_height
+    }
+
+    pub fn clamp(&self, position: (f32, f32)) -> (f32, f32) {
+        (
+            position.0.clamp(-self.half_width, self.half_width),
+            position.1.clamp(-self.half_height, self.half_height),
+        )
+    }
+}
+
+/// Why a position sent by a client was not taken as is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MoveViolation {
+    /// Moved `distance` units when only `allowed` were possible
+    TooFast {
+        distance: f32,
+        allowed: f32,
+    },
+    OutOfBounds,
+    /// The position was NaN or infinite
+    NotFinite,
+}
+
+impl MoveViolation {
+    /// Whether this is more likely cheating than a client bumping into the edge of the world.
+    pub fn is_suspicious(&self) -> bool {
+        !matches!(self, MoveViolation::OutOfBounds)
+    }
+}
+
+impl std::fmt::Display for MoveViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoveViolation::TooFast { distance, allowed } => {
+                write!(f, "moved {:.1} units, {:.1} allowed", distance, allowed)
+            }
+            MoveViolation::OutOfBounds => write!(f, "left the world"),
+            MoveViolation::NotFinite => write!(f, "sent a position that is not a number"),
+        }
+    }
+}
+
+/// A position the server put a player at instead of the one it sent.
+#[derive(Debug, Clone)]
+pub struct MoveCorrection {
+    pub point: crate::Point,
+    pub violation: MoveViolation,
+    /// Suspicious moves of this player so far, this one included
+    pub suspicious: u32,
+}
+
+/// Distance a player may still cover. It refills at the speed limit and holds at most
+/// `MOVE_SLACK` seconds worth of movement.
+#[derive(Debug, Clone, Copy)]
+pub struct MoveBudget {
+    distance: f32,
+    last: std::time::Instant,
+}
+
+impl MoveBudget {
+    pub fn new(max_speed: f32, now: std::time::Instant) -> Self {
+        Self {
+            distance: max_speed * MOVE_SLACK,
+            last: now,
+        }
+    }
+
+    /// Checks a move from `from` to `to`. Returns where the player actually ends up, which is
+    /// cut short along the same line if the move was too long or went out of bounds.
+    pub fn check(
+        &mut self,
+        from: (f32, f32),
+        to: (f32, f32),
+        max_speed: f32,
+        bounds: &WorldBounds,
+        now: std::time::Instant,
+    ) -> Result<(f32, f32), ((f32, f32), MoveViolation)> {
+        let refill = now.duration_since(self.last).as_secs_f32() * max_speed * SPEED_TOLERANCE;
+        self.distance = (self.distance + refill).min(max_speed * MOVE_SLACK);
+        self.last = now;
+        if !to.0.is_finite() || !to.1.is_finite() {
+            return Err((from, MoveViolation::NotFinite));
+        }
+
+        let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+        let distance = (dx * dx + dy * dy).sqrt();
+        if distance > self.distance {
+            let allowed = self.distance;
+            let scale = allowed / distance;
+            let reached = bounds.clamp((from.0 + dx * scale, from.1 + dy * scale));
+            self.distance = 0.;
+            return Err((reached, MoveViolation::TooFast { distance, allowed }));
+        }
+        self.distance -= distance;
+        if !bounds.contains(to) {
+            return Err((bounds.clamp(to), MoveViolation::OutOfBounds));
+        }
+
+        Ok(to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_moves_at_the_given_speed() {
+        assert_eq!(step((1., 2.), input::RIGHT, 300., 0.5), (151., 2.));
+        assert_eq!(step((0., 0.), input::DOWN | input::UP, 300., 1.), (0., 0.));
+        let (x, y) = step((0., 0.), input::UP | input::LEFT, 100., 1.);
+        assert!(((x * x + y * y).sqrt() - 100.).abs() < 0.001);
+        assert!(x < 0. && y > 0.);
+    }
+
+    fn bounds() -> WorldBounds {
+        WorldBounds::new(200., 200.)
+    }
+
+    #[test]
+    fn budget_allows_the_slack_at_once() {
+        let now = std::time::Instant::now();
+        let mut budget = MoveBudget::new(100., now);
+        assert_eq!(
+            budget.check((0., 0.), (30., 40.), 100., &bounds(), now),
+            Ok((30., 40.))
+        );
+        // 50 units of slack, 50 of them used up
+        assert_eq!(
+            budget.check((30., 40.), (30., 41.), 100., &bounds(), now),
+            Err((
+                (30., 40.),
+                MoveViolation::TooFast {
+                    distance: 1.,
+                    allowed: 0.
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn too_long_move_is_cut_short_along_its_line() {
+        let now = std::time::Instant::now();
+        let mut budget = MoveBudget::new(100., now);
+        assert_eq!(
+            budget.check((0., 0.), (-80., 0.), 100., &bounds(), now),
+            Err((
+                (-50., 0.),
+                MoveViolation::TooFast {
+                    distance: 80.,
+                    allowed: 50.
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn budget_refills_at_the_speed_limit_up_to_the_slack() {
+        let now = std::time::Instant::now();
+        let mut budget = MoveBudget::new(100., now);
+        budget
+            .check((0., 0.), (50., 0.), 100., &bounds(), now)
+            .unwrap();
+
+        // a tenth of a second at 100 units per second, plus the tolerance
+        let later = now + std::time::Duration::from_millis(100);
+        match budget.check((50., 0.), (50., 20.), 100., &bounds(), later) {
+            Err((reached, MoveViolation::TooFast { allowed, .. })) => {
+                assert!((allowed - 100. * 0.1 * SPEED_TOLERANCE).abs() < 0.01);
+                assert!((reached.1 - allowed).abs() < 0.01);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // however long the player stood still, it gets no more than the slack
+        let much_later = later + std::time::Duration::from_secs(60);
+        assert!(budget
+            .check((50., 0.), (0., 0.), 100., &bounds(), much_later)
+            .is_ok());
+        assert!(budget
+            .check((0., 0.), (0., 1.), 100., &bounds(), much_later)
+            .is_err());
+    }
+
+    #[test]
+    fn moves_are_kept_inside_the_world() {
+        let now = std::time::Instant::now();
+        let mut budget = MoveBudget::new(100., now);
+        assert_eq!(
+            budget.check((90., 0.), (110., -20.), 100., &bounds(), now),
+            Err(((100., -20.), MoveViolation::OutOfBounds))
+        );
+
+        // cutting a move short does not put the player outside either
+        let mut budget = MoveBudget::new(100., now);
+        match budget.check((95., 95.), (195., 95.), 100., &bounds(), now) {
+            Err((reached, MoveViolation::TooFast { .. })) => assert_eq!(reached, (100., 95.)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn positions_that_are_not_numbers_stay_put() {
+        let now = std::time::Instant::now();
+        let mut budget = MoveBudget::new(100., now);
+        assert_eq!(
+            budget.check((1., 2.), (f32::NAN, 0.), 100., &bounds(), now),
+            Err(((1., 2.), MoveViolation::NotFinite))
+        );
+        assert_eq!(
+            budget.check((1., 2.), (0., f32::INFINITY), 100., &bounds(), now),
+            Err(((1., 2.), MoveViolation::NotFinite))
+        );
+        // and used up nothing
+        assert!(budget
+            .check((1., 2.), (1., 52.), 100., &bounds(), now)
+            .is_ok());
+    }
+}
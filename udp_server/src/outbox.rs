@@ -0,0 +1,96 @@
+use crate::reliability::{Connections, Datagram};
+use crate::BellMessage;
+
+/// Messages to send at the end of a tick, each with everyone who gets it. Filling it only
+/// needs the game state, encoding and framing happen in `flush` once the state is unlocked.
+#[derive(Default)]
+pub struct Outbox {
+    messages: Vec<(BellMessage, Vec<std::net::SocketAddr>)>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, addr: std::net::SocketAddr, message: BellMessage) {
+        self.messages.push((message, vec![addr]));
+    }
+
+    /// Queues one message for every address in `addrs`, it is encoded once for all of them.
+    pub fn broadcast<'a>(
+        &mut self,
+        message: BellMessage,
+        addrs: impl IntoIterator<Item = &'a std::net::SocketAddr>,
+    ) {
+        let addrs = addrs
+            .into_iter()
+            .copied()
+            .collect::<Vec<std::net::SocketAddr>>();
+        if !addrs.is_empty() {
+            self.messages.push((message, addrs));
+        }
+    }
+
+    pub fn extend(
+        &mut self,
+        messages: impl IntoIterator<Item = (std::net::SocketAddr, BellMessage)>,
+    ) {
+        for (addr, message) in messages {
+            self.push(addr, message);
+        }
+    }
+
+    /// Number of messages, not counting how many peers each goes to.
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Encodes every message once and frames it for each of its recipients. The datagrams
+    /// come out in the order the messages were queued, messages that cannot be encoded are
+    /// left out.
+    pub fn flush(
+        self,
+        connections: &mut Connections,
+        now: std::time::Instant,
+    ) -> Vec<(std::net::SocketAddr, Datagram)> {
+        let wire_format = connections.wire_format();
+        let mut datagrams = Vec::with_capacity(self.messages.len());
+        for (message, addrs) in self.messages {
+            let payload: std::sync::Arc<[u8]> = match crate::encode_with(&message, wire_format) {
+                Ok(payload) => payload.into(),
+                Err(e) => {
+                    log::warn!("Dropping message to {} players: {}", addrs.len(), e);
+                    continue;
+                }
+            };
+            let delivery = message.delivery();
+            for addr in addrs {
+                datagrams.extend(
+                    connections
+                        .send_encoded(addr, &payload, delivery, now)
+                        .into_iter()
+                        .map(|data| (addr, data)),
+                );
+            }
+        }
+
+        datagrams
+    }
+}
+
+#[cfg(test)]
+impl Outbox {
+    /// The messages queued for `addr`, in order.
+    pub fn messages_to(&self, addr: std::net::SocketAddr) -> Vec<&BellMessage> {
+        self.messages
+            .iter()
+            .filter(|(_, addrs)| addrs.contains(&addr))
+            .map(|(message, _)| message)
+            .collect()
+    }
+}
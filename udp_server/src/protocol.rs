@@ -0,0 +1,39 @@
+/// Version of the protocol spoken by this build. It is written into every binary header and
+/// sent along with the registration.
+pub const PROTOCOL_VERSION: u8 = 12;
+/// Oldest protocol version the server still accepts registrations from.
+pub const MIN_SUPPORTED_VERSION: u8 = 12;
+
+/// How often clients send a `HeartbeatMessage` when they have nothing else to say.
+pub const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+/// How long the server waits without hearing from a player before evicting it.
+pub const DEFAULT_PLAYER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Capability bits exchanged during registration. The client sends what it supports and the
+/// server replies with the subset it agrees to use, or refuses the client if it lacks one the
+/// server requires.
+pub mod capabilities {
+    pub const BINARY_CODEC: u32 = 1 << 0;
+    pub const JSON_CODEC: u32 = 1 << 1;
+    /// The client sends `InputMessage`s and lets the server move its player.
+    pub const INPUT_MOVEMENT: u32 = 1 << 2;
+
+    /// Everything this build knows how to do.
+    pub const ALL: u32 = BINARY_CODEC | JSON_CODEC | INPUT_MOVEMENT;
+
+    pub fn names(capabilities: u32) -> Vec<&'static str> {
+        [
+            (BINARY_CODEC, "binary_codec"),
+            (JSON_CODEC, "json_codec"),
+            (INPUT_MOVEMENT, "input_movement"),
+        ]
+        .into_iter()
+        .filter(|(bit, _)| capabilities & bit != 0)
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+pub fn is_supported_version(version: u8) -> bool {
+    (MIN_SUPPORTED_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
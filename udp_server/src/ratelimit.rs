@@ -0,0 +1,386 @@
+use serde::{Deserialize, Serialize};
+
+/// How long strikes are remembered. An address that collects `ban_strikes` drops within this
+/// window is banned.
+pub const STRIKE_WINDOW: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Limits applied to incoming traffic. Rates are per second, bursts are how much can be used
+/// at once after a quiet period.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// Datagrams per second from one address, before they are even decoded
+    pub packet_rate: f32,
+    pub packet_burst: f32,
+    /// Messages per second naming one player, checked once the session was verified
+    pub player_message_rate: f32,
+    pub player_message_burst: f32,
+    /// Registrations per second from one address, challenges included
+    pub registration_rate: f32,
+    pub registration_burst: f32,
+    /// Dropped packets within `STRIKE_WINDOW` that get an address banned, 0 never bans
+    pub ban_strikes: u32,
+    pub ban_secs: u64,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            packet_rate: 200.,
+            packet_burst: 200.,
+            player_message_rate: 150.,
+            player_message_burst: 150.,
+            registration_rate: 2.,
+            registration_burst: 5.,
+            ban_strikes: 100,
+            ban_secs: 60,
+        }
+    }
+}
+
+/// Everything the rate limiters and a full command channel turned away.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DropStats {
+    pub packets_limited: u64,
+    pub registrations_limited: u64,
+    pub player_messages_limited: u64,
+    /// Datagrams from addresses that were banned at the time
+    pub banned_packets: u64,
+    pub bans: u64,
+    /// Messages dropped because the state task was too far behind to take them
+    pub queue_full: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Over the limit, the packet is dropped
+    Limited,
+    /// The address is banned, the packet is dropped without counting as a strike
+    Banned,
+}
+
+struct TokenBucket {
+    tokens: f32,
+    last: std::time::Instant,
+}
+
+impl TokenBucket {
+    fn full(burst: f32, now: std::time::Instant) -> Self {
+        Self {
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    fn take(&mut self, rate: f32, burst: f32, now: std::time::Instant) -> bool {
+        let elapsed = now.duration_since(self.last).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last = now;
+        if self.tokens < 1. {
+            return false;
+        }
+        self.tokens -= 1.;
+
+        true
+    }
+}
+
+struct AddrState {
+    packets: TokenBucket,
+    registrations: TokenBucket,
+    strikes: u32,
+    first_strike: std::time::Instant,
+    banned_until: Option<std::time::Instant>,
+    last_seen: std::time::Instant,
+}
+
+/// Token buckets per source address, with temporary bans for addresses that keep going over
+/// the limits. Owned by the task that reads the socket.
+pub struct RateLimiter {
+    limits: RateLimits,
+    addrs: std::collections::HashMap<std::net::SocketAddr, AddrState>,
+    stats: DropStats,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            addrs: std::collections::HashMap::new(),
+            stats: DropStats::default(),
+        }
+    }
+
+    fn addr_state(
+        &mut self,
+        addr: std::net::SocketAddr,
+        now: std::time::Instant,
+    ) -> &mut AddrState {
+        let limits = self.limits;
+        self.addrs.entry(addr).or_insert_with(|| AddrState {
+            packets: TokenBucket::full(limits.packet_burst, now),
+            registrations: TokenBucket::full(limits.registration_burst, now),
+            strikes: 0,
+            first_strike: now,
+            banned_until: None,
+            last_seen: now,
+        })
+    }
+
+    /// Checks a datagram as it comes off the socket.
+    pub fn check_packet(&mut self, addr: std::net::SocketAddr, now: std::time::Instant) -> Verdict {
+        let limits = self.limits;
+        let state = self.addr_state(addr, now);
+        state.last_seen = now;
+        if state.banned_until.is_some_and(|until| now < until) {
+            self.stats.banned_packets += 1;
+            return Verdict::Banned;
+        }
+        state.banned_until = None;
+
+        if state
+            .packets
+            .take(limits.packet_rate, limits.packet_burst, now)
+        {
+            return Verdict::Allow;
+        }
+        self.stats.packets_limited += 1;
+        self.strike(addr, now);
+
+        Verdict::Limited
+    }
+
+    /// Checks a registration, they have a bucket of their own per address.
+    pub fn check_registration(
+        &mut self,
+        addr: std::net::SocketAddr,
+        now: std::time::Instant,
+    ) -> bool {
+        let limits = self.limits;
+        let allowed = self.addr_state(addr, now).registrations.take(
+            limits.registration_rate,
+            limits.registration_burst,
+            now,
+        );
+        if !allowed {
+            self.stats.registrations_limited += 1;
+            self.strike(addr, now);
+        }
+
+        allowed
+    }
+
+    /// Counts a message the `PlayerLimiter` dropped as a strike against the address it came
+    /// from.
+    pub fn record_player_limited(&mut self, addr: std::net::SocketAddr, now: std::time::Instant) {
+        self.stats.player_messages_limited += 1;
+        self.strike(addr, now);
+    }
+
+    fn strike(&mut self, addr: std::net::SocketAddr, now: std::time::Instant) {
+        let limits = self.limits;
+        let state = self.addr_state(addr, now);
+        if now.duration_since(state.first_strike) > STRIKE_WINDOW {
+            state.strikes = 0;
+            state.first_strike = now;
+        }
+        state.strikes += 1;
+        if limits.ban_strikes == 0 || state.strikes < limits.ban_strikes {
+            return;
+        }
+
+        state.strikes = 0;
+        state.banned_until = Some(now + std::time::Duration::from_secs(limits.ban_secs));
+        self.stats.bans += 1;
+        log::warn!("Banning {} for {} seconds", addr, limits.ban_secs);
+    }
+
+    /// Counts a message that was dropped because the state task was behind.
+    pub fn record_queue_full(&mut self) {
+        self.stats.queue_full += 1;
+    }
+
+    /// Drops the state of addresses that were quiet for `timeout`. Bans are kept until they
+    /// run out.
+    pub fn evict_idle(&mut self, timeout: std::time::Duration, now: std::time::Instant) {
+        self.addrs.retain(|_, state| {
+            state.banned_until.is_some_and(|until| now < until)
+                || now.duration_since(state.last_seen) <= timeout
+        });
+    }
+
+    pub fn stats(&self) -> DropStats {
+        self.stats
+    }
+}
+
+/// Token buckets per player, owned by the state task since only it knows whether a message
+/// really came from the player it names. What it drops is reported back to the `RateLimiter`.
+pub struct PlayerLimiter {
+    limits: RateLimits,
+    players: std::collections::HashMap<u32, TokenBucket>,
+}
+
+impl PlayerLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            players: std::collections::HashMap::new(),
+        }
+    }
+
+    /// Checks a message naming player `id`, call this only once the session was verified.
+    pub fn check(&mut self, id: u32, now: std::time::Instant) -> bool {
+        let limits = self.limits;
+        self.players
+            .entry(id)
+            .or_insert_with(|| TokenBucket::full(limits.player_message_burst, now))
+            .take(limits.player_message_rate, limits.player_message_burst, now)
+    }
+
+    /// Drops the buckets of players that were quiet for `timeout`.
+    pub fn evict_idle(&mut self, timeout: std::time::Duration, now: std::time::Instant) {
+        self.players
+            .retain(|_, bucket| now.duration_since(bucket.last) <= timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> std::net::SocketAddr {
+        std::net::SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn limits() -> RateLimits {
+        RateLimits {
+            packet_rate: 10.,
+            packet_burst: 5.,
+            ban_strikes: 3,
+            ban_secs: 60,
+            ..RateLimits::default()
+        }
+    }
+
+    #[test]
+    fn burst_is_allowed_at_once() {
+        let mut limiter = RateLimiter::new(limits());
+        let now = std::time::Instant::now();
+        for _ in 0..5 {
+            assert_eq!(limiter.check_packet(addr(1), now), Verdict::Allow);
+        }
+        assert_eq!(limiter.check_packet(addr(1), now), Verdict::Limited);
+        // every address has buckets of its own
+        assert_eq!(limiter.check_packet(addr(2), now), Verdict::Allow);
+        assert_eq!(limiter.stats().packets_limited, 1);
+    }
+
+    #[test]
+    fn bucket_refills_at_the_rate_up_to_the_burst() {
+        let mut bucket = TokenBucket::full(5., std::time::Instant::now());
+        let now = bucket.last;
+        for _ in 0..5 {
+            assert!(bucket.take(10., 5., now));
+        }
+        assert!(!bucket.take(10., 5., now));
+
+        // 10 per second is one every 100ms
+        let later = now + std::time::Duration::from_millis(100);
+        assert!(bucket.take(10., 5., later));
+        assert!(!bucket.take(10., 5., later));
+
+        // a long quiet period does not save up more than the burst
+        let much_later = later + std::time::Duration::from_secs(60);
+        for _ in 0..5 {
+            assert!(bucket.take(10., 5., much_later));
+        }
+        assert!(!bucket.take(10., 5., much_later));
+    }
+
+    #[test]
+    fn strikes_get_an_address_banned_until_the_ban_runs_out() {
+        let mut limiter = RateLimiter::new(limits());
+        let now = std::time::Instant::now();
+        for _ in 0..5 {
+            limiter.check_packet(addr(1), now);
+        }
+        for _ in 0..3 {
+            assert_eq!(limiter.check_packet(addr(1), now), Verdict::Limited);
+        }
+        assert_eq!(limiter.stats().bans, 1);
+
+        // banned packets are counted but are no strikes of their own
+        let refilled = now + std::time::Duration::from_secs(1);
+        assert_eq!(limiter.check_packet(addr(1), refilled), Verdict::Banned);
+        assert_eq!(limiter.stats().banned_packets, 1);
+        assert_eq!(limiter.check_packet(addr(2), refilled), Verdict::Allow);
+
+        // eviction keeps the ban
+        limiter.evict_idle(std::time::Duration::ZERO, refilled);
+        let expired = now + std::time::Duration::from_secs(60);
+        assert_eq!(limiter.check_packet(addr(1), expired), Verdict::Allow);
+        assert_eq!(limiter.stats().bans, 1);
+    }
+
+    #[test]
+    fn strikes_are_forgotten_after_the_window() {
+        let mut limiter = RateLimiter::new(limits());
+        let now = std::time::Instant::now();
+        limiter.record_player_limited(addr(1), now);
+        limiter.record_player_limited(addr(1), now);
+
+        let later = now + STRIKE_WINDOW + std::time::Duration::from_secs(1);
+        limiter.record_player_limited(addr(1), later);
+        limiter.record_player_limited(addr(1), later);
+        assert_eq!(limiter.stats().bans, 0);
+        limiter.record_player_limited(addr(1), later);
+        assert_eq!(limiter.stats().bans, 1);
+        assert_eq!(limiter.stats().player_messages_limited, 5);
+    }
+
+    #[test]
+    fn zero_strikes_never_ban() {
+        let mut limiter = RateLimiter::new(RateLimits {
+            ban_strikes: 0,
+            ..limits()
+        });
+        let now = std::time::Instant::now();
+        for _ in 0..100 {
+            limiter.check_packet(addr(1), now);
+        }
+        assert_eq!(limiter.stats().bans, 0);
+        assert_eq!(limiter.check_packet(addr(1), now), Verdict::Limited);
+    }
+
+    #[test]
+    fn registrations_and_players_have_buckets_of_their_own() {
+        let limits = RateLimits {
+            registration_rate: 1.,
+            registration_burst: 2.,
+            player_message_rate: 1.,
+            player_message_burst: 1.,
+            ..limits()
+        };
+        let mut limiter = RateLimiter::new(limits);
+        let now = std::time::Instant::now();
+        assert!(limiter.check_registration(addr(1), now));
+        assert!(limiter.check_registration(addr(1), now));
+        assert!(!limiter.check_registration(addr(1), now));
+        assert_eq!(limiter.stats().registrations_limited, 1);
+        assert_eq!(limiter.check_packet(addr(1), now), Verdict::Allow);
+
+        let mut players = PlayerLimiter::new(limits);
+        assert!(players.check(1, now));
+        assert!(!players.check(1, now));
+        assert!(players.check(2, now));
+        players.evict_idle(std::time::Duration::from_secs(1), now);
+        assert_eq!(players.players.len(), 2);
+        players.evict_idle(
+            std::time::Duration::from_secs(1),
+            now + std::time::Duration::from_secs(2),
+        );
+        assert!(players.players.is_empty());
+    }
+}
@@ -0,0 +1,939 @@
+use crate::codec::{self, kind, CodecError, HEADER_LEN};
+use crate::fragment::{self, Reassembler};
+use crate::session;
+use crate::{BellMessage, Registration, WireFormat};
+
+/// How long to wait for an ack before the first retransmission.
+pub const INITIAL_RETRANSMIT_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(100);
+/// The retransmission timeout doubles after every attempt up to this value.
+pub const MAX_RETRANSMIT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+/// Retransmissions before a message is given up on.
+pub const MAX_RETRANSMITS: u32 = 10;
+
+/// How far behind the newest sequence number we still remember what was received. Anything
+/// older is treated as a duplicate.
+const RECEIVE_WINDOW: u32 = 1024;
+/// How many out of order messages the ordered channel holds on to while waiting for a gap to
+/// be filled. Messages beyond that are dropped unacked and will be retransmitted.
+const MAX_ORDERED_BACKLOG: usize = 256;
+/// How many epochs of earlier incarnations of a peer are remembered, so that their late
+/// datagrams are ignored instead of being taken for yet another restart.
+const RETIRED_EPOCHS: usize = 4;
+/// header + channel (1) + epoch (4) + sequence (4)
+const ENVELOPE_LEN: usize = HEADER_LEN + 9;
+
+/// How a message has to be delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Sent once, may be lost, duplicated or reordered. Fine for state that is resent anyway.
+    Unreliable,
+    /// Retransmitted until acked and delivered exactly once, in whatever order it arrives.
+    ReliableUnordered,
+    /// Retransmitted until acked and delivered exactly once, in the order it was sent.
+    ReliableOrdered,
+}
+
+impl Delivery {
+    fn channel(self) -> Option<u8> {
+        match self {
+            Delivery::Unreliable => None,
+            Delivery::ReliableUnordered => Some(0),
+            Delivery::ReliableOrdered => Some(1),
+        }
+    }
+
+    fn from_channel(channel: u8) -> Option<Self> {
+        match channel {
+            0 => Some(Delivery::ReliableUnordered),
+            1 => Some(Delivery::ReliableOrdered),
+            _ => None,
+        }
+    }
+}
+
+impl BellMessage {
+    /// The delivery class a message is sent with unless told otherwise. Anything that changes
+    /// who is in the game is ordered so that an insertion can never overtake a removal.
+    pub fn delivery(&self) -> Delivery {
+        match self {
+            BellMessage::PlayerRegistrationMessage(_)
+            | BellMessage::RegistrationReplyMessage(_, _, _, _, _)
+            | BellMessage::PlayerInsertionMessage(_)
+            | BellMessage::PlayerRemovalMessage(_)
+            | BellMessage::EnterViewMessage(_)
+            | BellMessage::LeaveViewMessage(_)
+            | BellMessage::SetViewMessage(_, _, _)
+            | BellMessage::PlayerLeaveMessage(_)
+            | BellMessage::CreateRoomMessage(_, _, _, _)
+            | BellMessage::JoinRoomMessage(_, _, _)
+            | BellMessage::RoomJoinedMessage(_, _)
+            | BellMessage::RoomErrorMessage(_) => Delivery::ReliableOrdered,
+            BellMessage::CollisionMessage(_, _) => Delivery::ReliableUnordered,
+            // the rejection has to stay readable by any client version, so it is never wrapped
+            BellMessage::RegistrationRejectedMessage(_)
+            // goes to addresses that were not verified yet and must stay small
+            | BellMessage::ChallengeMessage(_)
+            | BellMessage::PositionChangeMessage(_)
+            // a lost snapshot is not acked, the next one is relative to an older baseline
+            | BellMessage::SnapshotMessage(_)
+            | BellMessage::SnapshotAckMessage(_, _)
+            | BellMessage::DeferMessage
+            | BellMessage::HeartbeatMessage(_)
+            | BellMessage::ServerFullMessage(_)
+            | BellMessage::MissingCapabilitiesMessage(_)
+            | BellMessage::InputMessage(_, _, _)
+            // a lost listing is simply asked for again
+            | BellMessage::ListRoomsMessage(_)
+            | BellMessage::RoomListMessage(_) => Delivery::Unreliable,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConnectionStats {
+    pub reliable_sent: u64,
+    pub retransmitted: u64,
+    pub acked: u64,
+    /// Messages dropped after `MAX_RETRANSMITS` retransmissions without an ack.
+    pub expired: u64,
+    pub reliable_received: u64,
+    pub duplicates: u64,
+    /// Datagrams that were larger than the MTU and went out as fragments.
+    pub fragmented: u64,
+    pub reassembled: u64,
+    /// Incomplete fragmented datagrams that timed out or went over the reassembly limits.
+    pub reassembly_dropped: u64,
+    /// Received datagrams that were cut off or too large to reassemble.
+    pub oversized: u64,
+    /// Times a peer showed up with a new epoch, that is restarted from the same address.
+    pub peer_restarts: u64,
+}
+
+/// A datagram ready for the socket. Datagrams that go out unchanged to several peers share
+/// one buffer instead of being encoded for each of them.
+#[derive(Debug, Clone)]
+pub enum Datagram {
+    Shared(std::sync::Arc<[u8]>),
+    Owned(Vec<u8>),
+}
+
+impl std::ops::Deref for Datagram {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Datagram::Shared(data) => data,
+            Datagram::Owned(data) => data,
+        }
+    }
+}
+
+/// What came out of one incoming datagram.
+#[derive(Debug, Default)]
+pub struct Received {
+    /// Messages ready for the application, in delivery order.
+    pub messages: Vec<BellMessage>,
+    /// Datagrams (acks) that have to be sent back to the peer right away.
+    pub replies: Vec<Vec<u8>>,
+    /// Session token the datagram was sent with, if any.
+    pub session: Option<u64>,
+    /// A registration sent with a new epoch by a peer we already talk to. Nothing else from
+    /// that epoch is accepted until `Connections::restart` is called for it.
+    pub restart: Option<(u32, Registration)>,
+}
+
+struct Pending {
+    /// The datagram as sent, one entry per fragment.
+    data: Vec<Vec<u8>>,
+    next_send: std::time::Instant,
+    timeout: std::time::Duration,
+    retransmits: u32,
+}
+
+/// Remembers which sequence numbers were already delivered on one channel.
+#[derive(Default)]
+struct ReceiveWindow {
+    highest: Option<u32>,
+    seen: std::collections::BTreeSet<u32>,
+}
+
+impl ReceiveWindow {
+    /// Records a sequence number, returns false if it was seen before.
+    fn insert(&mut self, sequence: u32) -> bool {
+        let highest = self
+            .highest
+            .map_or(sequence, |highest| highest.max(sequence));
+        let floor = highest.saturating_sub(RECEIVE_WINDOW);
+        if sequence < floor || !self.seen.insert(sequence) {
+            return false;
+        }
+
+        self.highest = Some(highest);
+        self.seen = self.seen.split_off(&floor);
+
+        true
+    }
+}
+
+/// Reliability state for talking to a single peer. Sequence numbers are not expected to
+/// wrap within the lifetime of a connection.
+///
+/// Every connection picks a random epoch that goes into its envelopes, and acks echo the
+/// epoch of what they ack. A peer that shows up with a new epoch restarted, so its sequence
+/// numbers start over and what we received from it before is forgotten. The server side also
+/// starts its own sending over, since the new peer knows nothing of what was sent before.
+/// Anyone can put a new epoch on a datagram, so the server side only restarts a peer once it
+/// registered again with a valid cookie, see `Received::restart`.
+///
+/// Fragmentation happens below reliability: a reliable message larger than the MTU is split
+/// after it was wrapped, and a retransmission sends all of its fragments again. Once a session
+/// token is set every datagram is wrapped in a session frame before it is fragmented.
+pub struct Connection {
+    wire_format: WireFormat,
+    mtu: usize,
+    epoch: u32,
+    peer_epoch: Option<u32>,
+    retired_epochs: std::collections::VecDeque<u32>,
+    /// Whether a restarted peer also resets what we send, see `Connections`
+    restart_with_peer: bool,
+    session: Option<u64>,
+    next_message_id: u32,
+    reassembler: Reassembler,
+    next_sequence: [u32; 2],
+    pending: std::collections::BTreeMap<(u8, u32), Pending>,
+    unordered_window: ReceiveWindow,
+    next_ordered: u32,
+    ordered_backlog: std::collections::BTreeMap<u32, BellMessage>,
+    last_received: std::time::Instant,
+    stats: ConnectionStats,
+}
+
+impl Connection {
+    pub fn new(wire_format: WireFormat, mtu: usize) -> Self {
+        Self {
+            wire_format,
+            mtu: mtu.clamp(fragment::MIN_MTU, fragment::MAX_DATAGRAM_LEN),
+            epoch: rand::random(),
+            peer_epoch: None,
+            retired_epochs: std::collections::VecDeque::new(),
+            restart_with_peer: false,
+            session: None,
+            next_message_id: 0,
+            reassembler: Reassembler::default(),
+            next_sequence: [0; 2],
+            pending: std::collections::BTreeMap::new(),
+            unordered_window: ReceiveWindow::default(),
+            next_ordered: 0,
+            ordered_backlog: std::collections::BTreeMap::new(),
+            last_received: std::time::Instant::now(),
+            stats: ConnectionStats::default(),
+        }
+    }
+
+    /// Sends every later datagram, acks included, with this session token.
+    pub fn set_session(&mut self, token: u64) {
+        self.session = Some(token);
+    }
+
+    /// Encodes a message with its default delivery class, see `BellMessage::delivery`.
+    pub fn send(
+        &mut self,
+        message: &BellMessage,
+        now: std::time::Instant,
+    ) -> Result<Vec<Vec<u8>>, CodecError> {
+        self.send_with(message, message.delivery(), now)
+    }
+
+    /// Encodes a message into the datagrams to put on the wire, more than one if it had to be
+    /// fragmented. Reliable messages are kept until acked and show up in `poll` when they are
+    /// due for retransmission. Nothing is sent if the message cannot be encoded.
+    pub fn send_with(
+        &mut self,
+        message: &BellMessage,
+        delivery: Delivery,
+        now: std::time::Instant,
+    ) -> Result<Vec<Vec<u8>>, CodecError> {
+        let payload = codec::encode_with(message, self.wire_format)?;
+        Ok(match delivery.channel() {
+            Some(channel) => self.send_reliable(channel, &payload, now),
+            None => self.frame(payload),
+        })
+    }
+
+    /// Sends a message that was already encoded with this connection's wire format, so that
+    /// a message going to many peers is only encoded once. Unreliable messages that need no
+    /// framing go out as the shared buffer itself.
+    pub fn send_encoded(
+        &mut self,
+        payload: &std::sync::Arc<[u8]>,
+        delivery: Delivery,
+        now: std::time::Instant,
+    ) -> Vec<Datagram> {
+        let datagrams = match delivery.channel() {
+            None if self.session.is_none() && payload.len() <= self.mtu => {
+                return vec![Datagram::Shared(payload.clone())];
+            }
+            None => self.frame(payload.to_vec()),
+            Some(channel) => self.send_reliable(channel, payload, now),
+        };
+
+        datagrams.into_iter().map(Datagram::Owned).collect()
+    }
+
+    fn send_reliable(
+        &mut self,
+        channel: u8,
+        payload: &[u8],
+        now: std::time::Instant,
+    ) -> Vec<Vec<u8>> {
+        let sequence = self.next_sequence[channel as usize];
+        self.next_sequence[channel as usize] += 1;
+        let mut data = Vec::with_capacity(ENVELOPE_LEN + payload.len());
+        write_envelope(&mut data, kind::RELIABLE, channel, self.epoch, sequence);
+        data.extend_from_slice(payload);
+        let data = self.frame(data);
+
+        self.pending.insert(
+            (channel, sequence),
+            Pending {
+                data: data.clone(),
+                next_send: now + INITIAL_RETRANSMIT_TIMEOUT,
+                timeout: INITIAL_RETRANSMIT_TIMEOUT,
+                retransmits: 0,
+            },
+        );
+        self.stats.reliable_sent += 1;
+
+        data
+    }
+
+    fn frame(&mut self, datagram: Vec<u8>) -> Vec<Vec<u8>> {
+        let datagram = match self.session {
+            Some(token) => session::wrap(token, &datagram),
+            None => datagram,
+        };
+        if datagram.len() <= self.mtu {
+            return vec![datagram];
+        }
+
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        self.stats.fragmented += 1;
+
+        fragment::split(datagram, message_id, self.mtu)
+    }
+
+    /// Handles one datagram from the peer. A datagram longer than `MAX_DATAGRAM_LEN`, which
+    /// is what a read that filled a `RECV_BUFFER_LEN` buffer looks like, is reported as
+    /// oversized since it was cut off.
+    pub fn receive(
+        &mut self,
+        data: &[u8],
+        now: std::time::Instant,
+    ) -> Result<Received, CodecError> {
+        self.last_received = now;
+        if data.len() > fragment::MAX_DATAGRAM_LEN {
+            self.stats.oversized += 1;
+            return Err(CodecError::Oversized(data.len()));
+        }
+        if codec::peek_kind(data) != Some(kind::FRAGMENT) {
+            return self.receive_framed(data);
+        }
+
+        let reassembled = self.reassembler.insert(data, now).inspect_err(|e| {
+            if matches!(e, CodecError::Oversized(_)) {
+                self.stats.oversized += 1;
+            }
+        })?;
+        match reassembled {
+            Some(datagram) => self.receive_framed(&datagram),
+            None => Ok(Received::default()),
+        }
+    }
+
+    /// Takes off the session frame, if there is one, and wraps the replies in our own.
+    fn receive_framed(&mut self, data: &[u8]) -> Result<Received, CodecError> {
+        let (token, data) = match codec::peek_kind(data) {
+            Some(kind::SESSION) => {
+                let (token, inner) = session::unwrap(data)?;
+                (Some(token), inner)
+            }
+            _ => (None, data),
+        };
+        // fragments carry session frames, never the other way round, and neither is nested
+        if let Some(framing @ (kind::SESSION | kind::FRAGMENT)) = codec::peek_kind(data) {
+            return Err(CodecError::UnknownKind(framing));
+        }
+
+        let mut received = self.receive_datagram(data)?;
+        received.session = token;
+        if let Some(token) = self.session {
+            for reply in received.replies.iter_mut() {
+                *reply = session::wrap(token, reply);
+            }
+        }
+
+        Ok(received)
+    }
+
+    fn receive_datagram(&mut self, data: &[u8]) -> Result<Received, CodecError> {
+        let mut received = Received::default();
+        match codec::peek_kind(data) {
+            Some(kind::ACK) => {
+                let (channel, epoch, sequence) = read_envelope(data)?;
+                if data.len() != ENVELOPE_LEN {
+                    return Err(CodecError::TrailingBytes(data.len() - ENVELOPE_LEN));
+                }
+                // an ack for what an earlier epoch of ours sent says nothing about now
+                if epoch == self.epoch && self.pending.remove(&(channel, sequence)).is_some() {
+                    self.stats.acked += 1;
+                }
+            }
+            Some(kind::RELIABLE) => {
+                let (channel, epoch, sequence) = read_envelope(data)?;
+                let delivery =
+                    Delivery::from_channel(channel).ok_or(CodecError::UnknownKind(channel))?;
+                let message = codec::decode(&data[ENVELOPE_LEN..])?;
+                if self.retired_epochs.contains(&epoch) {
+                    return Ok(received);
+                }
+                match self.peer_epoch {
+                    Some(peer_epoch) if peer_epoch != epoch && self.restart_with_peer => {
+                        if let BellMessage::PlayerRegistrationMessage(registration) = message {
+                            received.restart = Some((epoch, registration));
+                        }
+                        return Ok(received);
+                    }
+                    Some(peer_epoch) if peer_epoch != epoch => self.restart_peer(),
+                    _ => {}
+                }
+                self.peer_epoch = Some(epoch);
+                self.stats.reliable_received += 1;
+
+                let accepted = match delivery {
+                    Delivery::ReliableOrdered => {
+                        self.receive_ordered(sequence, message, &mut received)
+                    }
+                    _ => {
+                        if self.unordered_window.insert(sequence) {
+                            received.messages.push(message);
+                        } else {
+                            self.stats.duplicates += 1;
+                        }
+                        true
+                    }
+                };
+                if !accepted {
+                    return Ok(received);
+                }
+
+                // acks are sent for duplicates too, the first ack may have been lost
+                let mut ack = Vec::with_capacity(ENVELOPE_LEN);
+                write_envelope(&mut ack, kind::ACK, channel, epoch, sequence);
+                received.replies.push(ack);
+            }
+            _ => received.messages.push(codec::decode(data)?),
+        }
+
+        Ok(received)
+    }
+
+    /// Forgets what the peer's earlier epoch sent us, and on the server side everything we
+    /// sent it, since the restarted peer will never ack it.
+    fn restart_peer(&mut self) {
+        if let Some(peer_epoch) = self.peer_epoch.take() {
+            self.retired_epochs.push_back(peer_epoch);
+            if self.retired_epochs.len() > RETIRED_EPOCHS {
+                self.retired_epochs.pop_front();
+            }
+        }
+        self.unordered_window = ReceiveWindow::default();
+        self.next_ordered = 0;
+        self.ordered_backlog.clear();
+        self.reassembler = Reassembler::default();
+        self.stats.peer_restarts += 1;
+
+        if self.restart_with_peer {
+            self.epoch = rand::random();
+            self.session = None;
+            self.next_sequence = [0; 2];
+            self.pending.clear();
+        }
+    }
+
+    /// Takes the peer for restarted with the given epoch, see `Received::restart`.
+    fn restart(&mut self, epoch: u32) {
+        if self.peer_epoch == Some(epoch) || self.retired_epochs.contains(&epoch) {
+            return;
+        }
+        self.restart_peer();
+        self.peer_epoch = Some(epoch);
+    }
+
+    /// Returns false if the message could not be buffered and must not be acked.
+    fn receive_ordered(
+        &mut self,
+        sequence: u32,
+        message: BellMessage,
+        received: &mut Received,
+    ) -> bool {
+        if sequence < self.next_ordered || self.ordered_backlog.contains_key(&sequence) {
+            self.stats.duplicates += 1;
+            return true;
+        }
+        if sequence > self.next_ordered && self.ordered_backlog.len() >= MAX_ORDERED_BACKLOG {
+            return false;
+        }
+
+        self.ordered_backlog.insert(sequence, message);
+        while let Some(message) = self.ordered_backlog.remove(&self.next_ordered) {
+            received.messages.push(message);
+            self.next_ordered += 1;
+        }
+
+        true
+    }
+
+    /// Datagrams that are due for retransmission. Messages that ran out of retransmissions
+    /// are dropped and counted in `ConnectionStats::expired`.
+    pub fn poll(&mut self, now: std::time::Instant) -> Vec<Vec<u8>> {
+        let mut resend = vec![];
+        let mut expired = vec![];
+        let mut retransmitted = 0;
+        for (key, pending) in self.pending.iter_mut() {
+            if pending.next_send > now {
+                continue;
+            }
+            if pending.retransmits >= MAX_RETRANSMITS {
+                expired.push(*key);
+                continue;
+            }
+
+            pending.retransmits += 1;
+            retransmitted += 1;
+            pending.timeout = (pending.timeout * 2).min(MAX_RETRANSMIT_TIMEOUT);
+            pending.next_send = now + pending.timeout;
+            resend.extend(pending.data.iter().cloned());
+        }
+
+        for key in expired {
+            self.pending.remove(&key);
+            self.stats.expired += 1;
+        }
+        self.stats.retransmitted += retransmitted;
+        self.reassembler.expire(now);
+
+        resend
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub fn last_received(&self) -> std::time::Instant {
+        self.last_received
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        let reassembly = self.reassembler.stats();
+        ConnectionStats {
+            reassembled: reassembly.reassembled,
+            reassembly_dropped: reassembly.dropped,
+            ..self.stats
+        }
+    }
+}
+
+fn write_envelope(buf: &mut Vec<u8>, kind: u8, channel: u8, epoch: u32, sequence: u32) {
+    codec::write_header(buf, kind);
+    buf.push(channel);
+    buf.extend_from_slice(&epoch.to_le_bytes());
+    buf.extend_from_slice(&sequence.to_le_bytes());
+}
+
+/// Channel, epoch and sequence number of a reliable envelope or an ack.
+fn read_envelope(data: &[u8]) -> Result<(u8, u32, u32), CodecError> {
+    if data.len() < ENVELOPE_LEN {
+        return Err(CodecError::Truncated);
+    }
+    let mut epoch = [0; 4];
+    epoch.copy_from_slice(&data[HEADER_LEN + 1..HEADER_LEN + 5]);
+    let mut sequence = [0; 4];
+    sequence.copy_from_slice(&data[HEADER_LEN + 5..ENVELOPE_LEN]);
+
+    Ok((
+        data[HEADER_LEN],
+        u32::from_le_bytes(epoch),
+        u32::from_le_bytes(sequence),
+    ))
+}
+
+/// One `Connection` per peer address, for the server side. A client that restarts from the
+/// same address, e.g. one with a fixed bind address, starts over with a clean connection in
+/// both directions.
+pub struct Connections {
+    wire_format: WireFormat,
+    mtu: usize,
+    connections: std::collections::HashMap<std::net::SocketAddr, Connection>,
+}
+
+impl Connections {
+    pub fn new(wire_format: WireFormat, mtu: usize) -> Self {
+        Self {
+            wire_format,
+            mtu,
+            connections: std::collections::HashMap::new(),
+        }
+    }
+
+    fn get_or_insert(&mut self, addr: std::net::SocketAddr) -> &mut Connection {
+        let (wire_format, mtu) = (self.wire_format, self.mtu);
+        self.connections.entry(addr).or_insert_with(|| Connection {
+            restart_with_peer: true,
+            ..Connection::new(wire_format, mtu)
+        })
+    }
+
+    pub fn send(
+        &mut self,
+        addr: std::net::SocketAddr,
+        message: &BellMessage,
+        now: std::time::Instant,
+    ) -> Result<Vec<Vec<u8>>, CodecError> {
+        self.get_or_insert(addr).send(message, now)
+    }
+
+    /// See `Connection::send_encoded`.
+    pub fn send_encoded(
+        &mut self,
+        addr: std::net::SocketAddr,
+        payload: &std::sync::Arc<[u8]>,
+        delivery: Delivery,
+        now: std::time::Instant,
+    ) -> Vec<Datagram> {
+        self.get_or_insert(addr)
+            .send_encoded(payload, delivery, now)
+    }
+
+    /// Format every message sent through these connections is encoded with.
+    pub fn wire_format(&self) -> WireFormat {
+        self.wire_format
+    }
+
+    pub fn receive(
+        &mut self,
+        addr: std::net::SocketAddr,
+        data: &[u8],
+        now: std::time::Instant,
+    ) -> Result<Received, CodecError> {
+        self.get_or_insert(addr).receive(data, now)
+    }
+
+    /// Accepts the new epoch of a peer whose registration came with `Received::restart`. Only
+    /// call this once the registration passed the cookie check.
+    pub fn restart(&mut self, addr: std::net::SocketAddr, epoch: u32) {
+        if let Some(connection) = self.connections.get_mut(&addr) {
+            connection.restart(epoch);
+        }
+    }
+
+    /// Retransmissions that are due, for every peer.
+    pub fn poll(&mut self, now: std::time::Instant) -> Vec<(std::net::SocketAddr, Vec<u8>)> {
+        self.connections
+            .iter_mut()
+            .flat_map(|(addr, connection)| {
+                connection
+                    .poll(now)
+                    .into_iter()
+                    .map(move |data| (*addr, data))
+            })
+            .collect()
+    }
+
+    /// Drops the state of peers we have not heard from within `timeout` and that have nothing
+    /// left to retransmit.
+    pub fn evict_idle(&mut self, timeout: std::time::Duration, now: std::time::Instant) {
+        self.connections.retain(|_, connection| {
+            connection.has_pending() || now.duration_since(connection.last_received()) <= timeout
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// Stats summed over every peer.
+    pub fn stats(&self) -> ConnectionStats {
+        self.connections.values().map(Connection::stats).fold(
+            ConnectionStats::default(),
+            |total, stats| ConnectionStats {
+                reliable_sent: total.reliable_sent + stats.reliable_sent,
+                retransmitted: total.retransmitted + stats.retransmitted,
+                acked: total.acked + stats.acked,
+                expired: total.expired + stats.expired,
+                reliable_received: total.reliable_received + stats.reliable_received,
+                duplicates: total.duplicates + stats.duplicates,
+                fragmented: total.fragmented + stats.fragmented,
+                reassembled: total.reassembled + stats.reassembled,
+                reassembly_dropped: total.reassembly_dropped + stats.reassembly_dropped,
+                oversized: total.oversized + stats.oversized,
+                peer_restarts: total.peer_restarts + stats.peer_restarts,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Point;
+
+    fn pair() -> (Connection, Connection) {
+        (
+            Connection::new(WireFormat::Binary, fragment::DEFAULT_MTU),
+            Connection::new(WireFormat::Binary, fragment::DEFAULT_MTU),
+        )
+    }
+
+    fn removed(received: &Received) -> Vec<u32> {
+        received
+            .messages
+            .iter()
+            .map(|message| match message {
+                BellMessage::PlayerRemovalMessage(id) => *id,
+                other => panic!("unexpected message {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unacked_message_is_resent_after_timeout() {
+        let (mut sender, mut receiver) = pair();
+        let now = std::time::Instant::now();
+        let sent = sender
+            .send(&BellMessage::PlayerRemovalMessage(7), now)
+            .unwrap();
+        assert_eq!(sent.len(), 1);
+
+        assert!(sender.poll(now).is_empty());
+        let resent = sender.poll(now + INITIAL_RETRANSMIT_TIMEOUT);
+        assert_eq!(resent, sent);
+        // the timeout doubled, so nothing is due right after the first retransmission
+        assert!(sender
+            .poll(now + INITIAL_RETRANSMIT_TIMEOUT + INITIAL_RETRANSMIT_TIMEOUT)
+            .is_empty());
+
+        let received = receiver.receive(&resent[0], now).unwrap();
+        assert_eq!(removed(&received), vec![7]);
+        assert_eq!(received.replies.len(), 1);
+
+        let acked = sender.receive(&received.replies[0], now).unwrap();
+        assert!(acked.messages.is_empty());
+        assert!(!sender.has_pending());
+        assert!(sender.poll(now + MAX_RETRANSMIT_TIMEOUT * 4).is_empty());
+        assert_eq!(sender.stats().acked, 1);
+        assert_eq!(sender.stats().retransmitted, 1);
+    }
+
+    #[test]
+    fn message_expires_after_max_retransmits() {
+        let (mut sender, _) = pair();
+        let mut now = std::time::Instant::now();
+        sender
+            .send(&BellMessage::PlayerRemovalMessage(1), now)
+            .unwrap();
+        for _ in 0..=MAX_RETRANSMITS {
+            now += MAX_RETRANSMIT_TIMEOUT;
+            sender.poll(now);
+        }
+
+        assert!(!sender.has_pending());
+        assert_eq!(sender.stats().retransmitted, MAX_RETRANSMITS as u64);
+        assert_eq!(sender.stats().expired, 1);
+    }
+
+    #[test]
+    fn ack_from_earlier_epoch_is_ignored() {
+        let (mut sender, mut receiver) = pair();
+        let now = std::time::Instant::now();
+        let sent = sender
+            .send(&BellMessage::PlayerRemovalMessage(1), now)
+            .unwrap();
+        let ack = receiver.receive(&sent[0], now).unwrap().replies.remove(0);
+
+        sender.epoch = sender.epoch.wrapping_add(1);
+        sender.receive(&ack, now).unwrap();
+        assert!(sender.has_pending());
+    }
+
+    #[test]
+    fn ordered_messages_wait_for_gaps() {
+        let (mut sender, mut receiver) = pair();
+        let now = std::time::Instant::now();
+        let sent: Vec<Vec<u8>> = (0..4)
+            .flat_map(|id| {
+                sender
+                    .send(&BellMessage::PlayerRemovalMessage(id), now)
+                    .unwrap()
+            })
+            .collect();
+
+        let first = receiver.receive(&sent[0], now).unwrap();
+        assert_eq!(removed(&first), vec![0]);
+        for datagram in [&sent[3], &sent[2]] {
+            let held = receiver.receive(datagram, now).unwrap();
+            assert!(held.messages.is_empty());
+            // held messages are acked so that they are not sent again
+            assert_eq!(held.replies.len(), 1);
+        }
+
+        let filled = receiver.receive(&sent[1], now).unwrap();
+        assert_eq!(removed(&filled), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn unordered_messages_are_delivered_as_they_arrive() {
+        let (mut sender, mut receiver) = pair();
+        let now = std::time::Instant::now();
+        let sent: Vec<Vec<u8>> = (0..3)
+            .flat_map(|id| {
+                sender
+                    .send_with(
+                        &BellMessage::PlayerRemovalMessage(id),
+                        Delivery::ReliableUnordered,
+                        now,
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        for index in [2, 0, 1] {
+            let received = receiver.receive(&sent[index], now).unwrap();
+            assert_eq!(removed(&received), vec![index as u32]);
+        }
+    }
+
+    #[test]
+    fn duplicates_are_acked_but_not_delivered() {
+        for delivery in [Delivery::ReliableOrdered, Delivery::ReliableUnordered] {
+            let (mut sender, mut receiver) = pair();
+            let now = std::time::Instant::now();
+            let sent = sender
+                .send_with(&BellMessage::PlayerRemovalMessage(5), delivery, now)
+                .unwrap();
+
+            assert_eq!(removed(&receiver.receive(&sent[0], now).unwrap()), vec![5]);
+            let again = receiver.receive(&sent[0], now).unwrap();
+            assert!(again.messages.is_empty());
+            assert_eq!(again.replies.len(), 1);
+            assert_eq!(receiver.stats().duplicates, 1);
+        }
+    }
+
+    #[test]
+    fn large_message_is_fragmented_and_resent_whole() {
+        let mut sender = Connection::new(WireFormat::Binary, fragment::MIN_MTU);
+        let mut receiver = Connection::new(WireFormat::Binary, fragment::MIN_MTU);
+        let now = std::time::Instant::now();
+        let points = (0..20)
+            .map(|id| Point {
+                x: id as f32,
+                y: -(id as f32),
+                id,
+            })
+            .collect();
+        let sent = sender
+            .send(&BellMessage::RoomJoinedMessage("lobby".into(), points), now)
+            .unwrap();
+        assert!(sent.len() > 1);
+        assert!(sent
+            .iter()
+            .all(|datagram| datagram.len() <= fragment::MIN_MTU));
+        assert_eq!(sender.poll(now + INITIAL_RETRANSMIT_TIMEOUT), sent);
+
+        let mut received = vec![];
+        for datagram in sent.iter().rev() {
+            received.extend(receiver.receive(datagram, now).unwrap().messages);
+        }
+        match received.as_slice() {
+            [BellMessage::RoomJoinedMessage(name, points)] => {
+                assert_eq!(name, "lobby");
+                assert_eq!(points.len(), 20);
+                assert_eq!(points[19].id, 19);
+            }
+            other => panic!("unexpected messages {:?}", other),
+        }
+        assert_eq!(receiver.stats().reassembled, 1);
+    }
+
+    #[test]
+    fn spoofed_epoch_cannot_take_over_an_established_peer() {
+        let mut server = Connections::new(WireFormat::Binary, fragment::DEFAULT_MTU);
+        let mut client = Connection::new(WireFormat::Binary, fragment::DEFAULT_MTU);
+        let mut spoofer = Connection::new(WireFormat::Binary, fragment::DEFAULT_MTU);
+        let addr = "127.0.0.1:4000".parse().unwrap();
+        let now = std::time::Instant::now();
+        let deliver = |server: &mut Connections, datagrams: Vec<Vec<u8>>| {
+            datagrams
+                .iter()
+                .map(|datagram| server.receive(addr, datagram, now).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let sent = client
+            .send(&BellMessage::PlayerRemovalMessage(1), now)
+            .unwrap();
+        assert_eq!(removed(&deliver(&mut server, sent)[0]), vec![1]);
+        server
+            .send(addr, &BellMessage::PlayerRemovalMessage(9), now)
+            .unwrap();
+
+        let registration = BellMessage::PlayerRegistrationMessage(Registration {
+            point: Point::default(),
+            protocol_version: crate::protocol::PROTOCOL_VERSION,
+            capabilities: 0,
+            cookie: 0,
+        });
+        let mut sent = spoofer
+            .send(&BellMessage::PlayerRemovalMessage(2), now)
+            .unwrap();
+        sent.extend(spoofer.send(&registration, now).unwrap());
+        let received = deliver(&mut server, sent);
+        assert!(received
+            .iter()
+            .all(|received| received.messages.is_empty() && received.replies.is_empty()));
+        assert!(received[0].restart.is_none());
+        assert_eq!(
+            received[1].restart.as_ref().map(|(epoch, _)| *epoch),
+            Some(spoofer.epoch)
+        );
+
+        // nothing changed for the real peer, in either direction
+        assert_eq!(server.stats().peer_restarts, 0);
+        assert_eq!(server.poll(now + INITIAL_RETRANSMIT_TIMEOUT).len(), 1);
+        let sent = client
+            .send(&BellMessage::PlayerRemovalMessage(3), now)
+            .unwrap();
+        assert_eq!(removed(&deliver(&mut server, sent)[0]), vec![3]);
+
+        // once the registration checked out the new epoch takes over
+        server.restart(addr, spoofer.epoch);
+        assert_eq!(server.stats().peer_restarts, 1);
+        assert!(server.poll(now + MAX_RETRANSMIT_TIMEOUT).is_empty());
+        let resent = spoofer.poll(now + INITIAL_RETRANSMIT_TIMEOUT);
+        let received = deliver(&mut server, resent);
+        assert_eq!(removed(&received[0]), vec![2]);
+        assert!(matches!(
+            received[1].messages.as_slice(),
+            [BellMessage::PlayerRegistrationMessage(_)]
+        ));
+        let sent = client
+            .send(&BellMessage::PlayerRemovalMessage(4), now)
+            .unwrap();
+        assert!(deliver(&mut server, sent)[0].messages.is_empty());
+    }
+}
@@ -0,0 +1,538 @@
+use crate::interest::View;
+use crate::movement::WorldBounds;
+use crate::session::{SessionStats, Sessions};
+use crate::snapshot::{SnapshotStats, Snapshots};
+use crate::{BellMessage, GameState};
+use serde::{Deserialize, Serialize};
+
+/// Room every player is put in when it registers. It always exists and is the only one with
+/// a waitlist.
+pub const DEFAULT_ROOM: &str = "main";
+/// Room limit used when the server is not told otherwise.
+pub const DEFAULT_MAX_ROOMS: usize = 32;
+/// Longest room name, in bytes.
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+/// Longest room password, in bytes.
+pub const MAX_PASSWORD_LEN: usize = 64;
+
+/// What `RoomListMessage` tells about a room.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct RoomInfo {
+    pub name: String,
+    pub players: u16,
+    pub max_players: u16,
+    pub has_password: bool,
+}
+
+/// Why a room could not be created or joined.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomError {
+    NotFound,
+    Full,
+    WrongPassword,
+    AlreadyExists,
+    TooManyRooms,
+    InvalidName,
+}
+
+impl RoomError {
+    pub fn code(self) -> u8 {
+        match self {
+            RoomError::NotFound => 0,
+            RoomError::Full => 1,
+            RoomError::WrongPassword => 2,
+            RoomError::AlreadyExists => 3,
+            RoomError::TooManyRooms => 4,
+            RoomError::InvalidName => 5,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(RoomError::NotFound),
+            1 => Some(RoomError::Full),
+            2 => Some(RoomError::WrongPassword),
+            3 => Some(RoomError::AlreadyExists),
+            4 => Some(RoomError::TooManyRooms),
+            5 => Some(RoomError::InvalidName),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for RoomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            RoomError::NotFound => "there is no such room",
+            RoomError::Full => "the room is full",
+            RoomError::WrongPassword => "wrong password",
+            RoomError::AlreadyExists => "a room with that name already exists",
+            RoomError::TooManyRooms => "the server has no room for more rooms",
+            RoomError::InvalidName => "invalid room name or password",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+struct Room {
+    state: GameState,
+    password: Option<String>,
+}
+
+/// Every room on the server. Player ids are unique across rooms, so a player keeps its id
+/// when it moves.
+pub struct Lobby {
+    rooms: std::collections::BTreeMap<String, Room>,
+    members: std::collections::HashMap<u32, String>,
+    sessions: Sessions,
+    snapshots: Snapshots,
+    next_available_id: u32,
+    max_players: usize,
+    max_rooms: usize,
+    hitbox_size: f32,
+    max_speed: f32,
+    bounds: WorldBounds,
+    view: View,
+}
+
+impl Lobby {
+    /// `max_players` is the player limit of the whole lobby, of the default room and the
+    /// largest limit other rooms can ask for.
+    pub fn new(max_players: usize, max_rooms: usize) -> Self {
+        let mut lobby = Self {
+            rooms: std::collections::BTreeMap::new(),
+            members: std::collections::HashMap::new(),
+            sessions: Sessions::default(),
+            snapshots: Snapshots::default(),
+            next_available_id: 0,
+            max_players,
+            max_rooms: max_rooms.max(1),
+            hitbox_size: crate::DEFAULT_HITBOX_SIZE,
+            max_speed: crate::movement::PLAYER_SPEED,
+            bounds: WorldBounds::default(),
+            view: View::default(),
+        };
+        lobby.insert_room(DEFAULT_ROOM.to_string(), max_players, None);
+
+        lobby
+    }
+
+    pub fn set_hitbox_size(&mut self, hitbox_size: f32) {
+        self.hitbox_size = hitbox_size;
+        for room in self.rooms.values_mut() {
+            room.state.set_hitbox_size(hitbox_size);
+        }
+    }
+
+    /// See `GameState::set_movement_limits`, applies to every room.
+    pub fn set_movement_limits(&mut self, max_speed: f32, bounds: WorldBounds) {
+        self.max_speed = max_speed;
+        self.bounds = bounds;
+        for room in self.rooms.values_mut() {
+            room.state.set_movement_limits(max_speed, bounds);
+        }
+    }
+
+    /// See `GameState::set_default_view`, applies to every room.
+    pub fn set_default_view(&mut self, view: View) {
+        self.view = view;
+        for room in self.rooms.values_mut() {
+            room.state.set_default_view(view);
+        }
+    }
+
+    fn insert_room(&mut self, name: String, max_players: usize, password: Option<String>) {
+        let mut state = GameState::new_with_limits(max_players);
+        state.set_hitbox_size(self.hitbox_size);
+        state.set_movement_limits(self.max_speed, self.bounds);
+        state.set_default_view(self.view);
+        self.rooms.insert(name, Room { state, password });
+    }
+
+    pub fn allocate_id(&mut self) -> u32 {
+        let id = self.next_available_id;
+        self.next_available_id += 1;
+
+        id
+    }
+
+    pub fn room(&self, name: &str) -> Option<&GameState> {
+        self.rooms.get(name).map(|room| &room.state)
+    }
+
+    pub fn room_mut(&mut self, name: &str) -> Option<&mut GameState> {
+        self.rooms.get_mut(name).map(|room| &mut room.state)
+    }
+
+    pub fn default_room(&self) -> &GameState {
+        &self.rooms[DEFAULT_ROOM].state
+    }
+
+    pub fn default_room_mut(&mut self) -> &mut GameState {
+        &mut self.rooms.get_mut(DEFAULT_ROOM).unwrap().state
+    }
+
+    /// Every room by name, for the tick to run them one after the other.
+    pub fn rooms_mut(&mut self) -> impl Iterator<Item = (&String, &mut GameState)> {
+        self.rooms
+            .iter_mut()
+            .map(|(name, room)| (name, &mut room.state))
+    }
+
+    /// Name of the room a player is in.
+    pub fn room_of(&self, id: u32) -> Option<&str> {
+        self.members.get(&id).map(String::as_str)
+    }
+
+    /// The game state of the room a player is in.
+    pub fn state_of(&self, id: u32) -> Option<&GameState> {
+        self.room(self.members.get(&id)?)
+    }
+
+    pub fn state_of_mut(&mut self, id: u32) -> Option<&mut GameState> {
+        let name = self.members.get(&id)?;
+        self.rooms.get_mut(name).map(|room| &mut room.state)
+    }
+
+    /// Id of the player registered from `addr`, in whatever room it is.
+    pub fn get_id_for_addr(&self, addr: &std::net::SocketAddr) -> Option<u32> {
+        self.rooms
+            .values()
+            .find_map(|room| room.state.get_id_for_addr(addr))
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Whether the lobby holds `max_players` players, whatever rooms they are in.
+    pub fn is_full(&self) -> bool {
+        self.player_count() >= self.max_players
+    }
+
+    /// Adds a player to a room. Does nothing if the room does not exist.
+    pub fn insert_player(
+        &mut self,
+        room: &str,
+        id: u32,
+        x: f32,
+        y: f32,
+        addr: std::net::SocketAddr,
+    ) {
+        if let Some(state) = self.room_mut(room) {
+            state.insert_player(id, x, y, addr);
+            self.members.insert(id, room.to_string());
+        }
+    }
+
+    /// Removes a player from its room, ends its session and returns the name of the room.
+    pub fn remove_player(&mut self, id: u32) -> Option<String> {
+        self.sessions.close(id);
+        self.snapshots.remove(id);
+        self.leave_room(id)
+    }
+
+    fn leave_room(&mut self, id: u32) -> Option<String> {
+        let name = self.members.remove(&id)?;
+        if let Some(room) = self.rooms.get_mut(&name) {
+            room.state.remove_player(id);
+        }
+
+        Some(name)
+    }
+
+    pub fn list(&self) -> Vec<RoomInfo> {
+        self.rooms
+            .iter()
+            .map(|(name, room)| RoomInfo {
+                name: name.clone(),
+                players: room.state.player_count().min(u16::MAX as usize) as u16,
+                max_players: room.state.max_players().min(u16::MAX as usize) as u16,
+                has_password: room.password.is_some(),
+            })
+            .collect()
+    }
+
+    /// Creates an empty room. A limit of 0, or anything above the server's own limit, gets
+    /// the server's limit.
+    pub fn create_room(
+        &mut self,
+        name: &str,
+        max_players: usize,
+        password: Option<String>,
+    ) -> Result<(), RoomError> {
+        if !is_valid_name(name)
+            || password
+                .as_ref()
+                .is_some_and(|password| password.len() > MAX_PASSWORD_LEN)
+        {
+            return Err(RoomError::InvalidName);
+        }
+        if self.rooms.contains_key(name) {
+            return Err(RoomError::AlreadyExists);
+        }
+        if self.rooms.len() >= self.max_rooms {
+            return Err(RoomError::TooManyRooms);
+        }
+
+        let max_players = match max_players {
+            0 => self.max_players,
+            limit => limit.min(self.max_players),
+        };
+        self.insert_room(name.to_string(), max_players, password);
+
+        Ok(())
+    }
+
+    /// Checks whether a player may enter a room.
+    pub fn check_join(&self, name: &str, password: Option<&str>) -> Result<(), RoomError> {
+        let room = self.rooms.get(name).ok_or(RoomError::NotFound)?;
+        if room.password.is_some() && room.password.as_deref() != password {
+            return Err(RoomError::WrongPassword);
+        }
+        if room.state.is_full() {
+            return Err(RoomError::Full);
+        }
+
+        Ok(())
+    }
+
+    /// Moves a player into another room, where it starts at the origin. Its address and
+    /// capabilities go with it. Returns the room it left.
+    pub fn move_player(&mut self, id: u32, to: &str) -> Option<String> {
+        if !self.rooms.contains_key(to) {
+            return None;
+        }
+        let from = self.state_of(id)?;
+        let addr = *from.get_addr_from_id(id)?;
+        let capabilities = from.get_capabilities(id);
+
+        let left = self.leave_room(id)?;
+        self.insert_player(to, id, 0., 0., addr);
+        if let Some(state) = self.room_mut(to) {
+            state.set_capabilities(id, capabilities);
+        }
+
+        Some(left)
+    }
+
+    /// The message a player gets after entering a room, with the players it sees there.
+    pub fn joined_message(&mut self, id: u32) -> Option<BellMessage> {
+        let name = self.room_of(id)?.to_string();
+        let points = self.room_mut(&name)?.reset_interest(id);
+
+        Some(BellMessage::RoomJoinedMessage(name, points))
+    }
+
+    /// Evicts silent players from every room and returns them with the room they were in.
+    pub fn evict_stale(&mut self, timeout: std::time::Duration) -> Vec<(String, u32)> {
+        let mut evicted = vec![];
+        for (name, room) in self.rooms.iter_mut() {
+            for id in room.state.evict_stale(timeout) {
+                evicted.push((name.clone(), id));
+            }
+        }
+        for (_, id) in evicted.iter() {
+            self.members.remove(id);
+            self.sessions.close(*id);
+            self.snapshots.remove(*id);
+        }
+
+        evicted
+    }
+
+    /// Starts a session for a player that was just admitted and returns its token.
+    pub fn open_session(&mut self, id: u32, addr: std::net::SocketAddr) -> u64 {
+        self.sessions.open(id, addr)
+    }
+
+    pub fn session_token(&self, id: u32) -> Option<u64> {
+        self.sessions.token(id)
+    }
+
+    /// See `Sessions::authenticate`.
+    pub fn authenticate(
+        &mut self,
+        addr: std::net::SocketAddr,
+        token: Option<u64>,
+        id: u32,
+    ) -> bool {
+        self.sessions.authenticate(addr, token, id)
+    }
+
+    pub fn session_stats(&self) -> SessionStats {
+        self.sessions.stats()
+    }
+
+    /// Builds the snapshot of `tick`, which ran at `time`, for every player in every room,
+    /// relative to the last snapshot the player acked. Players that would get an empty delta
+    /// are skipped.
+    pub fn snapshots(&mut self, tick: u32, time: u32) -> Vec<(std::net::SocketAddr, BellMessage)> {
+        let mut snapshots = vec![];
+        for (id, name) in self.members.iter() {
+            let state = &self.rooms[name].state;
+            let Some(addr) = state.get_addr_from_id(*id) else {
+                continue;
+            };
+            if let Some(snapshot) =
+                self.snapshots
+                    .build(*id, tick, time, state.snapshot_positions(*id))
+            {
+                snapshots.push((*addr, BellMessage::SnapshotMessage(snapshot)));
+            }
+        }
+
+        snapshots
+    }
+
+    pub fn ack_snapshot(&mut self, id: u32, tick: u32) {
+        self.snapshots.ack(id, tick);
+    }
+
+    pub fn snapshot_stats(&self) -> SnapshotStats {
+        self.snapshots.stats()
+    }
+
+    /// Drops every room but the default one that nobody is in anymore.
+    pub fn remove_empty_rooms(&mut self) {
+        self.rooms
+            .retain(|name, room| name == DEFAULT_ROOM || room.state.player_count() > 0);
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_ROOM_NAME_LEN && !name.chars().any(|c| c.is_control())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> std::net::SocketAddr {
+        std::net::SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn names(lobby: &Lobby) -> Vec<String> {
+        lobby.list().into_iter().map(|info| info.name).collect()
+    }
+
+    #[test]
+    fn rooms_need_a_free_valid_name() {
+        let mut lobby = Lobby::new(4, 3);
+        assert_eq!(lobby.create_room("side", 0, None), Ok(()));
+        assert_eq!(
+            lobby.create_room("side", 0, None),
+            Err(RoomError::AlreadyExists)
+        );
+        assert_eq!(
+            lobby.create_room(DEFAULT_ROOM, 0, None),
+            Err(RoomError::AlreadyExists)
+        );
+        assert_eq!(lobby.create_room("", 0, None), Err(RoomError::InvalidName));
+        assert_eq!(
+            lobby.create_room(&"x".repeat(MAX_ROOM_NAME_LEN + 1), 0, None),
+            Err(RoomError::InvalidName)
+        );
+        assert_eq!(
+            lobby.create_room("locked", 0, Some("x".repeat(MAX_PASSWORD_LEN + 1))),
+            Err(RoomError::InvalidName)
+        );
+        assert_eq!(names(&lobby), vec![DEFAULT_ROOM, "side"]);
+    }
+
+    #[test]
+    fn rooms_are_limited_by_the_server() {
+        let mut lobby = Lobby::new(4, 4);
+        lobby.create_room("any", 0, None).unwrap();
+        lobby.create_room("huge", 100, None).unwrap();
+        lobby.create_room("small", 2, None).unwrap();
+        assert_eq!(
+            lobby.create_room("one too many", 0, None),
+            Err(RoomError::TooManyRooms)
+        );
+
+        let limits: Vec<(String, u16)> = lobby
+            .list()
+            .into_iter()
+            .map(|info| (info.name, info.max_players))
+            .collect();
+        assert_eq!(
+            limits,
+            vec![
+                ("any".to_string(), 4),
+                ("huge".to_string(), 4),
+                (DEFAULT_ROOM.to_string(), 4),
+                ("small".to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn joining_checks_the_password_and_the_room_limit() {
+        let mut lobby = Lobby::new(4, 4);
+        lobby
+            .create_room("locked", 1, Some("secret".to_string()))
+            .unwrap();
+        assert!(lobby.list()[0].has_password);
+
+        assert_eq!(lobby.check_join("nowhere", None), Err(RoomError::NotFound));
+        assert_eq!(
+            lobby.check_join("locked", None),
+            Err(RoomError::WrongPassword)
+        );
+        assert_eq!(
+            lobby.check_join("locked", Some("guess")),
+            Err(RoomError::WrongPassword)
+        );
+        assert_eq!(lobby.check_join("locked", Some("secret")), Ok(()));
+
+        lobby.insert_player("locked", 0, 0., 0., addr(1));
+        assert_eq!(
+            lobby.check_join("locked", Some("secret")),
+            Err(RoomError::Full)
+        );
+        assert_eq!(lobby.check_join(DEFAULT_ROOM, Some("anything")), Ok(()));
+    }
+
+    #[test]
+    fn players_keep_their_id_and_address_when_they_move() {
+        let mut lobby = Lobby::new(4, 4);
+        lobby.create_room("side", 0, None).unwrap();
+        lobby.insert_player(DEFAULT_ROOM, 7, 10., 10., addr(1));
+
+        assert_eq!(lobby.move_player(7, "nowhere"), None);
+        assert_eq!(lobby.move_player(7, "side"), Some(DEFAULT_ROOM.to_string()));
+        assert_eq!(lobby.room_of(7), Some("side"));
+        assert_eq!(lobby.get_id_for_addr(&addr(1)), Some(7));
+        assert_eq!(lobby.default_room().player_count(), 0);
+        assert_eq!(lobby.player_count(), 1);
+    }
+
+    #[test]
+    fn empty_rooms_are_removed_but_the_default_one() {
+        let mut lobby = Lobby::new(4, 4);
+        lobby.create_room("busy", 0, None).unwrap();
+        lobby.create_room("empty", 0, None).unwrap();
+        lobby.insert_player("busy", 0, 0., 0., addr(1));
+
+        lobby.remove_empty_rooms();
+        assert_eq!(names(&lobby), vec!["busy", DEFAULT_ROOM]);
+
+        assert_eq!(lobby.remove_player(0), Some("busy".to_string()));
+        lobby.remove_empty_rooms();
+        assert_eq!(names(&lobby), vec![DEFAULT_ROOM]);
+    }
+
+    #[test]
+    fn player_limit_covers_every_room() {
+        let mut lobby = Lobby::new(2, 4);
+        lobby.create_room("side", 0, None).unwrap();
+        lobby.insert_player(DEFAULT_ROOM, 0, 0., 0., addr(1));
+        lobby.move_player(0, "side");
+        assert!(!lobby.is_full());
+
+        lobby.insert_player(DEFAULT_ROOM, 1, 0., 0., addr(2));
+        assert!(lobby.is_full());
+        assert!(!lobby.default_room().is_full());
+    }
+}
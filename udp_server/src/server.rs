@@ -0,0 +1,663 @@
+use crate::codec::{kind, CodecError};
+use crate::handshake::Cookies;
+use crate::interest::View;
+use crate::movement::WorldBounds;
+use crate::protocol::{self, capabilities, MIN_SUPPORTED_VERSION};
+use crate::ratelimit::{DropStats, RateLimiter, RateLimits, Verdict};
+use crate::reliability::{ConnectionStats, Connections, Datagram};
+use crate::room::{self, Lobby};
+use crate::session::SessionStats;
+use crate::snapshot::SnapshotStats;
+use crate::state::{self, Command, StateHandle, TickOutput};
+use crate::{fragment, interest, movement, tick, BellMessage, Registration, WireFormat};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+
+/// How many ticks can wait to be sent before the state task waits for the network task.
+const FLUSH_BACKLOG: usize = 4;
+/// How many events a subscriber can fall behind before it misses some.
+pub const EVENT_BACKLOG: usize = 1024;
+
+/// Something that happened in the game, handed to the hooks and the event stream at the end
+/// of the tick it happened in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerEvent {
+    PlayerJoined {
+        id: u32,
+        addr: std::net::SocketAddr,
+    },
+    PlayerLeft {
+        id: u32,
+        reason: LeaveReason,
+    },
+    RoomChanged {
+        id: u32,
+        from: String,
+        to: String,
+    },
+    Collision {
+        room: String,
+        a: u32,
+        b: u32,
+    },
+    TickOverrun {
+        tick: u64,
+        took: std::time::Duration,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaveReason {
+    /// The player said goodbye
+    Left,
+    /// Nothing was heard from the player for the player timeout
+    TimedOut,
+}
+
+/// Everything a running server counts, see `Server::stats`.
+#[derive(Debug, Clone)]
+pub struct ServerStats {
+    pub players: usize,
+    pub rooms: usize,
+    pub sessions: SessionStats,
+    pub snapshots: SnapshotStats,
+    pub reliability: ConnectionStats,
+    /// What the rate limiters turned away, and the messages the state task was too far
+    /// behind to take
+    pub drops: DropStats,
+}
+
+/// Called by the state task for every event. Runs in the middle of the game loop, so it
+/// should be quick.
+pub type Hook = Arc<dyn Fn(&ServerEvent) + Send + Sync>;
+
+#[derive(Debug)]
+pub enum ServerError {
+    Io(std::io::Error),
+    InvalidConfig(String),
+    /// `run` was called on a server that already ran
+    AlreadyRunning,
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::Io(e) => write!(f, "{}", e),
+            ServerError::InvalidConfig(e) => write!(f, "invalid configuration: {}", e),
+            ServerError::AlreadyRunning => write!(f, "the server already ran"),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl From<std::io::Error> for ServerError {
+    fn from(e: std::io::Error) -> Self {
+        ServerError::Io(e)
+    }
+}
+
+/// Everything a running server needs to know, set through `ServerBuilder`.
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    pub bind: std::net::SocketAddr,
+    pub tick_rate: u32,
+    pub max_players: usize,
+    pub max_rooms: usize,
+    pub queue_capacity: usize,
+    pub player_timeout: std::time::Duration,
+    pub input_only: bool,
+    pub hitbox_size: f32,
+    pub max_speed: f32,
+    pub world: WorldBounds,
+    pub view: View,
+    pub wire_format: WireFormat,
+    pub mtu: usize,
+    pub rate_limits: RateLimits,
+}
+
+impl Config {
+    pub fn tick_period(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(1) / self.tick_rate
+    }
+
+    /// The capabilities this server agrees to use when a client asks for them.
+    pub fn offered_capabilities(&self) -> u32 {
+        let codec = match self.wire_format {
+            WireFormat::Binary => capabilities::BINARY_CODEC,
+            WireFormat::Json => capabilities::JSON_CODEC,
+        };
+
+        codec | capabilities::INPUT_MOVEMENT
+    }
+
+    /// The capabilities a client has to ask for to be let in.
+    pub fn required_capabilities(&self) -> u32 {
+        if self.input_only {
+            capabilities::INPUT_MOVEMENT
+        } else {
+            0
+        }
+    }
+}
+
+/// Sets up a `Server`. Everything not set keeps the same default as the `udp_server` binary,
+/// except for the address, which is an ephemeral port on localhost.
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Config,
+    hooks: Vec<Hook>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: std::net::SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, 0)),
+            tick_rate: tick::DEFAULT_TICK_RATE,
+            max_players: crate::DEFAULT_MAX_PLAYERS,
+            max_rooms: room::DEFAULT_MAX_ROOMS,
+            queue_capacity: crate::DEFAULT_QUEUE_CAPACITY,
+            player_timeout: protocol::DEFAULT_PLAYER_TIMEOUT,
+            input_only: false,
+            hitbox_size: crate::DEFAULT_HITBOX_SIZE,
+            max_speed: movement::PLAYER_SPEED,
+            world: WorldBounds::default(),
+            view: View::new(interest::DEFAULT_VIEW_WIDTH, interest::DEFAULT_VIEW_HEIGHT),
+            wire_format: WireFormat::default(),
+            mtu: fragment::DEFAULT_MTU,
+            rate_limits: RateLimits::default(),
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Address to listen on, port 0 picks a free one (see `Server::local_addr`).
+    pub fn bind(mut self, addr: std::net::SocketAddr) -> Self {
+        self.config.bind = addr;
+        self
+    }
+
+    pub fn tick_rate(mut self, tick_rate: u32) -> Self {
+        self.config.tick_rate = tick_rate;
+        self
+    }
+
+    pub fn max_players(mut self, max_players: usize) -> Self {
+        self.config.max_players = max_players;
+        self
+    }
+
+    pub fn max_rooms(mut self, max_rooms: usize) -> Self {
+        self.config.max_rooms = max_rooms;
+        self
+    }
+
+    /// How many commands can wait for the state task before new ones are dropped.
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.config.queue_capacity = queue_capacity;
+        self
+    }
+
+    pub fn player_timeout(mut self, player_timeout: std::time::Duration) -> Self {
+        self.config.player_timeout = player_timeout;
+        self
+    }
+
+    /// Players can only move by sending inputs, clients that do not ask for input movement are
+    /// refused.
+    pub fn input_only(mut self, input_only: bool) -> Self {
+        self.config.input_only = input_only;
+        self
+    }
+
+    pub fn hitbox_size(mut self, hitbox_size: f32) -> Self {
+        self.config.hitbox_size = hitbox_size;
+        self
+    }
+
+    pub fn movement_limits(mut self, max_speed: f32, world: WorldBounds) -> Self {
+        self.config.max_speed = max_speed;
+        self.config.world = world;
+        self
+    }
+
+    pub fn view(mut self, view: View) -> Self {
+        self.config.view = view;
+        self
+    }
+
+    pub fn wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.config.wire_format = wire_format;
+        self
+    }
+
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.config.mtu = mtu;
+        self
+    }
+
+    pub fn rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        self.config.rate_limits = rate_limits;
+        self
+    }
+
+    /// Adds a function that is called with every event, in the order they were added.
+    pub fn hook(mut self, hook: impl Fn(&ServerEvent) + Send + Sync + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Checks the settings and binds the socket. Nothing is received before `run`.
+    pub async fn build(self) -> Result<Server, ServerError> {
+        self.validate().map_err(ServerError::InvalidConfig)?;
+        let socket = UdpSocket::bind(self.config.bind).await?;
+        let local_addr = socket.local_addr()?;
+        let (events, _) = broadcast::channel(EVENT_BACKLOG);
+        let (shutdown, _) = watch::channel(false);
+
+        Ok(Server {
+            inner: Arc::new(Inner {
+                config: Arc::new(self.config),
+                socket: Arc::new(socket),
+                local_addr,
+                hooks: self.hooks.into(),
+                events,
+                shutdown,
+                started: std::sync::atomic::AtomicBool::new(false),
+                stats: std::sync::Mutex::new(None),
+            }),
+        })
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let config = &self.config;
+        if config.tick_rate == 0 {
+            return Err("the tick rate must be at least 1".to_string());
+        }
+        if config.player_timeout.is_zero() {
+            return Err("the player timeout must be above 0".to_string());
+        }
+        if !is_positive(config.hitbox_size) {
+            return Err("the hitbox size must be above 0".to_string());
+        }
+        if !is_positive(config.max_speed) {
+            return Err("the max speed must be above 0".to_string());
+        }
+        if !is_positive(config.world.half_width) || !is_positive(config.world.half_height) {
+            return Err("the world must be larger than 0".to_string());
+        }
+        if config.mtu < fragment::MIN_MTU || config.mtu > fragment::MAX_DATAGRAM_LEN {
+            return Err(format!(
+                "the mtu must be between {} and {}",
+                fragment::MIN_MTU,
+                fragment::MAX_DATAGRAM_LEN
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn is_positive(value: f32) -> bool {
+    value.is_finite() && value > 0.
+}
+
+struct Inner {
+    config: Arc<Config>,
+    socket: Arc<UdpSocket>,
+    local_addr: std::net::SocketAddr,
+    hooks: Arc<[Hook]>,
+    events: broadcast::Sender<ServerEvent>,
+    shutdown: watch::Sender<bool>,
+    started: std::sync::atomic::AtomicBool,
+    /// Where stats are asked for while the server runs
+    stats: std::sync::Mutex<Option<mpsc::Sender<oneshot::Sender<ServerStats>>>>,
+}
+
+/// A bell server that can run inside another program. Clones share the same server, so one
+/// clone can `run` it while another one shuts it down.
+#[derive(Clone)]
+pub struct Server {
+    inner: Arc<Inner>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// The address the socket is bound to, with the actual port if an ephemeral one was asked
+    /// for.
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.inner.local_addr
+    }
+
+    /// Every event from now on. A subscriber that falls more than `EVENT_BACKLOG` events
+    /// behind skips the oldest ones.
+    pub fn events(&self) -> broadcast::Receiver<ServerEvent> {
+        self.inner.events.subscribe()
+    }
+
+    /// What the server counted so far: players, sessions, snapshots, reliability and what was
+    /// dropped. None if the server is not running.
+    pub async fn stats(&self) -> Option<ServerStats> {
+        let requests = self.inner.stats.lock().unwrap().clone()?;
+        let (reply, answer) = oneshot::channel();
+        requests.send(reply).await.ok()?;
+        answer.await.ok()
+    }
+
+    /// Makes `run` return. What the current tick produced is still sent. A server that is
+    /// shut down before it runs returns right away.
+    pub fn shutdown(&self) {
+        self.inner.shutdown.send_replace(true);
+    }
+
+    /// Receives and ticks until `shutdown` is called. A server only runs once.
+    pub async fn run(&self) -> Result<(), ServerError> {
+        let inner = &self.inner;
+        if inner
+            .started
+            .swap(true, std::sync::atomic::Ordering::SeqCst)
+        {
+            return Err(ServerError::AlreadyRunning);
+        }
+        let mut shutdown = inner.shutdown.subscribe();
+        if *shutdown.borrow_and_update() {
+            return Ok(());
+        }
+
+        let config = &inner.config;
+        let mut lobby = Lobby::new(config.max_players, config.max_rooms);
+        lobby.set_hitbox_size(config.hitbox_size);
+        lobby.set_movement_limits(config.max_speed, config.world);
+        lobby.set_default_view(config.view);
+        let socket = inner.socket.clone();
+        log::info!("Listening on {}", inner.local_addr);
+
+        // State task: the only one that touches the game, one tick at a time
+        let (flush, mut ticks) = mpsc::channel::<TickOutput>(FLUSH_BACKLOG);
+        let (state, ticking) = state::spawn(
+            lobby,
+            config.clone(),
+            flush,
+            inner.hooks.clone(),
+            inner.events.clone(),
+        );
+
+        // Stats are put together by the network loop, which asks the state task for its part
+        let (stats_requests, mut stats) = mpsc::channel::<oneshot::Sender<ServerStats>>(1);
+        *inner.stats.lock().unwrap() = Some(stats_requests.clone());
+
+        // Report task: logs the stats of every part next to the tick timings
+        let reporting = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(state::TICK_REPORT_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let (reply, answer) = oneshot::channel();
+                if stats_requests.send(reply).await.is_err() {
+                    return;
+                }
+                let Ok(stats) = answer.await else {
+                    return;
+                };
+                log::info!("Players: {} in {} rooms", stats.players, stats.rooms);
+                log::info!("Reliability stats: {:?}", stats.reliability);
+                log::info!("Session stats: {:?}", stats.sessions);
+                log::info!("Drop stats: {:?}", stats.drops);
+                log::info!(
+                    "Snapshot stats: {:?}, {:.1} bytes per client and tick",
+                    stats.snapshots,
+                    stats.snapshots.bytes_per_client()
+                );
+            }
+        });
+
+        // Everything else happens here: receiving, checking and sending what the ticks produced
+        let mut network = Network {
+            socket: socket.clone(),
+            connections: Connections::new(config.wire_format, config.mtu),
+            limiter: RateLimiter::new(config.rate_limits),
+            cookies: Cookies::new(),
+            player_timeout: config.player_timeout,
+        };
+        let mut buf = vec![0; fragment::RECV_BUFFER_LEN];
+
+        let result = loop {
+            let (size, src) = tokio::select! {
+                received = socket.recv_from(&mut buf) => match received {
+                    Ok(received) => received,
+                    Err(e) => break Err(ServerError::Io(e)),
+                },
+                Some(output) = ticks.recv() => {
+                    network.flush(output).await;
+                    continue;
+                }
+                Some(reply) = stats.recv() => {
+                    network.stats(&state, reply);
+                    continue;
+                }
+                _ = shutdown.changed() => break Ok(()),
+            };
+            network.receive(&state, src, &buf[..size]).await;
+        };
+
+        // the state task stops once the last handle is gone, what it produced until then is
+        // still sent
+        inner.stats.lock().unwrap().take();
+        reporting.abort();
+        _ = reporting.await;
+        drop(state);
+        while let Some(output) = ticks.recv().await {
+            network.flush(output).await;
+        }
+        _ = ticking.await;
+        log::info!("Server on {} stopped", inner.local_addr);
+
+        result
+    }
+}
+
+/// The socket side of a running server: the acks and retransmissions for every peer, the
+/// limits per address and the cookies. Only the task running the server touches it, so
+/// nothing here is locked.
+struct Network {
+    socket: Arc<UdpSocket>,
+    connections: Connections,
+    limiter: RateLimiter,
+    cookies: Cookies,
+    player_timeout: std::time::Duration,
+}
+
+impl Network {
+    /// Checks a datagram, answers what can be answered without the game and hands the rest
+    /// to the state task. Sessions and per player limits are checked by the state task.
+    async fn receive(&mut self, state: &StateHandle, src: std::net::SocketAddr, data: &[u8]) {
+        log::debug!("Received {} bytes from {}", data.len(), src);
+        match self.limiter.check_packet(src, std::time::Instant::now()) {
+            Verdict::Allow => {}
+            Verdict::Limited => {
+                log::debug!("Rate limiting {}", src);
+                return;
+            }
+            Verdict::Banned => return,
+        }
+        let received = self
+            .connections
+            .receive(src, data, std::time::Instant::now());
+        let (messages, session, restart) = match received {
+            Ok(received) => {
+                for reply in received.replies {
+                    _ = self.socket.send_to(&reply, src).await;
+                }
+                (received.messages, received.session, received.restart)
+            }
+            // an older or newer client whose payload we cannot read, but the header still
+            // tells us it is trying to register
+            Err(CodecError::UnsupportedVersion { version, kind })
+                if kind == kind::PLAYER_REGISTRATION =>
+            {
+                reject_registration(&self.socket, src, version).await;
+                return;
+            }
+            Err(e @ CodecError::Oversized(_)) => {
+                log::warn!("Dropping datagram from {}: {}", src, e);
+                return;
+            }
+            Err(e) => {
+                log::warn!("Message received isn't BellMessage: {}", e);
+                return;
+            }
+        };
+
+        // the registration itself is resent by the peer once its new epoch is accepted
+        if let Some((epoch, registration)) = restart {
+            if self
+                .check_registration(src, &registration, data.len())
+                .await
+            {
+                log::info!("{} restarted", src);
+                self.connections.restart(src, epoch);
+            }
+        }
+
+        for message in messages {
+            if let BellMessage::PlayerRegistrationMessage(ref registration) = message {
+                if !self.check_registration(src, registration, data.len()).await {
+                    continue;
+                }
+            }
+
+            let Some(command) = Command::from_message(src, session, message) else {
+                continue;
+            };
+            if !state.send(command) {
+                self.limiter.record_queue_full();
+                log::warn!("State task is behind, dropping a message from {}", src);
+            }
+        }
+    }
+
+    /// Whether a registration may go ahead. Answers it with a rejection or a challenge if
+    /// its version is not supported or its cookie did not check out.
+    async fn check_registration(
+        &mut self,
+        src: std::net::SocketAddr,
+        registration: &Registration,
+        received_len: usize,
+    ) -> bool {
+        let now = std::time::Instant::now();
+        if !self.limiter.check_registration(src, now) {
+            log::debug!("Too many registrations from {}", src);
+            return false;
+        }
+        if !protocol::is_supported_version(registration.protocol_version) {
+            reject_registration(&self.socket, src, registration.protocol_version).await;
+            return false;
+        }
+        if !self.cookies.verify(src, registration.cookie, now) {
+            let cookie = self.cookies.issue(src, now);
+            challenge(&self.socket, src, cookie, received_len).await;
+            return false;
+        }
+
+        true
+    }
+
+    /// Sends what a tick produced together with the retransmissions that are due, and counts
+    /// the players the state task rate limited against their addresses.
+    async fn flush(&mut self, output: TickOutput) {
+        let now = std::time::Instant::now();
+        for addr in output.limited {
+            self.limiter.record_player_limited(addr, now);
+        }
+        let mut datagrams = output.outgoing.flush(&mut self.connections, now);
+        datagrams.extend(
+            self.connections
+                .poll(now)
+                .into_iter()
+                .map(|(addr, data)| (addr, Datagram::Owned(data))),
+        );
+        self.connections.evict_idle(self.player_timeout, now);
+        self.limiter.evict_idle(self.player_timeout, now);
+        send_batch(&self.socket, &datagrams).await;
+    }
+
+    /// Answers with the network stats and the ones the state task adds. The answer is waited
+    /// for on its own task, the state task may be waiting for this one to take a tick.
+    fn stats(&self, state: &StateHandle, reply: oneshot::Sender<ServerStats>) {
+        let reliability = self.connections.stats();
+        let drops = self.limiter.stats();
+        let state = state.clone();
+        tokio::spawn(async move {
+            let Some(stats) = state.stats().await else {
+                return;
+            };
+            _ = reply.send(ServerStats {
+                players: stats.players,
+                rooms: stats.rooms,
+                sessions: stats.sessions,
+                snapshots: stats.snapshots,
+                reliability,
+                drops,
+            });
+        });
+    }
+}
+
+/// Writes a tick's worth of datagrams. The socket is only waited on when its send buffer is
+/// full, instead of once for every datagram.
+async fn send_batch(socket: &UdpSocket, datagrams: &[(std::net::SocketAddr, Datagram)]) {
+    for (addr, data) in datagrams {
+        loop {
+            match socket.try_send_to(data, *addr) {
+                Ok(_) => break,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    if socket.writable().await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    log::debug!("Failed to send to {}: {}", addr, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Turns away a client speaking a protocol version we do not support. The rejection is sent
+/// bare and binary so that any version can read it, whatever format it speaks.
+async fn reject_registration(socket: &UdpSocket, addr: std::net::SocketAddr, version: u8) {
+    log::info!(
+        "Rejecting registration from {} with protocol version {}",
+        addr,
+        version
+    );
+    let reply = BellMessage::RegistrationRejectedMessage(MIN_SUPPORTED_VERSION);
+    if let Ok(reply) = crate::encode(&reply) {
+        _ = socket.send_to(&reply, addr).await;
+    }
+}
+
+/// Asks a client to prove it can receive at the address it registered from. Nothing is kept
+/// about the client, and the challenge is not sent at all if it would be larger than the
+/// datagram that asked for it. It always goes out binary, every client decodes that and it is
+/// smaller than any registration, so a JSON server can still challenge binary clients.
+async fn challenge(
+    socket: &UdpSocket,
+    addr: std::net::SocketAddr,
+    cookie: u64,
+    request_len: usize,
+) {
+    let Ok(reply) = crate::encode(&BellMessage::ChallengeMessage(cookie)) else {
+        return;
+    };
+    if reply.len() > request_len {
+        log::debug!("Not challenging {}, the request was too small", addr);
+        return;
+    }
+    log::debug!("Challenging registration from {}", addr);
+    _ = socket.send_to(&reply, addr).await;
+}
@@ -0,0 +1,155 @@
+use crate::codec::{self, kind, CodecError, HEADER_LEN};
+
+/// header + token (8)
+pub const SESSION_HEADER_LEN: usize = HEADER_LEN + 8;
+
+/// Prefixes a datagram with the session token of the client sending it.
+pub fn wrap(token: u64, datagram: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(SESSION_HEADER_LEN + datagram.len());
+    codec::write_header(&mut data, kind::SESSION);
+    data.extend_from_slice(&token.to_le_bytes());
+    data.extend_from_slice(datagram);
+
+    data
+}
+
+/// Splits a session frame into its token and the datagram it carries.
+pub fn unwrap(data: &[u8]) -> Result<(u64, &[u8]), CodecError> {
+    if data.len() < SESSION_HEADER_LEN {
+        return Err(CodecError::Truncated);
+    }
+    let mut token = [0; 8];
+    token.copy_from_slice(&data[HEADER_LEN..SESSION_HEADER_LEN]);
+
+    Ok((u64::from_le_bytes(token), &data[SESSION_HEADER_LEN..]))
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SessionStats {
+    pub opened: u64,
+    /// Messages that needed a session but came without a token.
+    pub missing_token: u64,
+    /// Messages whose token, source address and player id did not belong together.
+    pub mismatched: u64,
+}
+
+/// Session tokens handed out at registration. A token is bound to the player id and the
+/// address it registered from, and is only good for that pair.
+#[derive(Default)]
+pub struct Sessions {
+    by_token: std::collections::HashMap<u64, (u32, std::net::SocketAddr)>,
+    by_id: std::collections::HashMap<u32, u64>,
+    stats: SessionStats,
+}
+
+impl Sessions {
+    /// Hands out a fresh token for a player, replacing any it had before.
+    pub fn open(&mut self, id: u32, addr: std::net::SocketAddr) -> u64 {
+        self.close(id);
+        let token = loop {
+            // 0 never goes out so that it can't be mistaken for a missing token
+            let token = rand::random::<u64>();
+            if token != 0 && !self.by_token.contains_key(&token) {
+                break token;
+            }
+        };
+        self.by_token.insert(token, (id, addr));
+        self.by_id.insert(id, token);
+        self.stats.opened += 1;
+
+        token
+    }
+
+    pub fn close(&mut self, id: u32) {
+        if let Some(token) = self.by_id.remove(&id) {
+            self.by_token.remove(&token);
+        }
+    }
+
+    pub fn token(&self, id: u32) -> Option<u64> {
+        self.by_id.get(&id).copied()
+    }
+
+    /// Whether a message naming player `id` may be handled. It has to come from the address
+    /// its token was issued to and name the player it was issued for, registrations need no
+    /// session. Refused messages are counted.
+    pub fn authenticate(
+        &mut self,
+        addr: std::net::SocketAddr,
+        token: Option<u64>,
+        id: u32,
+    ) -> bool {
+        let Some(token) = token else {
+            self.stats.missing_token += 1;
+            return false;
+        };
+
+        let matches = self
+            .by_token
+            .get(&token)
+            .is_some_and(|(owner, session_addr)| *session_addr == addr && *owner == id);
+        if !matches {
+            self.stats.mismatched += 1;
+        }
+
+        matches
+    }
+
+    pub fn stats(&self) -> SessionStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> std::net::SocketAddr {
+        std::net::SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn token_is_only_good_for_its_player_and_address() {
+        let mut sessions = Sessions::default();
+        let token = sessions.open(1, addr(1));
+        let other = sessions.open(2, addr(2));
+
+        assert!(sessions.authenticate(addr(1), Some(token), 1));
+        assert!(!sessions.authenticate(addr(1), Some(token.wrapping_add(1)), 1));
+        assert!(!sessions.authenticate(addr(3), Some(token), 1));
+        assert!(!sessions.authenticate(addr(1), Some(token), 2));
+        // a valid token of another player does not help either
+        assert!(!sessions.authenticate(addr(1), Some(other), 1));
+        assert!(!sessions.authenticate(addr(1), None, 1));
+
+        let stats = sessions.stats();
+        assert_eq!(stats.opened, 2);
+        assert_eq!(stats.mismatched, 4);
+        assert_eq!(stats.missing_token, 1);
+    }
+
+    #[test]
+    fn closed_and_replaced_tokens_stop_working() {
+        let mut sessions = Sessions::default();
+        let first = sessions.open(1, addr(1));
+        let second = sessions.open(1, addr(1));
+        assert_ne!(first, second);
+        assert!(!sessions.authenticate(addr(1), Some(first), 1));
+        assert_eq!(sessions.token(1), Some(second));
+
+        sessions.close(1);
+        assert!(!sessions.authenticate(addr(1), Some(second), 1));
+        assert_eq!(sessions.token(1), None);
+    }
+
+    #[test]
+    fn frame_round_trips() {
+        let framed = wrap(42, &[1, 2, 3]);
+        assert_eq!(framed.len(), SESSION_HEADER_LEN + 3);
+        assert_eq!(unwrap(&framed).unwrap(), (42, &[1, 2, 3][..]));
+        assert!(matches!(
+            unwrap(&framed[..SESSION_HEADER_LEN - 1]),
+            Err(CodecError::Truncated)
+        ));
+    }
+}
@@ -0,0 +1,484 @@
+use crate::Point;
+use serde::{Deserialize, Serialize};
+
+/// Positions go on the wire in steps of `1 / QUANTIZE` units.
+pub const QUANTIZE: f32 = 16.;
+/// How many snapshots sent to a client are kept around to be used as its baseline, and how
+/// many a client keeps to apply deltas to. A client that did not ack any of them gets a full
+/// snapshot.
+pub const HISTORY_LEN: usize = 32;
+
+/// A position in steps of `1 / QUANTIZE`.
+pub type Quantized = (i32, i32);
+
+pub fn quantize(position: (f32, f32)) -> Quantized {
+    (
+        (position.0 * QUANTIZE).round() as i32,
+        (position.1 * QUANTIZE).round() as i32,
+    )
+}
+
+pub fn dequantize(position: Quantized) -> (f32, f32) {
+    (position.0 as f32 / QUANTIZE, position.1 as f32 / QUANTIZE)
+}
+
+/// An entity that changed since the baseline. Entities that were in the baseline carry the
+/// difference to their position there, new ones their quantized position.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub id: u32,
+    pub x: i32,
+    pub y: i32,
+}
+
+/// The positions a client sees at one tick, as a delta against a snapshot it acknowledged.
+/// Entities that did not move since the baseline are left out.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub tick: u32,
+    /// Milliseconds since the server started when the tick ran. Clients place the positions
+    /// on their timeline with it, wraps after 49 days
+    pub time: u32,
+    /// Tick of the snapshot this one is relative to, 0 for a full snapshot
+    pub baseline: u32,
+    pub changed: Vec<SnapshotEntry>,
+    /// Entities of the baseline that are not in this snapshot anymore
+    pub removed: Vec<u32>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SnapshotStats {
+    pub full: u64,
+    pub delta: u64,
+    /// Ticks where nothing a client sees changed since its baseline, so nothing was sent
+    pub unchanged: u64,
+    /// Size of every snapshot sent, in the binary format
+    pub bytes: u64,
+    pub max_bytes: usize,
+}
+
+impl SnapshotStats {
+    /// Average snapshot traffic per client and tick, skipped snapshots included.
+    pub fn bytes_per_client(&self) -> f64 {
+        match self.full + self.delta + self.unchanged {
+            0 => 0.,
+            count => self.bytes as f64 / count as f64,
+        }
+    }
+}
+
+#[derive(Default)]
+struct History {
+    sent: std::collections::VecDeque<(u32, std::collections::HashMap<u32, Quantized>)>,
+    acked: Option<u32>,
+}
+
+impl History {
+    fn get(&self, tick: u32) -> Option<&std::collections::HashMap<u32, Quantized>> {
+        self.sent
+            .iter()
+            .find(|(sent, _)| *sent == tick)
+            .map(|(_, positions)| positions)
+    }
+}
+
+/// Server side: the snapshots sent to every client and the last one each of them acked.
+#[derive(Default)]
+pub struct Snapshots {
+    clients: std::collections::HashMap<u32, History>,
+    stats: SnapshotStats,
+}
+
+impl Snapshots {
+    /// Builds the snapshot of `tick`, which ran at `time`, for a client from what it sees.
+    /// Returns None when nothing changed since the snapshot it acked last. Ticks have to start
+    /// at 1 and go up.
+    pub fn build(
+        &mut self,
+        id: u32,
+        tick: u32,
+        time: u32,
+        positions: std::collections::HashMap<u32, Quantized>,
+    ) -> Option<Snapshot> {
+        let history = self.clients.entry(id).or_default();
+        let baseline = history
+            .acked
+            .and_then(|acked| Some((acked, history.get(acked)?)));
+
+        let snapshot = match baseline {
+            Some((acked, baseline)) => {
+                let changed = positions
+                    .iter()
+                    .filter(|(entity, position)| baseline.get(entity) != Some(position))
+                    .map(|(entity, (x, y))| match baseline.get(entity) {
+                        Some((base_x, base_y)) => SnapshotEntry {
+                            id: *entity,
+                            x: x.wrapping_sub(*base_x),
+                            y: y.wrapping_sub(*base_y),
+                        },
+                        None => SnapshotEntry {
+                            id: *entity,
+                            x: *x,
+                            y: *y,
+                        },
+                    })
+                    .collect::<Vec<SnapshotEntry>>();
+                let removed = baseline
+                    .keys()
+                    .filter(|entity| !positions.contains_key(entity))
+                    .copied()
+                    .collect::<Vec<u32>>();
+                if changed.is_empty() && removed.is_empty() {
+                    self.stats.unchanged += 1;
+                    return None;
+                }
+                self.stats.delta += 1;
+                Snapshot {
+                    tick,
+                    time,
+                    baseline: acked,
+                    changed,
+                    removed,
+                }
+            }
+            None => {
+                self.stats.full += 1;
+                Snapshot {
+                    tick,
+                    time,
+                    baseline: 0,
+                    changed: positions
+                        .iter()
+                        .map(|(entity, (x, y))| SnapshotEntry {
+                            id: *entity,
+                            x: *x,
+                            y: *y,
+                        })
+                        .collect(),
+                    removed: vec![],
+                }
+            }
+        };
+
+        history.sent.push_back((tick, positions));
+        if history.sent.len() > HISTORY_LEN {
+            history.sent.pop_front();
+        }
+        let len = crate::codec::snapshot_len(&snapshot);
+        self.stats.bytes += len as u64;
+        self.stats.max_bytes = self.stats.max_bytes.max(len);
+
+        Some(snapshot)
+    }
+
+    /// Records that a client got the snapshot of `tick`. Acks for snapshots that were not
+    /// sent, or older than the last acked one, are ignored.
+    pub fn ack(&mut self, id: u32, tick: u32) {
+        let Some(history) = self.clients.get_mut(&id) else {
+            return;
+        };
+        if history.acked.is_some_and(|acked| tick <= acked) || history.get(tick).is_none() {
+            return;
+        }
+        history.acked = Some(tick);
+        // acks only go forward, older snapshots will never be a baseline again
+        history.sent.retain(|(sent, _)| *sent >= tick);
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.clients.remove(&id);
+    }
+
+    pub fn stats(&self) -> SnapshotStats {
+        self.stats
+    }
+}
+
+/// Client side: the snapshots received last, to apply the deltas that refer to them.
+#[derive(Default)]
+pub struct Baselines {
+    received: std::collections::VecDeque<(u32, std::collections::HashMap<u32, Quantized>)>,
+}
+
+impl Baselines {
+    /// Applies a snapshot and returns the entities that are somewhere else than in the newest
+    /// snapshot before it. Returns None, and the snapshot should not be acked, if it is older
+    /// than the newest one or its baseline is not known.
+    pub fn apply(&mut self, snapshot: &Snapshot) -> Option<Vec<Point>> {
+        if self
+            .received
+            .back()
+            .is_some_and(|(newest, _)| snapshot.tick <= *newest)
+        {
+            return None;
+        }
+        let mut positions = match snapshot.baseline {
+            0 => std::collections::HashMap::new(),
+            baseline => self
+                .received
+                .iter()
+                .find(|(tick, _)| *tick == baseline)
+                .map(|(_, positions)| positions.clone())?,
+        };
+        for entity in snapshot.removed.iter() {
+            positions.remove(entity);
+        }
+        for entry in snapshot.changed.iter() {
+            let position = match positions.get(&entry.id) {
+                Some((x, y)) if snapshot.baseline != 0 => {
+                    (x.wrapping_add(entry.x), y.wrapping_add(entry.y))
+                }
+                _ => (entry.x, entry.y),
+            };
+            positions.insert(entry.id, position);
+        }
+
+        let previous = self.received.back().map(|(_, positions)| positions);
+        let moved = positions
+            .iter()
+            .filter(|(entity, position)| {
+                previous.and_then(|previous| previous.get(entity)) != Some(position)
+            })
+            .map(|(entity, position)| {
+                let (x, y) = dequantize(*position);
+                Point { x, y, id: *entity }
+            })
+            .collect();
+
+        self.received.push_back((snapshot.tick, positions));
+        if self.received.len() > HISTORY_LEN {
+            self.received.pop_front();
+        }
+
+        Some(moved)
+    }
+
+    /// Every position of the newest snapshot applied.
+    pub fn newest(&self) -> Vec<Point> {
+        self.received
+            .back()
+            .map(|(_, positions)| {
+                positions
+                    .iter()
+                    .map(|(entity, position)| {
+                        let (x, y) = dequantize(*position);
+                        Point { x, y, id: *entity }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positions(entries: &[(u32, Quantized)]) -> std::collections::HashMap<u32, Quantized> {
+        entries.iter().copied().collect()
+    }
+
+    fn sorted(mut points: Vec<Point>) -> Vec<(u32, f32, f32)> {
+        points.sort_by_key(|point| point.id);
+        points
+            .into_iter()
+            .map(|point| (point.id, point.x, point.y))
+            .collect()
+    }
+
+    #[test]
+    fn quantize_rounds_to_steps() {
+        assert_eq!(quantize((1.5, -0.03)), (24, 0));
+        assert_eq!(quantize((-2.04, 0.04)), (-33, 1));
+        assert_eq!(dequantize(quantize((12.25, -7.5))), (12.25, -7.5));
+    }
+
+    #[test]
+    fn first_snapshot_is_full() {
+        let mut snapshots = Snapshots::default();
+        let snapshot = snapshots
+            .build(1, 1, 10, positions(&[(5, (16, -16))]))
+            .unwrap();
+        assert_eq!(snapshot.baseline, 0);
+        assert_eq!(
+            snapshot.changed,
+            vec![SnapshotEntry {
+                id: 5,
+                x: 16,
+                y: -16
+            }]
+        );
+        assert!(snapshot.removed.is_empty());
+        assert_eq!(snapshots.stats().full, 1);
+    }
+
+    #[test]
+    fn delta_holds_only_changes_since_the_ack() {
+        let mut snapshots = Snapshots::default();
+        snapshots.build(
+            1,
+            1,
+            0,
+            positions(&[(1, (0, 0)), (2, (10, 10)), (3, (5, 5))]),
+        );
+        snapshots.ack(1, 1);
+
+        let mut snapshot = snapshots
+            .build(
+                1,
+                2,
+                50,
+                positions(&[(1, (0, 0)), (2, (13, 8)), (4, (-7, 7))]),
+            )
+            .unwrap();
+        snapshot.changed.sort_by_key(|entry| entry.id);
+        assert_eq!(snapshot.baseline, 1);
+        assert_eq!(
+            snapshot.changed,
+            vec![
+                SnapshotEntry { id: 2, x: 3, y: -2 },
+                SnapshotEntry { id: 4, x: -7, y: 7 },
+            ]
+        );
+        assert_eq!(snapshot.removed, vec![3]);
+        assert_eq!(snapshots.stats().delta, 1);
+    }
+
+    #[test]
+    fn nothing_is_sent_while_nothing_changes() {
+        let mut snapshots = Snapshots::default();
+        let seen = positions(&[(1, (3, 4))]);
+        snapshots.build(1, 1, 0, seen.clone());
+        snapshots.ack(1, 1);
+
+        assert!(snapshots.build(1, 2, 50, seen).is_none());
+        assert_eq!(snapshots.stats().unchanged, 1);
+    }
+
+    #[test]
+    fn unacked_client_keeps_getting_full_snapshots() {
+        let mut snapshots = Snapshots::default();
+        snapshots.build(1, 1, 0, positions(&[(1, (0, 0))]));
+        // acks for ticks that were never sent do not make a baseline
+        snapshots.ack(1, 7);
+        snapshots.ack(2, 1);
+
+        let snapshot = snapshots
+            .build(1, 2, 50, positions(&[(1, (0, 0))]))
+            .unwrap();
+        assert_eq!(snapshot.baseline, 0);
+        assert_eq!(snapshot.changed.len(), 1);
+    }
+
+    #[test]
+    fn older_ack_does_not_move_the_baseline_back() {
+        let mut snapshots = Snapshots::default();
+        for tick in 1..=3 {
+            snapshots.build(1, tick, 0, positions(&[(1, (tick as i32, 0))]));
+        }
+        snapshots.ack(1, 3);
+        snapshots.ack(1, 2);
+
+        let snapshot = snapshots.build(1, 4, 0, positions(&[(1, (4, 0))])).unwrap();
+        assert_eq!(snapshot.baseline, 3);
+        assert_eq!(snapshot.changed, vec![SnapshotEntry { id: 1, x: 1, y: 0 }]);
+    }
+
+    #[test]
+    fn client_rebuilds_the_positions_from_deltas() {
+        let mut snapshots = Snapshots::default();
+        let mut baselines = Baselines::default();
+        let ticks = [
+            positions(&[(1, (0, 0)), (2, (100, 100))]),
+            positions(&[(1, (16, 0)), (2, (100, 100))]),
+            positions(&[(1, (32, -8)), (3, (i32::MAX, i32::MIN))]),
+            positions(&[(1, (32, -8)), (2, (0, 0)), (3, (i32::MIN, i32::MAX))]),
+        ];
+
+        for (tick, seen) in (1..).zip(ticks.iter()) {
+            let snapshot = snapshots.build(1, tick, 0, seen.clone()).unwrap();
+            // the snapshot of the second tick is lost, the third is relative to the first
+            if tick == 2 {
+                continue;
+            }
+            let snapshot = decode_snapshot(&snapshot);
+            baselines.apply(&snapshot).unwrap();
+            snapshots.ack(1, tick);
+
+            let expected = seen
+                .iter()
+                .map(|(id, position)| {
+                    let (x, y) = dequantize(*position);
+                    Point { x, y, id: *id }
+                })
+                .collect();
+            assert_eq!(sorted(baselines.newest()), sorted(expected));
+        }
+    }
+
+    #[test]
+    fn apply_returns_only_what_moved() {
+        let mut baselines = Baselines::default();
+        baselines.apply(&Snapshot {
+            tick: 1,
+            time: 0,
+            baseline: 0,
+            changed: vec![
+                SnapshotEntry { id: 1, x: 0, y: 0 },
+                SnapshotEntry {
+                    id: 2,
+                    x: 16,
+                    y: 16,
+                },
+            ],
+            removed: vec![],
+        });
+
+        let moved = baselines
+            .apply(&Snapshot {
+                tick: 2,
+                time: 50,
+                baseline: 1,
+                changed: vec![SnapshotEntry { id: 2, x: 16, y: 0 }],
+                removed: vec![],
+            })
+            .unwrap();
+        assert_eq!(sorted(moved), vec![(2, 2., 1.)]);
+    }
+
+    #[test]
+    fn stale_or_unknown_snapshots_are_not_applied() {
+        let mut baselines = Baselines::default();
+        let full = Snapshot {
+            tick: 5,
+            time: 0,
+            baseline: 0,
+            changed: vec![SnapshotEntry { id: 1, x: 1, y: 1 }],
+            removed: vec![],
+        };
+        assert!(baselines.apply(&full).is_some());
+        assert!(baselines.apply(&full).is_none());
+        assert!(baselines
+            .apply(&Snapshot {
+                tick: 6,
+                baseline: 4,
+                ..full.clone()
+            })
+            .is_none());
+        assert!(baselines
+            .apply(&Snapshot {
+                tick: 6,
+                baseline: 5,
+                ..full
+            })
+            .is_some());
+    }
+
+    fn decode_snapshot(snapshot: &Snapshot) -> Snapshot {
+        let data = crate::encode(&crate::BellMessage::SnapshotMessage(snapshot.clone())).unwrap();
+        match crate::decode(&data).unwrap() {
+            crate::BellMessage::SnapshotMessage(snapshot) => snapshot,
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,88 @@
+/// Uniform grid that buckets ids by position so that neighbourhood queries only look at the
+/// surrounding cells instead of every player.
+pub struct SpatialHashGrid {
+    cell_size: f32,
+    cells: std::collections::HashMap<(i32, i32), Vec<u32>>,
+}
+
+impl SpatialHashGrid {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size: cell_size.max(f32::EPSILON),
+            cells: std::collections::HashMap::new(),
+        }
+    }
+
+    /// Cells past the range of `i32` are folded into the outermost ones.
+    pub fn cell_of(&self, x: f32, y: f32) -> (i32, i32) {
+        (
+            (x / self.cell_size).floor() as i32,
+            (y / self.cell_size).floor() as i32,
+        )
+    }
+
+    pub fn insert(&mut self, id: u32, x: f32, y: f32) {
+        let cell = self.cell_of(x, y);
+        self.cells.entry(cell).or_default().push(id);
+    }
+
+    /// Ids in the cell containing `(x, y)` and the eight cells around it. With a cell size of
+    /// at least the query distance this contains everything within that distance.
+    pub fn neighbours(&self, x: f32, y: f32) -> impl Iterator<Item = u32> + '_ {
+        self.within(x, y, 1)
+    }
+
+    /// Ids in every cell at most `radius` cells away from the one containing `(x, y)`.
+    pub fn within(&self, x: f32, y: f32, radius: i32) -> impl Iterator<Item = u32> + '_ {
+        let (cx, cy) = self.cell_of(x, y);
+        let span = move |center: i32| center.saturating_sub(radius)..=center.saturating_add(radius);
+        span(cx)
+            .flat_map(move |gx| span(cy).map(move |gy| (gx, gy)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(ids: impl Iterator<Item = u32>) -> Vec<u32> {
+        let mut ids = ids.collect::<Vec<u32>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn cells_round_down() {
+        let grid = SpatialHashGrid::new(10.);
+        assert_eq!(grid.cell_of(0., 9.9), (0, 0));
+        assert_eq!(grid.cell_of(10., -0.1), (1, -1));
+        assert_eq!(grid.cell_of(-10., -10.1), (-1, -2));
+    }
+
+    #[test]
+    fn neighbours_are_the_surrounding_cells() {
+        let mut grid = SpatialHashGrid::new(10.);
+        grid.insert(1, 0., 0.);
+        grid.insert(2, 19., 19.);
+        grid.insert(3, -5., 5.);
+        grid.insert(4, 25., 0.);
+        grid.insert(5, -100., -100.);
+
+        assert_eq!(sorted(grid.neighbours(5., 5.)), vec![1, 2, 3]);
+        assert_eq!(sorted(grid.within(5., 5., 2)), vec![1, 2, 3, 4]);
+        assert_eq!(sorted(grid.within(5., 5., 0)), vec![1]);
+    }
+
+    #[test]
+    fn far_away_positions_do_not_overflow() {
+        let mut grid = SpatialHashGrid::new(f32::MIN_POSITIVE);
+        grid.insert(1, f32::MAX, f32::MAX);
+        grid.insert(2, -f32::MAX, 0.);
+        assert_eq!(grid.cell_of(f32::MAX, -f32::MAX), (i32::MAX, i32::MIN));
+        assert_eq!(sorted(grid.neighbours(1e30, 1e30)), vec![1]);
+        assert_eq!(sorted(grid.neighbours(-1e30, 0.)), vec![2]);
+    }
+}
@@ -0,0 +1,1004 @@
+use crate::interest::InterestChange;
+use crate::movement::MoveCorrection;
+use crate::outbox::Outbox;
+use crate::protocol::capabilities;
+use crate::ratelimit::PlayerLimiter;
+use crate::room::{self, Lobby, RoomError};
+use crate::server::{Config, Hook, LeaveReason, ServerEvent};
+use crate::session::SessionStats;
+use crate::snapshot::SnapshotStats;
+use crate::tick::TickStats;
+use crate::{BellMessage, GameState, Point, Registration};
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+/// How many suspicious moves of one player are logged as a single warning.
+const SUSPICIOUS_MOVE_REPORT_EVERY: u32 = 50;
+
+/// How often the tick timings are printed.
+pub const TICK_REPORT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Where the answer to a command goes, None if nobody waits for it.
+pub type Reply<T> = Option<oneshot::Sender<T>>;
+
+/// What the state task can be asked to do. Commands are applied as they arrive, what they
+/// send and the events they cause go out with the next tick.
+#[derive(Debug)]
+pub enum Command {
+    /// A registration that passed the version and cookie checks
+    Register {
+        addr: std::net::SocketAddr,
+        registration: Registration,
+        reply: Reply<Registered>,
+    },
+    /// A position or the held inputs of a player
+    Move {
+        addr: std::net::SocketAddr,
+        session: Option<u64>,
+        movement: Movement,
+        reply: Reply<Moved>,
+    },
+    /// A player shutting down cleanly, answered with whether it was in the game
+    Leave {
+        addr: std::net::SocketAddr,
+        session: Option<u64>,
+        id: u32,
+        reply: Reply<bool>,
+    },
+    /// Anything else a player asks for, answered with whether it was taken. What comes of it
+    /// is sent to the player
+    Request {
+        addr: std::net::SocketAddr,
+        session: Option<u64>,
+        id: u32,
+        request: Request,
+        reply: Reply<bool>,
+    },
+    Query(Query),
+}
+
+#[derive(Debug)]
+pub enum Movement {
+    /// Where the player says it is
+    Position(Point),
+    /// Input sequence number and the held directions (see `movement::input`)
+    Input { id: u32, sequence: u32, inputs: u8 },
+}
+
+impl Movement {
+    fn player_id(&self) -> u32 {
+        match self {
+            Movement::Position(point) => point.id,
+            Movement::Input { id, .. } => *id,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Request {
+    Heartbeat,
+    /// Tick of a snapshot the player received
+    AckSnapshot(u32),
+    /// Width and height of the area the player wants to hear about
+    SetView(f32, f32),
+    ListRooms,
+    CreateRoom {
+        name: String,
+        max_players: u16,
+        password: Option<String>,
+    },
+    JoinRoom {
+        name: String,
+        password: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Registered {
+    /// The player is in the game with this id, also the answer to a repeated registration
+    Admitted(u32),
+    /// The game is full, carries the 1-based position in the waitlist
+    Waitlisted(u32),
+    /// The client lacks capabilities the server requires, carries the missing ones
+    Refused(u32),
+}
+
+#[derive(Debug, Clone)]
+pub enum Moved {
+    Accepted,
+    /// The move broke the movement rules, the player was sent where it was put instead
+    Corrected,
+    /// Not taken: the session did not match, the player is unknown or over its message limit,
+    /// or it moves some other way
+    Ignored,
+}
+
+impl Command {
+    /// Sorts a message that came in with `session` into the command for it. Messages only the
+    /// server sends give None.
+    pub fn from_message(
+        addr: std::net::SocketAddr,
+        session: Option<u64>,
+        message: BellMessage,
+    ) -> Option<Self> {
+        let request = |id, request| Command::Request {
+            addr,
+            session,
+            id,
+            request,
+            reply: None,
+        };
+        let movement = |movement| Command::Move {
+            addr,
+            session,
+            movement,
+            reply: None,
+        };
+        let command = match message {
+            BellMessage::PlayerRegistrationMessage(registration) => Command::Register {
+                addr,
+                registration,
+                reply: None,
+            },
+            BellMessage::PositionChangeMessage(point) => movement(Movement::Position(point)),
+            BellMessage::InputMessage(id, sequence, inputs) => movement(Movement::Input {
+                id,
+                sequence,
+                inputs,
+            }),
+            BellMessage::PlayerLeaveMessage(id) => Command::Leave {
+                addr,
+                session,
+                id,
+                reply: None,
+            },
+            BellMessage::HeartbeatMessage(id) => request(id, Request::Heartbeat),
+            BellMessage::SnapshotAckMessage(id, tick) => request(id, Request::AckSnapshot(tick)),
+            BellMessage::SetViewMessage(id, width, height) => {
+                request(id, Request::SetView(width, height))
+            }
+            BellMessage::ListRoomsMessage(id) => request(id, Request::ListRooms),
+            BellMessage::CreateRoomMessage(id, name, max_players, password) => request(
+                id,
+                Request::CreateRoom {
+                    name,
+                    max_players,
+                    password,
+                },
+            ),
+            BellMessage::JoinRoomMessage(id, name, password) => {
+                request(id, Request::JoinRoom { name, password })
+            }
+            _ => return None,
+        };
+
+        Some(command)
+    }
+}
+
+/// Questions about the state, answered right away without waiting for a tick.
+#[derive(Debug)]
+pub enum Query {
+    Stats(oneshot::Sender<StateStats>),
+}
+
+#[derive(Debug, Clone)]
+pub struct StateStats {
+    pub players: usize,
+    pub rooms: usize,
+    pub sessions: SessionStats,
+    pub snapshots: SnapshotStats,
+}
+
+/// What a tick hands to the network task.
+pub struct TickOutput {
+    pub outgoing: Outbox,
+    /// Addresses of players that went over their message limit since the last tick
+    pub limited: Vec<std::net::SocketAddr>,
+}
+
+/// The way to talk to the state task. Cheap to clone.
+#[derive(Clone)]
+pub struct StateHandle {
+    commands: mpsc::Sender<Command>,
+}
+
+impl StateHandle {
+    /// Hands a command to the state task without waiting. Returns false if the task is too
+    /// far behind and the command was dropped.
+    pub fn send(&self, command: Command) -> bool {
+        self.commands.try_send(command).is_ok()
+    }
+
+    /// Sends the command `build` makes around a reply channel and waits for the answer. None
+    /// if the state task stopped.
+    pub async fn ask<T>(&self, build: impl FnOnce(oneshot::Sender<T>) -> Command) -> Option<T> {
+        let (reply, answer) = oneshot::channel();
+        self.commands.send(build(reply)).await.ok()?;
+        answer.await.ok()
+    }
+
+    pub async fn stats(&self) -> Option<StateStats> {
+        self.ask(|reply| Command::Query(Query::Stats(reply))).await
+    }
+}
+
+/// Owns the lobby and everything in it. Commands change the game as they come in, what they
+/// want sent and what happened is kept until the next tick. Once per tick the simulation
+/// runs, what the tick wants sent goes to `flush` and what happened goes to the hooks and the
+/// event stream.
+struct StateTask {
+    lobby: Lobby,
+    config: Arc<Config>,
+    players: PlayerLimiter,
+    flush: mpsc::Sender<TickOutput>,
+    hooks: Arc<[Hook]>,
+    events: broadcast::Sender<ServerEvent>,
+    outgoing: Outbox,
+    happened: Vec<ServerEvent>,
+    limited: Vec<std::net::SocketAddr>,
+}
+
+/// Starts the state task on the current runtime. It stops once every handle is dropped, the
+/// returned join handle finishes when it did.
+pub fn spawn(
+    lobby: Lobby,
+    config: Arc<Config>,
+    flush: mpsc::Sender<TickOutput>,
+    hooks: Arc<[Hook]>,
+    events: broadcast::Sender<ServerEvent>,
+) -> (StateHandle, tokio::task::JoinHandle<()>) {
+    let (commands, receiver) = mpsc::channel(config.queue_capacity.max(1));
+    let task = StateTask::new(lobby, config, flush, hooks, events);
+    let running = tokio::spawn(task.run(receiver));
+
+    (StateHandle { commands }, running)
+}
+
+impl StateTask {
+    fn new(
+        lobby: Lobby,
+        config: Arc<Config>,
+        flush: mpsc::Sender<TickOutput>,
+        hooks: Arc<[Hook]>,
+        events: broadcast::Sender<ServerEvent>,
+    ) -> Self {
+        Self {
+            lobby,
+            players: PlayerLimiter::new(config.rate_limits),
+            config,
+            flush,
+            hooks,
+            events,
+            outgoing: Outbox::new(),
+            happened: vec![],
+            limited: vec![],
+        }
+    }
+
+    async fn run(mut self, mut commands: mpsc::Receiver<Command>) {
+        let period = self.config.tick_period();
+        // the simulation always advances by a whole tick, if ticks are late the interval
+        // catches up instead of stretching the step
+        let dt = period.as_secs_f32();
+        let mut interval = tokio::time::interval(period);
+        let mut tick_stats = TickStats::new(period);
+        let mut tick_number = 0_u64;
+        let epoch = std::time::Instant::now();
+        log::info!("Ticking at {} Hz", self.config.tick_rate);
+        loop {
+            tokio::select! {
+                // a tick that is due goes before the commands that keep coming in
+                biased;
+                _ = interval.tick() => {}
+                command = commands.recv() => {
+                    match command {
+                        Some(command) => self.handle(command),
+                        None => return,
+                    }
+                    continue;
+                }
+            }
+
+            let started = std::time::Instant::now();
+            tick_number += 1;
+            let mut outgoing = std::mem::take(&mut self.outgoing);
+            let mut events = std::mem::take(&mut self.happened);
+            run_tick(
+                &mut self.lobby,
+                &self.config,
+                dt,
+                tick_number as u32,
+                started.duration_since(epoch).as_millis() as u32,
+                &mut outgoing,
+                &mut events,
+            );
+            self.players.evict_idle(self.config.player_timeout, started);
+            let elapsed = started.elapsed();
+            let output = TickOutput {
+                outgoing,
+                limited: std::mem::take(&mut self.limited),
+            };
+            if self.flush.send(output).await.is_err() {
+                return;
+            }
+
+            if tick_stats.record(elapsed) {
+                log::warn!(
+                    "Tick {} overran: took {:?} with a budget of {:?}",
+                    tick_number,
+                    elapsed,
+                    period
+                );
+                events.push(ServerEvent::TickOverrun {
+                    tick: tick_number,
+                    took: elapsed,
+                });
+            }
+            self.publish(events);
+            if let Some(report) = tick_stats.take_report(TICK_REPORT_INTERVAL) {
+                log::info!("Tick stats: {}", report);
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Register {
+                addr,
+                registration,
+                reply,
+            } => respond(reply, self.register(addr, registration)),
+            Command::Move {
+                addr,
+                session,
+                movement,
+                reply,
+            } => {
+                let moved = if self.let_through(addr, session, movement.player_id()) {
+                    self.apply_move(addr, movement)
+                } else {
+                    Moved::Ignored
+                };
+                respond(reply, moved);
+            }
+            Command::Leave {
+                addr,
+                session,
+                id,
+                reply,
+            } => {
+                let left = self.let_through(addr, session, id) && self.leave(id);
+                respond(reply, left);
+            }
+            Command::Request {
+                addr,
+                session,
+                id,
+                request,
+                reply,
+            } => {
+                let taken = self.let_through(addr, session, id) && self.request(addr, id, request);
+                respond(reply, taken);
+            }
+            Command::Query(query) => self.answer(query),
+        }
+    }
+
+    /// Whether a message naming player `id` gets handled: it has to come with the player's
+    /// session and the player has to be within its message limit.
+    fn let_through(&mut self, addr: std::net::SocketAddr, session: Option<u64>, id: u32) -> bool {
+        if !self.lobby.authenticate(addr, session, id) {
+            log::debug!("Dropping unauthenticated message for {} from {}", id, addr);
+            return false;
+        }
+        if !self.players.check(id, std::time::Instant::now()) {
+            log::debug!("Rate limiting messages for player {}", id);
+            self.limited.push(addr);
+            return false;
+        }
+
+        true
+    }
+
+    fn register(&mut self, addr: std::net::SocketAddr, registration: Registration) -> Registered {
+        let lobby = &mut self.lobby;
+        let missing = self.config.required_capabilities() & !registration.capabilities;
+        match lobby.get_id_for_addr(&addr) {
+            // the reply got lost and the client is asking again
+            Some(id) => {
+                let token = lobby.session_token(id).unwrap_or_default();
+                let game_state = lobby.state_of_mut(id).unwrap();
+                let points = game_state.reset_interest(id);
+                let accepted = game_state.get_capabilities(id);
+                let world = game_state.bounds();
+                self.outgoing.push(
+                    addr,
+                    BellMessage::RegistrationReplyMessage(id, points, accepted, token, world),
+                );
+                Registered::Admitted(id)
+            }
+            None if missing != 0 => {
+                log::info!(
+                    "Refusing {}, it lacks capabilities {:?}",
+                    addr,
+                    capabilities::names(missing)
+                );
+                self.outgoing
+                    .push(addr, BellMessage::MissingCapabilitiesMessage(missing));
+                Registered::Refused(missing)
+            }
+            None if lobby.is_full() || lobby.default_room().is_full() => {
+                let position = lobby.default_room_mut().join_waitlist(addr, registration);
+                log::info!(
+                    "Game is full, {} is number {} in the waitlist",
+                    addr,
+                    position
+                );
+                self.outgoing
+                    .push(addr, BellMessage::ServerFullMessage(position));
+                Registered::Waitlisted(position)
+            }
+            None => Registered::Admitted(admit_player(
+                lobby,
+                registration,
+                self.config.offered_capabilities(),
+                addr,
+                &mut self.outgoing,
+                &mut self.happened,
+            )),
+        }
+    }
+
+    fn apply_move(&mut self, addr: std::net::SocketAddr, movement: Movement) -> Moved {
+        let id = movement.player_id();
+        let Some(game_state) = self.lobby.state_of_mut(id) else {
+            return Moved::Ignored;
+        };
+        game_state.touch(id);
+        match movement {
+            Movement::Input {
+                sequence, inputs, ..
+            } => {
+                if !moves_by_input(game_state, id) {
+                    return Moved::Ignored;
+                }
+                game_state.apply_input(id, sequence, inputs);
+                Moved::Accepted
+            }
+            Movement::Position(point) => {
+                if self.config.input_only || moves_by_input(game_state, id) {
+                    log::debug!("Ignoring absolute position from input driven player {}", id);
+                    return Moved::Ignored;
+                }
+                log::debug!("Processing position change message for id {}", id);
+                // the others get the new position with the next snapshot
+                match game_state.validate_move(id, point.x, point.y) {
+                    Ok(()) => Moved::Accepted,
+                    Err(correction) => {
+                        report_correction(&correction);
+                        // the client has to snap back to where we put it
+                        self.outgoing
+                            .push(addr, BellMessage::PositionChangeMessage(correction.point));
+                        Moved::Corrected
+                    }
+                }
+            }
+        }
+    }
+
+    fn leave(&mut self, id: u32) -> bool {
+        let Some(room) = self.lobby.remove_player(id) else {
+            return false;
+        };
+        log::info!("Player {} left", id);
+        broadcast_removal(self.lobby.room(&room).unwrap(), id, &mut self.outgoing);
+        self.happened.push(ServerEvent::PlayerLeft {
+            id,
+            reason: LeaveReason::Left,
+        });
+
+        true
+    }
+
+    /// Returns false if the player is not in the game or may not ask for this.
+    fn request(&mut self, addr: std::net::SocketAddr, id: u32, request: Request) -> bool {
+        let lobby = &mut self.lobby;
+        let Some(game_state) = lobby.state_of_mut(id) else {
+            return false;
+        };
+        game_state.touch(id);
+        match request {
+            Request::Heartbeat => {}
+            Request::AckSnapshot(tick) => lobby.ack_snapshot(id, tick),
+            Request::SetView(width, height) => game_state.set_view(id, width, height),
+            Request::ListRooms => {
+                self.outgoing
+                    .push(addr, BellMessage::RoomListMessage(lobby.list()));
+            }
+            Request::CreateRoom {
+                name,
+                max_players,
+                password,
+            } => {
+                if !is_member(lobby, id, addr) {
+                    return false;
+                }
+                match lobby.create_room(&name, max_players as usize, password) {
+                    Ok(()) => {
+                        log::info!("Player {} created room {}", id, name);
+                        change_room(lobby, id, &name, &mut self.outgoing, &mut self.happened);
+                    }
+                    Err(e) => self.outgoing.push(addr, BellMessage::RoomErrorMessage(e)),
+                }
+            }
+            Request::JoinRoom { name, password } => {
+                if !is_member(lobby, id, addr) {
+                    return false;
+                }
+                if lobby.room_of(id) == Some(name.as_str()) {
+                    return true;
+                }
+                match lobby.check_join(&name, password.as_deref()) {
+                    Ok(()) => change_room(lobby, id, &name, &mut self.outgoing, &mut self.happened),
+                    Err(e) => self.outgoing.push(addr, BellMessage::RoomErrorMessage(e)),
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Runs the hooks for every event and then puts it on the event stream. Nobody listening
+    /// to the stream is fine.
+    fn publish(&self, events: Vec<ServerEvent>) {
+        for event in events {
+            for hook in self.hooks.iter() {
+                hook(&event);
+            }
+            _ = self.events.send(event);
+        }
+    }
+
+    fn answer(&self, query: Query) {
+        match query {
+            Query::Stats(reply) => respond(
+                Some(reply),
+                StateStats {
+                    players: self.lobby.player_count(),
+                    rooms: self.lobby.list().len(),
+                    sessions: self.lobby.session_stats(),
+                    snapshots: self.lobby.snapshot_stats(),
+                },
+            ),
+        }
+    }
+}
+
+/// Sends an answer if somebody waits for it. Nobody waiting anymore is fine.
+fn respond<T>(reply: Reply<T>, answer: T) {
+    if let Some(reply) = reply {
+        _ = reply.send(answer);
+    }
+}
+
+/// Runs one server tick: evicts silent players, admits waitlisted ones and advances the
+/// simulation and collisions of every room. Everything that has to be sent out for this tick
+/// is added to `outgoing`, ending with the snapshot of `tick` for every player, stamped with
+/// `time` in milliseconds since the server started. What happened to the players is added to
+/// `events`.
+fn run_tick(
+    lobby: &mut Lobby,
+    config: &Config,
+    dt: f32,
+    tick: u32,
+    time: u32,
+    outgoing: &mut Outbox,
+    events: &mut Vec<ServerEvent>,
+) {
+    for (room, id) in lobby.evict_stale(config.player_timeout) {
+        log::info!("Player {} timed out", id);
+        broadcast_removal(lobby.room(&room).unwrap(), id, outgoing);
+        events.push(ServerEvent::PlayerLeft {
+            id,
+            reason: LeaveReason::TimedOut,
+        });
+    }
+    drain_waitlist(lobby, config.offered_capabilities(), outgoing, events);
+
+    for (room, game_state) in lobby.rooms_mut() {
+        // the new positions go out with the snapshots at the end of the tick
+        game_state.simulate(dt);
+
+        for (a, b) in game_state.update_collisions() {
+            log::info!("Players {} and {} collided in {}", a, b, room);
+            let audience = [a, b]
+                .into_iter()
+                .filter_map(|id| game_state.get_addr_from_id(id))
+                .chain(game_state.get_observer_addrs(a))
+                .chain(game_state.get_observer_addrs(b))
+                .collect::<std::collections::HashSet<_>>();
+            outgoing.broadcast(BellMessage::CollisionMessage(a, b), audience);
+            events.push(ServerEvent::Collision {
+                room: room.clone(),
+                a,
+                b,
+            });
+        }
+
+        for (observer, change) in game_state.update_interest() {
+            let Some(addr) = game_state.get_addr_from_id(observer) else {
+                continue;
+            };
+            let message = match change {
+                InterestChange::Enter(point) => BellMessage::EnterViewMessage(point),
+                InterestChange::Leave(id) => BellMessage::LeaveViewMessage(id),
+            };
+            outgoing.push(*addr, message);
+        }
+    }
+    lobby.remove_empty_rooms();
+    outgoing.extend(lobby.snapshots(tick, time));
+}
+
+/// Room requests are only taken from the address the player registered from.
+fn is_member(lobby: &Lobby, id: u32, addr: std::net::SocketAddr) -> bool {
+    lobby
+        .state_of(id)
+        .and_then(|game_state| game_state.get_addr_from_id(id))
+        == Some(&addr)
+}
+
+/// Moves a player into another room. The room it left sees it go and the player gets the
+/// positions of the players it sees in the new one. Those see it arrive once the tick works out
+/// who sees whom.
+fn change_room(
+    lobby: &mut Lobby,
+    id: u32,
+    room: &str,
+    outgoing: &mut Outbox,
+    events: &mut Vec<ServerEvent>,
+) {
+    // moving leaves the lobby-wide count as it is, only the room's own limit can be in the way
+    if lobby.room(room).is_some_and(GameState::is_full) {
+        if let Some(addr) = lobby
+            .state_of(id)
+            .and_then(|state| state.get_addr_from_id(id))
+        {
+            outgoing.push(*addr, BellMessage::RoomErrorMessage(RoomError::Full));
+        }
+        return;
+    }
+    let Some(left) = lobby.move_player(id, room) else {
+        return;
+    };
+    log::info!("Player {} moved from {} to {}", id, left, room);
+    if let Some(old_room) = lobby.room(&left) {
+        broadcast_removal(old_room, id, outgoing);
+    }
+
+    let addr = *lobby.room(room).unwrap().get_addr_from_id(id).unwrap();
+    outgoing.push(addr, lobby.joined_message(id).unwrap());
+    events.push(ServerEvent::RoomChanged {
+        id,
+        from: left,
+        to: room.to_string(),
+    });
+}
+
+/// Logs a corrected move. Suspicious ones are warned about the first time and then every
+/// `SUSPICIOUS_MOVE_REPORT_EVERY` times, so a cheating client cannot flood the log.
+fn report_correction(correction: &MoveCorrection) {
+    let id = correction.point.id;
+    if !correction.violation.is_suspicious() {
+        log::debug!("Corrected player {}: {}", id, correction.violation);
+        return;
+    }
+    if correction.suspicious % SUSPICIOUS_MOVE_REPORT_EVERY == 1 {
+        log::warn!(
+            "Suspicious move by player {}: {} ({} so far)",
+            id,
+            correction.violation,
+            correction.suspicious
+        );
+    }
+}
+
+fn broadcast_removal(game_state: &GameState, id: u32, outgoing: &mut Outbox) {
+    outgoing.broadcast(
+        BellMessage::PlayerRemovalMessage(id),
+        game_state.get_addrs_for_id(id),
+    );
+}
+
+fn moves_by_input(game_state: &GameState, id: u32) -> bool {
+    game_state.get_capabilities(id) & capabilities::INPUT_MOVEMENT != 0
+}
+
+/// Adds a new player to the default room and replies with its id and the positions of the
+/// players it sees. Those see it arrive once the tick works out who sees whom. It gets the
+/// capabilities it asked for that are in `offered`. Returns the id.
+fn admit_player(
+    lobby: &mut Lobby,
+    mut registration: Registration,
+    offered: u32,
+    addr: std::net::SocketAddr,
+    outgoing: &mut Outbox,
+    events: &mut Vec<ServerEvent>,
+) -> u32 {
+    let id = lobby.allocate_id();
+    let point = &mut registration.point;
+    point.id = id;
+    lobby.insert_player(room::DEFAULT_ROOM, id, point.x, point.y, addr);
+    let token = lobby.open_session(id, addr);
+    let game_state = lobby.default_room_mut();
+    let accepted = registration.capabilities & offered;
+    game_state.set_capabilities(id, accepted);
+    log::info!(
+        "Player {} registered with capabilities {:?}",
+        id,
+        capabilities::names(accepted)
+    );
+
+    let points = game_state.reset_interest(id);
+    outgoing.push(
+        addr,
+        BellMessage::RegistrationReplyMessage(id, points, accepted, token, game_state.bounds()),
+    );
+    events.push(ServerEvent::PlayerJoined { id, addr });
+
+    id
+}
+
+/// Lets waitlisted players into the default room while there is space and tells the ones
+/// still waiting where they stand.
+fn drain_waitlist(
+    lobby: &mut Lobby,
+    offered: u32,
+    outgoing: &mut Outbox,
+    events: &mut Vec<ServerEvent>,
+) {
+    let mut admitted_any = false;
+    while !lobby.is_full() {
+        let Some((addr, registration)) = lobby.default_room_mut().pop_waitlist() else {
+            break;
+        };
+        log::info!("Admitting {} from the waitlist", addr);
+        admit_player(lobby, registration, offered, addr, outgoing, events);
+        admitted_any = true;
+    }
+
+    if admitted_any {
+        let waiting = lobby.default_room().get_waitlist_addrs();
+        for (position, addr) in waiting.into_iter().enumerate() {
+            outgoing.push(addr, BellMessage::ServerFullMessage(position as u32 + 1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PROTOCOL_VERSION;
+    use crate::Point;
+
+    fn task(config: Config) -> StateTask {
+        let (flush, _) = mpsc::channel(1);
+        let (events, _) = broadcast::channel(16);
+        StateTask::new(
+            Lobby::new(config.max_players, config.max_rooms),
+            Arc::new(config),
+            flush,
+            Arc::new([]),
+            events,
+        )
+    }
+
+    fn addr(port: u16) -> std::net::SocketAddr {
+        std::net::SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn registration() -> Registration {
+        Registration {
+            point: Point::default(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: 0,
+            cookie: 0,
+        }
+    }
+
+    fn tick(task: &mut StateTask) {
+        task.outgoing = Outbox::new();
+        run_tick(
+            &mut task.lobby,
+            &task.config,
+            0.05,
+            1,
+            0,
+            &mut task.outgoing,
+            &mut task.happened,
+        );
+    }
+
+    fn admitted(registered: Registered) -> u32 {
+        match registered {
+            Registered::Admitted(id) => id,
+            other => panic!("not admitted: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn waitlist_keeps_its_order() {
+        let mut task = task(Config {
+            max_players: 1,
+            ..Config::default()
+        });
+        admitted(task.register(addr(1), registration()));
+        assert_eq!(
+            task.register(addr(2), registration()),
+            Registered::Waitlisted(1)
+        );
+        assert_eq!(
+            task.register(addr(3), registration()),
+            Registered::Waitlisted(2)
+        );
+        // asking again keeps the place
+        assert_eq!(
+            task.register(addr(2), registration()),
+            Registered::Waitlisted(1)
+        );
+        assert_eq!(
+            task.lobby.default_room().get_waitlist_addrs(),
+            vec![addr(2), addr(3)]
+        );
+    }
+
+    #[test]
+    fn waitlisted_player_is_admitted_when_a_slot_frees_up() {
+        let mut task = task(Config {
+            max_players: 1,
+            ..Config::default()
+        });
+        let first = admitted(task.register(addr(1), registration()));
+        task.register(addr(2), registration());
+        task.register(addr(3), registration());
+
+        assert!(task.leave(first));
+        tick(&mut task);
+        let second = task.lobby.get_id_for_addr(&addr(2)).unwrap();
+        assert_ne!(second, first);
+        assert!(task.lobby.get_id_for_addr(&addr(3)).is_none());
+        assert!(matches!(
+            task.outgoing.messages_to(addr(2)).as_slice(),
+            [BellMessage::RegistrationReplyMessage(id, ..), ..] if *id == second
+        ));
+        // the one still waiting moved up
+        assert!(matches!(
+            task.outgoing.messages_to(addr(3)).as_slice(),
+            [BellMessage::ServerFullMessage(1)]
+        ));
+        assert!(task
+            .happened
+            .iter()
+            .any(|event| matches!(event, ServerEvent::PlayerJoined { id, .. } if *id == second)));
+    }
+
+    #[test]
+    fn silent_players_and_registrations_are_evicted() {
+        let mut task = task(Config {
+            max_players: 1,
+            player_timeout: std::time::Duration::from_millis(20),
+            ..Config::default()
+        });
+        let first = admitted(task.register(addr(1), registration()));
+        task.register(addr(2), registration());
+        task.register(addr(3), registration());
+
+        std::thread::sleep(std::time::Duration::from_millis(40));
+        // only the second one keeps asking
+        task.register(addr(2), registration());
+        tick(&mut task);
+
+        assert!(task.happened.iter().any(|event| matches!(
+            event,
+            ServerEvent::PlayerLeft {
+                id,
+                reason: LeaveReason::TimedOut
+            } if *id == first
+        )));
+        assert!(task.lobby.get_id_for_addr(&addr(1)).is_none());
+        assert!(task.lobby.get_id_for_addr(&addr(2)).is_some());
+        assert!(task.lobby.get_id_for_addr(&addr(3)).is_none());
+        assert!(task.lobby.default_room().get_waitlist_addrs().is_empty());
+    }
+
+    #[test]
+    fn lost_reply_is_sent_again() {
+        let mut task = task(Config::default());
+        let id = admitted(task.register(addr(1), registration()));
+        task.outgoing = Outbox::new();
+
+        assert_eq!(
+            task.register(addr(1), registration()),
+            Registered::Admitted(id)
+        );
+        assert!(matches!(
+            task.outgoing.messages_to(addr(1)).as_slice(),
+            [BellMessage::RegistrationReplyMessage(again, ..)] if *again == id
+        ));
+    }
+
+    #[test]
+    fn player_limit_counts_every_room() {
+        let mut task = task(Config {
+            max_players: 2,
+            ..Config::default()
+        });
+        let first = admitted(task.register(addr(1), registration()));
+        let second = admitted(task.register(addr(2), registration()));
+        assert!(task.request(
+            addr(1),
+            first,
+            Request::CreateRoom {
+                name: "side".to_string(),
+                max_players: 0,
+                password: None,
+            }
+        ));
+        assert_eq!(task.lobby.room_of(first), Some("side"));
+
+        // the default room has a free slot, but the server does not
+        assert_eq!(
+            task.register(addr(3), registration()),
+            Registered::Waitlisted(1)
+        );
+        tick(&mut task);
+        assert!(task.lobby.get_id_for_addr(&addr(3)).is_none());
+
+        assert!(task.leave(second));
+        tick(&mut task);
+        assert!(task.lobby.get_id_for_addr(&addr(3)).is_some());
+        assert_eq!(task.lobby.player_count(), 2);
+    }
+
+    #[test]
+    fn full_room_cannot_be_joined() {
+        let mut task = task(Config::default());
+        let first = admitted(task.register(addr(1), registration()));
+        let second = admitted(task.register(addr(2), registration()));
+        task.request(
+            addr(1),
+            first,
+            Request::CreateRoom {
+                name: "alone".to_string(),
+                max_players: 1,
+                password: None,
+            },
+        );
+        task.outgoing = Outbox::new();
+
+        task.request(
+            addr(2),
+            second,
+            Request::JoinRoom {
+                name: "alone".to_string(),
+                password: None,
+            },
+        );
+        assert_eq!(task.lobby.room_of(second), Some(room::DEFAULT_ROOM));
+        assert!(matches!(
+            task.outgoing.messages_to(addr(2)).as_slice(),
+            [BellMessage::RoomErrorMessage(RoomError::Full)]
+        ));
+
+        // changing rooms checks the limit on its own too
+        change_room(
+            &mut task.lobby,
+            second,
+            "alone",
+            &mut task.outgoing,
+            &mut task.happened,
+        );
+        assert_eq!(task.lobby.room_of(second), Some(room::DEFAULT_ROOM));
+    }
+}
@@ -0,0 +1,83 @@
+/// Default number of server ticks per second.
+pub const DEFAULT_TICK_RATE: u32 = 60;
+
+/// Timing of the ticks run since the last report.
+#[derive(Debug, Clone)]
+pub struct TickReport {
+    pub ticks: u64,
+    pub average: std::time::Duration,
+    pub slowest: std::time::Duration,
+    pub overruns: u64,
+    /// Share of the wall clock time spent doing tick work, 1.0 means no idle time at all.
+    pub load: f32,
+}
+
+impl std::fmt::Display for TickReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ticks, avg {:?}, max {:?}, {} overruns, load {:.1}%",
+            self.ticks,
+            self.average,
+            self.slowest,
+            self.overruns,
+            self.load * 100.
+        )
+    }
+}
+
+/// Collects per-tick timings for the server loop.
+pub struct TickStats {
+    period: std::time::Duration,
+    window_started: std::time::Instant,
+    ticks: u64,
+    busy: std::time::Duration,
+    slowest: std::time::Duration,
+    overruns: u64,
+}
+
+impl TickStats {
+    pub fn new(period: std::time::Duration) -> Self {
+        Self {
+            period,
+            window_started: std::time::Instant::now(),
+            ticks: 0,
+            busy: std::time::Duration::ZERO,
+            slowest: std::time::Duration::ZERO,
+            overruns: 0,
+        }
+    }
+
+    /// Records how long one tick took. Returns true if it took longer than the tick period.
+    pub fn record(&mut self, duration: std::time::Duration) -> bool {
+        self.ticks += 1;
+        self.busy += duration;
+        self.slowest = self.slowest.max(duration);
+        let overran = duration > self.period;
+        if overran {
+            self.overruns += 1;
+        }
+
+        overran
+    }
+
+    /// Returns the report for the current window and starts a new one once `every` has
+    /// passed, otherwise returns None.
+    pub fn take_report(&mut self, every: std::time::Duration) -> Option<TickReport> {
+        let elapsed = self.window_started.elapsed();
+        if elapsed < every || self.ticks == 0 {
+            return None;
+        }
+
+        let report = TickReport {
+            ticks: self.ticks,
+            average: self.busy / self.ticks as u32,
+            slowest: self.slowest,
+            overruns: self.overruns,
+            load: self.busy.as_secs_f32() / elapsed.as_secs_f32(),
+        };
+        *self = Self::new(self.period);
+
+        Some(report)
+    }
+}
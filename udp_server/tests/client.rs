@@ -0,0 +1,113 @@
+use lib_udp_server::client::ClientOptions;
+use lib_udp_server::movement::input;
+use lib_udp_server::server::LeaveReason;
+use lib_udp_server::{BellClient, ClientEvent, Server, ServerEvent};
+
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+async fn start() -> (Server, tokio::task::JoinHandle<()>) {
+    let server = Server::builder().max_players(1).build().await.unwrap();
+    let running = tokio::spawn({
+        let server = server.clone();
+        async move { server.run().await.unwrap() }
+    });
+
+    (server, running)
+}
+
+/// Waits for the first event `wanted` picks something out of.
+async fn wait_for<T>(
+    client: &mut BellClient,
+    mut wanted: impl FnMut(ClientEvent) -> Option<T>,
+) -> T {
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            let event = client.next_event().await.expect("the client stopped");
+            if let Some(found) = wanted(event) {
+                return found;
+            }
+        }
+    })
+    .await
+    .expect("the event never came")
+}
+
+#[tokio::test]
+async fn client_plays_against_a_server() {
+    let (server, running) = start().await;
+    let mut events = server.events();
+
+    let mut client = BellClient::connect(server.local_addr()).await.unwrap();
+    let id = client.id();
+    assert_ne!(
+        client.capabilities() & lib_udp_server::protocol::capabilities::INPUT_MOVEMENT,
+        0
+    );
+    assert_eq!(server.stats().await.unwrap().sessions.opened, 1);
+
+    client.send_input(input::RIGHT).unwrap();
+    let moved = wait_for(&mut client, |event| match event {
+        ClientEvent::PlayerMoved(point) if point.id == id && point.x > 0. => Some(point),
+        _ => None,
+    })
+    .await;
+    assert_eq!(moved.y, 0.);
+    wait_for(&mut client, |event| match event {
+        ClientEvent::Snapshot(_, points) => points.iter().any(|point| point.id == id).then_some(()),
+        _ => None,
+    })
+    .await;
+
+    client.shutdown().await;
+    let left = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            if let ServerEvent::PlayerLeft { id: left, reason } = events.recv().await.unwrap() {
+                return (left, reason);
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(left, (id, LeaveReason::Left));
+
+    server.shutdown();
+    tokio::time::timeout(TIMEOUT, running)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn waitlisted_client_gets_in_once_a_slot_frees_up() {
+    let (server, running) = start().await;
+    let first = BellClient::connect(server.local_addr()).await.unwrap();
+
+    let (positions, mut waitlisted) = tokio::sync::mpsc::unbounded_channel();
+    let options = ClientOptions {
+        waitlisted: Some(std::sync::Arc::new(move |position| {
+            _ = positions.send(position);
+        })),
+        ..ClientOptions::default()
+    };
+    let waiting = tokio::spawn(BellClient::connect_with(server.local_addr(), options));
+    let position = tokio::time::timeout(TIMEOUT, waitlisted.recv())
+        .await
+        .unwrap();
+    assert_eq!(position, Some(1));
+
+    first.shutdown().await;
+    let second = tokio::time::timeout(TIMEOUT, waiting)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_ne!(second.id(), first.id());
+    assert_eq!(server.stats().await.unwrap().sessions.opened, 2);
+
+    second.shutdown().await;
+    server.shutdown();
+    tokio::time::timeout(TIMEOUT, running)
+        .await
+        .unwrap()
+        .unwrap();
+}
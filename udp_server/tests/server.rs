@@ -0,0 +1,111 @@
+use lib_udp_server::protocol::PROTOCOL_VERSION;
+use lib_udp_server::reliability::Connection;
+use lib_udp_server::server::ServerError;
+use lib_udp_server::{fragment, BellMessage, Point, Registration, Server, ServerEvent, WireFormat};
+use tokio::net::UdpSocket;
+
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Sends a message to the server and waits for the first one that comes back, acking it if
+/// it was reliable.
+async fn ask(
+    socket: &UdpSocket,
+    connection: &mut Connection,
+    message: &BellMessage,
+) -> BellMessage {
+    let now = std::time::Instant::now();
+    for datagram in connection.send(message, now).unwrap() {
+        socket.send(&datagram).await.unwrap();
+    }
+
+    let mut buf = vec![0; fragment::RECV_BUFFER_LEN];
+    loop {
+        let size = tokio::time::timeout(TIMEOUT, socket.recv(&mut buf))
+            .await
+            .expect("the server did not answer")
+            .unwrap();
+        let received = connection
+            .receive(&buf[..size], std::time::Instant::now())
+            .unwrap();
+        for reply in received.replies {
+            socket.send(&reply).await.unwrap();
+        }
+        if let Some(message) = received.messages.into_iter().next() {
+            return message;
+        }
+    }
+}
+
+fn registration(cookie: u64) -> BellMessage {
+    BellMessage::PlayerRegistrationMessage(Registration {
+        point: Point::default(),
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: 0,
+        cookie,
+    })
+}
+
+#[tokio::test]
+async fn server_runs_until_it_is_shut_down() {
+    let server = Server::builder().build().await.unwrap();
+    let mut events = server.events();
+    let running = tokio::spawn({
+        let server = server.clone();
+        async move { server.run().await }
+    });
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(server.local_addr()).await.unwrap();
+    let mut connection = Connection::new(WireFormat::default(), fragment::DEFAULT_MTU);
+    let cookie = match ask(&socket, &mut connection, &registration(0)).await {
+        BellMessage::ChallengeMessage(cookie) => cookie,
+        other => panic!("expected a challenge, got {:?}", other),
+    };
+    let id = match ask(&socket, &mut connection, &registration(cookie)).await {
+        BellMessage::RegistrationReplyMessage(id, ..) => id,
+        other => panic!("expected a registration reply, got {:?}", other),
+    };
+
+    let joined = tokio::time::timeout(TIMEOUT, events.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        joined,
+        ServerEvent::PlayerJoined {
+            id,
+            addr: socket.local_addr().unwrap()
+        }
+    );
+    assert_eq!(server.stats().await.unwrap().players, 1);
+
+    server.shutdown();
+    let result = tokio::time::timeout(TIMEOUT, running)
+        .await
+        .expect("run did not return after shutdown")
+        .unwrap();
+    assert!(result.is_ok());
+    assert!(server.stats().await.is_none());
+    assert!(matches!(
+        server.run().await,
+        Err(ServerError::AlreadyRunning)
+    ));
+}
+
+#[tokio::test]
+async fn server_shut_down_before_running_returns_right_away() {
+    let server = Server::builder().build().await.unwrap();
+    server.shutdown();
+    tokio::time::timeout(TIMEOUT, server.run())
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn invalid_settings_are_refused() {
+    let result = Server::builder().tick_rate(0).build().await;
+    assert!(matches!(result, Err(ServerError::InvalidConfig(_))));
+    let result = Server::builder().hitbox_size(f32::NAN).build().await;
+    assert!(matches!(result, Err(ServerError::InvalidConfig(_))));
+}